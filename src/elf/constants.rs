@@ -1,56 +1,77 @@
-pub(crate) const STB_LOCAL: u8 = 0;
-pub(crate) const STB_GLOBAL: u8 = 1;
-
-pub(crate) const STT_NOTYPE: u8 = 0;
-pub(crate) const STT_OBJECT: u8 = 1;
-pub(crate) const STT_FUNC: u8 = 2;
-pub(crate) const STT_SECTION: u8 = 3;
-pub(crate) const STT_FILE: u8 = 4;
-pub(crate) const STT_COMMON: u8 = 5;
-pub(crate) const STT_TLS: u8 = 6;
-pub(crate) const STT_RELC: u8 = 8;
-pub(crate) const STT_SRELC: u8 = 9;
-pub(crate) const STT_LOOS: u8 = 10;
-pub(crate) const STT_HIOS: u8 = 12;
-pub(crate) const STT_LOPROC: u8 = 13;
-pub(crate) const STT_HIPROC: u8 = 14;
-
-pub(crate) const SHT_NULL: u32 = 0;
-pub(crate) const SHT_PROGBITS: u32 = 1;
-pub(crate) const SHT_SYMTAB: u32 = 2;
-pub(crate) const SHT_STRTAB: u32 = 3;
-pub(crate) const SHT_RELA: u32 = 4;
-
-pub(crate) const SHF_WRITE: u64 = 0x1;
-pub(crate) const SHF_ALLOC: u64 = 0x2;
-pub(crate) const SHF_EXECINSTR: u64 = 0x4;
-pub(crate) const SHF_MERGE: u64 = 0x10;
-pub(crate) const SHF_STRINGS: u64 = 0x20;
-pub(crate) const SHF_INFO_LINK: u64 = 0x40;
-pub(crate) const SHF_LINK_ORDER: u64 = 0x80;
-pub(crate) const SHF_OS_NONCONFORMING: u64 = 0x100;
-pub(crate) const SHF_GROUP: u64 = 0x200;
-pub(crate) const SHF_TLS: u64 = 0x400;
-
-pub(crate) const R_X86_64_NONE: u64 = 0;
-pub(crate) const R_X86_64_64: u64 = 1;
-pub(crate) const R_X86_64_PC32: u64 = 2;
-pub(crate) const R_X86_64_GOT32: u64 = 3;
-pub(crate) const R_X86_64_PLT32: u64 = 4;
-pub(crate) const R_X86_64_COPY: u64 = 5;
-pub(crate) const R_X86_64_GLOB_DAT: u64 = 6;
-pub(crate) const R_X86_64_JUMP_SLOT: u64 = 7;
-pub(crate) const R_X86_64_RELATIVE: u64 = 8;
-pub(crate) const R_X86_64_GOTPCREL: u64 = 9;
-pub(crate) const R_X86_64_32: u64 = 10;
-pub(crate) const R_X86_64_32S: u64 = 11;
-pub(crate) const R_X86_64_16: u64 = 12;
-pub(crate) const R_X86_64_PC16: u64 = 13;
-pub(crate) const R_X86_64_8: u64 = 14;
-pub(crate) const R_X86_64_PC8: u64 = 15;
-pub(crate) const R_X86_64_PC64: u64 = 24;
-
-pub(crate) const STV_DEFAULT: u8 = 0;
-pub(crate) const STV_INTERNAL: u8 = 1;
-pub(crate) const STV_HIDDEN: u8 = 2;
-pub(crate) const STV_PROTECTED: u8 = 3;
+/// The OS ABI in `e_ident` of an object which uses GNU extensions. e.g. `SHF_GNU_RETAIN`
+pub const ELFOSABI_GNU: u8 = 3;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+pub const STT_COMMON: u8 = 5;
+pub const STT_TLS: u8 = 6;
+pub const STT_RELC: u8 = 8;
+pub const STT_SRELC: u8 = 9;
+pub const STT_LOOS: u8 = 10;
+pub const STT_HIOS: u8 = 12;
+pub const STT_LOPROC: u8 = 13;
+pub const STT_HIPROC: u8 = 14;
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_GROUP: u32 = 17;
+pub const SHT_SYMTAB_SHNDX: u32 = 18;
+
+/// The section indexes from this are reserved, so the larger indexes are stored out of the headers.
+pub const SHN_LORESERVE: usize = 0xff00;
+/// The section index is in `.symtab_shndx`, or in the null section header.
+pub const SHN_XINDEX: u16 = 0xffff;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+pub const SHF_MERGE: u64 = 0x10;
+pub const SHF_STRINGS: u64 = 0x20;
+pub const SHF_INFO_LINK: u64 = 0x40;
+pub const SHF_LINK_ORDER: u64 = 0x80;
+pub const SHF_OS_NONCONFORMING: u64 = 0x100;
+pub const SHF_GROUP: u64 = 0x200;
+pub const SHF_TLS: u64 = 0x400;
+pub const SHF_GNU_RETAIN: u64 = 0x200000;
+
+/// The flag word of `SHT_GROUP` section.
+pub const GRP_COMDAT: u32 = 0x1;
+
+pub const R_X86_64_NONE: u64 = 0;
+pub const R_X86_64_64: u64 = 1;
+pub const R_X86_64_PC32: u64 = 2;
+pub const R_X86_64_GOT32: u64 = 3;
+pub const R_X86_64_PLT32: u64 = 4;
+pub const R_X86_64_COPY: u64 = 5;
+pub const R_X86_64_GLOB_DAT: u64 = 6;
+pub const R_X86_64_JUMP_SLOT: u64 = 7;
+pub const R_X86_64_RELATIVE: u64 = 8;
+pub const R_X86_64_GOTPCREL: u64 = 9;
+pub const R_X86_64_32: u64 = 10;
+pub const R_X86_64_32S: u64 = 11;
+pub const R_X86_64_16: u64 = 12;
+pub const R_X86_64_PC16: u64 = 13;
+pub const R_X86_64_8: u64 = 14;
+pub const R_X86_64_PC8: u64 = 15;
+pub const R_X86_64_DTPOFF32: u64 = 21;
+pub const R_X86_64_GOTTPOFF: u64 = 22;
+pub const R_X86_64_TPOFF32: u64 = 23;
+pub const R_X86_64_PC64: u64 = 24;
+pub const R_X86_64_GOTPCRELX: u64 = 41;
+pub const R_X86_64_REX_GOTPCRELX: u64 = 42;
+
+pub const STV_DEFAULT: u8 = 0;
+pub const STV_INTERNAL: u8 = 1;
+pub const STV_HIDDEN: u8 = 2;
+pub const STV_PROTECTED: u8 = 3;
@@ -1,21 +1,37 @@
-use std::{collections::HashMap, fs, io::Write, mem};
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::mem;
 
 use crate::{
-    globals::{RELA_TEXT_USERS, USER_DEFINED_SECTIONS, USER_DEFINED_SYMBOLS},
-    utils::any_as_u8_slice,
+    encoder::{Encoder, Instr, Rela},
+    error::{bail, Result},
+    utils::{any_as_u8_slice, HashMap},
 };
 
 use super::constants::{
-    R_X86_64_16, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_8, R_X86_64_PC32, SHF_INFO_LINK,
-    SHT_NULL, SHT_PROGBITS, SHT_RELA, SHT_STRTAB, SHT_SYMTAB, STB_GLOBAL, STB_LOCAL, STT_NOTYPE,
-    STT_SECTION,
+    ELFOSABI_GNU, GRP_COMDAT, R_X86_64_DTPOFF32, R_X86_64_GOTTPOFF, R_X86_64_TPOFF32,
+    SHF_GNU_RETAIN, SHF_GROUP, SHF_INFO_LINK, SHF_MERGE, SHF_STRINGS, SHN_LORESERVE, SHN_XINDEX,
+    SHT_GROUP, SHT_NOBITS, SHT_NULL, SHT_PROGBITS, SHT_RELA, SHT_STRTAB, SHT_SYMTAB,
+    SHT_SYMTAB_SHNDX, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_NOTYPE, STT_SECTION, STT_TLS,
 };
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Elf<'a> {
-    out_file: &'a str,
+    /// Encoder which has already assigned addresses.
+    encoder: &'a Encoder,
     /// flag to keep local labels. labels that start from `.L`
     keep_locals: bool,
+    /// Prefixes of the `.L` labels kept even without `keep_locals`. e.g. `.Lfunc`
+    keep_locals_matching: &'a [String],
+    /// Contents of `.comment`. Empty if no `.comment` is stamped.
+    comment: Vec<u8>,
+    /// Emit the relocations resolved by the assembler too. e.g. `jmp` to a local label
+    emit_relocs: bool,
     // Elf header
     ehdr: Elf64Ehdr,
     /// symtab symbol index
@@ -26,16 +42,31 @@ pub struct Elf<'a> {
     rela_symbols: Vec<String>,
     /// list of user-defined section names
     user_defined_section_names: Vec<String>,
+    /// `.group` sections. They precede the user-defined sections because a group must precede its members.
+    groups: Vec<Group>,
     user_defined_section_idx: HashMap<String, usize>,
     section_name_offs: HashMap<String, usize>,
     strtab: Vec<u8>,
     symtab: Vec<Elf64Sym>,
+    /// `.symtab_shndx`, the section index of each symbol. Empty if all of them fit in `st_shndx`.
+    symtab_shndx: Vec<u32>,
     rela_section_names: Vec<String>,
     rela: HashMap<String, Vec<Elf64Rela>>,
     shstrtab: Vec<u8>,
     section_headers: Vec<Elf64Shdr>,
 }
 
+/// `SHT_GROUP` section of the sections with the same signature.
+#[derive(Clone, Debug, Default)]
+struct Group {
+    signature: String,
+    is_comdat: bool,
+    /// Names of the member sections. Their relocation sections are members too.
+    members: Vec<String>,
+    /// The flag word followed by the member section indexes. It is built by `build_headers`.
+    contents: Vec<u32>,
+}
+
 /// [File header](https://en.wikipedia.org/wiki/Executable_and_Linkable_Format#:~:text=header%5B4%5D-,File%20header,-edit)
 #[repr(C)] // To prevent auto organize fields.
 #[derive(Clone, Debug, Default)]
@@ -85,7 +116,7 @@ struct Elf64Shdr {
 
 /// Reallocation entries
 #[repr(C)] // To prevent auto organize fields.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Elf64Rela {
     r_offset: u64,
     r_info: u64,
@@ -116,54 +147,167 @@ struct Elf64Phdr {
 }
 
 impl<'a> Elf<'a> {
-    pub fn new(out_file: &'a str, keep_locals: bool) -> Self {
+    pub fn new(encoder: &'a Encoder, keep_locals: bool) -> Self {
         let mut e = Self {
-            out_file,
+            encoder,
             keep_locals,
-            ..Default::default()
+            keep_locals_matching: &[],
+            comment: Default::default(),
+            emit_relocs: false,
+            ehdr: Default::default(),
+            symtab_symbol_indexes: Default::default(),
+            local_symbols_count: Default::default(),
+            rela_symbols: Default::default(),
+            user_defined_section_names: Default::default(),
+            groups: Default::default(),
+            user_defined_section_idx: Default::default(),
+            section_name_offs: Default::default(),
+            strtab: Default::default(),
+            symtab: Default::default(),
+            symtab_shndx: Default::default(),
+            rela_section_names: Default::default(),
+            rela: Default::default(),
+            shstrtab: Default::default(),
+            section_headers: Default::default(),
         };
 
-        for (name, _) in USER_DEFINED_SECTIONS.lock().unwrap().iter() {
-            e.user_defined_section_names.push(name.clone());
-            e.user_defined_section_idx
-                .insert(name.clone(), e.user_defined_section_idx.len() + 1);
+        e.user_defined_section_names = encoder.section_names();
+        for name in &e.user_defined_section_names {
+            let Some(group) = &encoder.user_defined_sections[name].group else {
+                continue;
+            };
+            match e.groups.iter_mut().find(|g| g.signature == group.signature) {
+                Some(g) => g.members.push(name.clone()),
+                None => e.groups.push(Group {
+                    signature: group.signature.clone(),
+                    is_comdat: group.is_comdat,
+                    members: vec![name.clone()],
+                    ..Default::default()
+                }),
+            }
+        }
+        for name in &e.user_defined_section_names {
+            e.user_defined_section_idx.insert(
+                name.clone(),
+                e.groups.len() + e.user_defined_section_idx.len() + 1,
+            );
         }
 
         e
     }
+
+    /// Keep the `.L` labels which start with any of `prefixes`.
+    pub fn keep_locals_matching(&mut self, prefixes: &'a [String]) {
+        self.keep_locals_matching = prefixes;
+    }
+
+    /// Stamp `.comment` with the assembler identity. e.g. `ras 0.1.0`
+    ///
+    /// It is not stamped if the source has its own `.comment`.
+    pub fn comment(&mut self, identity: &str) {
+        if !self.encoder.user_defined_sections.contains_key(".comment") {
+            self.comment = format!("{identity}\0").into_bytes();
+        }
+    }
+
+    /// Keep the relocations resolved by the assembler in the relocation sections.
+    ///
+    /// Their values are already filled in the code, so the linker writes the same values.
+    pub fn emit_relocs(&mut self) {
+        self.emit_relocs = true;
+    }
+
+    /// Is the relocation left out of the relocation sections?
+    fn is_omitted(&self, rela: &Rela) -> bool {
+        rela.is_already_resolved && !self.emit_relocs
+    }
+
+    /// Is `name` a local label dropped from `.symtab`? e.g. `.L1`
+    fn is_dropped_local(&self, name: &str) -> bool {
+        !self.keep_locals
+            && name.to_uppercase().starts_with(".L")
+            && !self
+                .keep_locals_matching
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()))
+    }
 }
 
 pub fn align_to(n: usize, align: usize) -> usize {
-    (n + align - 1) / align * align
+    n.div_ceil(align) * align
+}
+
+/// `.bss` like sections occupy no space in the file.
+/// Does the section occupy no space in the file? e.g. `.bss`, `.bss.foo`, `.tbss`
+pub(crate) fn is_nobits_section(name: &str) -> bool {
+    [".bss", ".tbss"].iter().any(|base| {
+        name.strip_prefix(base)
+            .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('.'))
+    })
+}
+
+fn section_type(name: &str) -> u32 {
+    match is_nobits_section(name) {
+        true => SHT_NOBITS,
+        false => SHT_PROGBITS,
+    }
+}
+
+/// Does the relocation refer to a thread-local variable? e.g. `%fs:foo@tpoff`
+fn is_tls_relocation(rtype: u64) -> bool {
+    matches!(
+        rtype,
+        R_X86_64_TPOFF32 | R_X86_64_DTPOFF32 | R_X86_64_GOTTPOFF
+    )
+}
+
+/// Is a relocation against `symbol` made against its section symbol instead?
+///
+/// The local symbols may be dropped from `.symtab`(e.g. `.L1`), but the offsets of the thread-local variables
+/// are relative to the TLS block, not to their sections.
+fn is_relocated_by_section(symbol: &Instr) -> bool {
+    symbol.binding == STB_LOCAL && symbol.symbol_type != STT_TLS
 }
 
 fn add_padding(code: &mut Vec<u8>) {
-    let padding = align_to(code.len(), 16) - code.len();
-    code.extend(std::iter::repeat(0).take(padding));
+    code.resize(align_to(code.len(), 16), 0);
 }
 
 impl Elf<'_> {
+    /// `st_shndx` of the next symbol in the section `idx`.
+    ///
+    /// The index over the reserved range is stored in `.symtab_shndx` instead.
+    fn symbol_shndx(&mut self, idx: usize) -> u16 {
+        if idx < SHN_LORESERVE {
+            return idx as u16;
+        }
+        self.symtab_shndx.resize(self.symtab.len(), 0);
+        self.symtab_shndx.push(idx as u32);
+        SHN_XINDEX
+    }
+
     fn elf_symbol(&mut self, symbol_binding: u8, off: &mut usize, string: &mut String) {
-        for (symbol_name, symbol) in USER_DEFINED_SYMBOLS.lock().unwrap().clone() {
+        // Sort by name to get the same output for the same input.
+        let mut symbols: Vec<_> = self.encoder.user_defined_symbols.iter().collect();
+        symbols.sort_by_key(|(name, _)| *name);
+
+        for (symbol_name, symbol) in symbols {
             if symbol.binding != symbol_binding {
                 continue;
             }
 
             if symbol.binding == STB_LOCAL {
-                if !self.keep_locals
-                    && symbol.binding == STB_LOCAL
-                    && symbol_name.to_uppercase().starts_with(".L")
-                {
+                if self.is_dropped_local(symbol_name) {
                     continue;
                 }
                 self.local_symbols_count += 1;
             }
 
             self.symtab_symbol_indexes
-                .insert(symbol_name.clone(), self.symtab_symbol_indexes.len());
+                .insert(symbol_name.to_string(), self.symtab_symbol_indexes.len());
 
             *off += string.len() + 1;
-            let st_shndx = self.user_defined_section_idx[&symbol.section_name] as u16;
+            let st_shndx = self.symbol_shndx(self.user_defined_section_idx[&symbol.section_name]);
             let st_name = if symbol.symbol_type == STT_SECTION {
                 0
             } else {
@@ -176,24 +320,106 @@ impl Elf<'_> {
                 st_other: symbol.visibility,
                 st_shndx,
                 st_value: symbol.addr,
-                ..Default::default()
+                st_size: symbol.size as u64,
             });
 
             self.strtab
                 .extend_from_slice(format!("{symbol_name}\0").as_bytes());
-            *string = symbol_name;
+            *string = symbol_name.to_string();
+        }
+    }
+
+    /// Add `STT_SECTION` symbols of the sections which have local symbols referred by relocations.
+    ///
+    /// The local symbols may be dropped from `.symtab`(e.g. `.L1`), so they are relocated by the section symbols.
+    /// The section symbols have no name, and are indexed by the section names.
+    fn elf_section_symbol(&mut self) {
+        let referred: Vec<String> = self
+            .user_defined_section_names
+            .iter()
+            .filter(|name| {
+                self.encoder.rela_text_users.iter().any(|rela| {
+                    !self.is_omitted(rela)
+                        && self
+                            .encoder
+                            .user_defined_symbols
+                            .get(&rela.uses)
+                            .is_some_and(|s| is_relocated_by_section(s) && s.section_name == **name)
+                })
+            })
+            .cloned()
+            .collect();
+
+        for name in referred {
+            self.local_symbols_count += 1;
+            let st_shndx = self.symbol_shndx(self.user_defined_section_idx[&name]);
+            self.symtab_symbol_indexes
+                .insert(name, self.symtab_symbol_indexes.len());
+            self.symtab.push(Elf64Sym {
+                st_name: 0,
+                st_info: (STB_LOCAL << 4) + (STT_SECTION & 0xf),
+                st_shndx,
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Add local symbols in the `.group` sections for the group signatures which are not defined.
+    fn elf_group_symbol(&mut self, off: &mut usize, string: &mut String) {
+        for (idx, group) in self.groups.iter().enumerate() {
+            let signature = &group.signature;
+            let is_in_symtab = self.rela_symbols.contains(signature)
+                || self
+                    .encoder
+                    .user_defined_symbols
+                    .get(signature)
+                    .is_some_and(|s| s.binding != STB_LOCAL || !self.is_dropped_local(signature));
+            if is_in_symtab {
+                continue;
+            }
+
+            *off += string.len() + 1;
+            self.symtab_symbol_indexes
+                .insert(signature.clone(), self.symtab_symbol_indexes.len());
+            self.local_symbols_count += 1;
+            self.symtab.push(Elf64Sym {
+                st_name: *off as u32,
+                st_info: (STB_LOCAL << 4) + (STT_NOTYPE & 0xf),
+                st_shndx: (idx + 1) as u16,
+                ..Default::default()
+            });
+
+            self.strtab
+                .extend_from_slice(format!("{signature}\0").as_bytes());
+            *string = signature.clone();
         }
     }
 
     fn elf_rela_symbol(&mut self, off: &mut usize, string: &mut String) {
         for symbol_name in &self.rela_symbols {
+            // An undefined thread-local variable is typed by the relocations against it.
+            let symbol_type = match self
+                .encoder
+                .rela_text_users
+                .iter()
+                .any(|rela| rela.uses == *symbol_name && is_tls_relocation(rela.rtype))
+            {
+                true => STT_TLS,
+                false => STT_NOTYPE,
+            };
+            let binding = self
+                .encoder
+                .undefined_bindings
+                .get(symbol_name)
+                .copied()
+                .unwrap_or(STB_GLOBAL);
             *off += string.len() + 1;
             self.symtab_symbol_indexes
                 .insert(symbol_name.to_owned(), self.symtab_symbol_indexes.len());
 
             self.symtab.push(Elf64Sym {
                 st_name: *off as u32,
-                st_info: (STB_GLOBAL << 4) + (STT_NOTYPE & 0xf),
+                st_info: (binding << 4) + (symbol_type & 0xf),
                 st_shndx: 0,
                 ..Default::default()
             });
@@ -205,49 +431,37 @@ impl Elf<'_> {
     }
 
     pub fn rela_text_users(&mut self) {
-        for r in RELA_TEXT_USERS.lock().unwrap().clone() {
-            let mut index = 0;
-            let mut r_addend = if [
-                R_X86_64_32S,
-                R_X86_64_32,
-                R_X86_64_64,
-                R_X86_64_32,
-                R_X86_64_16,
-                R_X86_64_8,
-            ]
-            .contains(&r.rtype)
-            {
-                0
-            } else if r.rtype == R_X86_64_PC32 {
-                (r.offset - r.instr.code.len()) as i64
-            } else {
-                -4
-            };
+        for r in &self.encoder.rela_text_users {
+            let mut r_addend = r.addend();
 
             // Skip already resolved instruction.
-            if r.is_already_resolved {
+            if self.is_omitted(r) {
                 continue;
             }
 
-            if let Some(s) = USER_DEFINED_SYMBOLS.lock().unwrap().get(&r.uses) {
-                if s.binding == STB_GLOBAL {
-                    index = self.symtab_symbol_indexes[&r.uses];
-                } else {
+            let index = match self.encoder.user_defined_symbols.get(&r.uses) {
+                // Non-local symbols may be preempted, so they are relocated by themselves.
+                Some(s) if is_relocated_by_section(s) => {
                     r_addend += s.addr as i64;
-                    index = self.symtab_symbol_indexes[&s.section_name];
+                    self.symtab_symbol_indexes[&s.section_name]
                 }
-            } else {
-                index = self.symtab_symbol_indexes[&r.uses];
-            }
+                _ => self.symtab_symbol_indexes[&r.uses],
+            };
 
             let rela_section_name = format!(".rela{}", r.instr.section_name);
+            log::debug!(
+                "relocation created {rela_section_name}+{:#x}: type {} `{}` {r_addend:+}",
+                r.instr.addr + r.offset,
+                r.rtype,
+                r.uses
+            );
             self.rela
                 .entry(rela_section_name.clone())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(Elf64Rela {
                     r_offset: (r.instr.addr + r.offset) as u64,
                     r_info: ((index as u64) << 32) + r.rtype,
-                    r_addend: r_addend + r.adjust as i64,
+                    r_addend,
                 });
 
             if !self.rela_section_names.contains(&rela_section_name) {
@@ -257,18 +471,27 @@ impl Elf<'_> {
     }
 
     pub fn collect_rela_symbols(&mut self) {
-        for rela in RELA_TEXT_USERS.lock().unwrap().clone() {
-            if !self.rela_symbols.contains(&rela.uses.to_owned()) {
-                if USER_DEFINED_SYMBOLS
-                    .lock()
-                    .unwrap()
-                    .contains_key(&rela.uses)
-                {
+        for rela in &self.encoder.rela_text_users {
+            // `.reloc` without symbol refers to the null symbol.
+            if rela.uses.is_empty() {
+                continue;
+            }
+            if !self.rela_symbols.contains(&rela.uses) {
+                if self.encoder.user_defined_symbols.contains_key(&rela.uses) {
                     continue;
                 }
                 self.rela_symbols.push(rela.uses.to_string());
             }
         }
+
+        // The declared external symbols are kept without any use. Sort them to get the same output.
+        let mut declared: Vec<_> = self.encoder.undefined_bindings.keys().collect();
+        declared.sort();
+        for name in declared {
+            if !self.rela_symbols.contains(name) {
+                self.rela_symbols.push(name.clone());
+            }
+        }
     }
 
     pub fn build_symtab_strtab(&mut self) {
@@ -283,13 +506,20 @@ impl Elf<'_> {
             .insert(String::new(), self.symtab_symbol_indexes.len());
         self.local_symbols_count += 1;
 
+        self.elf_section_symbol();
+
         let mut off = 0;
         let mut string = String::new();
 
         self.elf_symbol(STB_LOCAL, &mut off, &mut string); // local
+        self.elf_group_symbol(&mut off, &mut string); // group signature
         self.elf_rela_symbol(&mut off, &mut string); // rela local
         self.elf_symbol(STB_GLOBAL, &mut off, &mut string); // global
+        self.elf_symbol(STB_WEAK, &mut off, &mut string); // weak
 
+        if !self.symtab_shndx.is_empty() {
+            self.symtab_shndx.resize(self.symtab.len(), 0);
+        }
         add_padding(&mut self.strtab);
     }
 
@@ -300,6 +530,12 @@ impl Elf<'_> {
 
         // custom sections
         let mut name_offs = 1;
+        if !self.groups.is_empty() {
+            self.section_name_offs
+                .insert(".group".to_string(), name_offs);
+            name_offs += ".group".len() + 1;
+            self.shstrtab.extend_from_slice(b".group\0");
+        }
         for name in &self.user_defined_section_names {
             self.section_name_offs.insert(name.clone(), name_offs);
             name_offs += name.len() + 1;
@@ -316,7 +552,8 @@ impl Elf<'_> {
             self.shstrtab.push(0x00);
         }
 
-        for name in self.rela.keys() {
+        // In order of the headers, not of the map, to keep the output reproducible.
+        for name in &self.rela_section_names {
             self.section_name_offs.insert(name.clone(), name_offs);
             name_offs += name.len() + 1;
 
@@ -324,10 +561,22 @@ impl Elf<'_> {
             self.shstrtab.push(0x00);
         }
 
+        if !self.comment.is_empty() {
+            self.section_name_offs
+                .insert(".comment".to_string(), name_offs);
+            name_offs += ".comment".len() + 1;
+            self.shstrtab.extend_from_slice(b".comment\0");
+        }
+        if !self.symtab_shndx.is_empty() {
+            self.section_name_offs
+                .insert(".symtab_shndx".to_string(), name_offs);
+            self.shstrtab.extend_from_slice(b".symtab_shndx\0");
+        }
+
         add_padding(&mut self.shstrtab);
     }
 
-    pub fn build_headers(&mut self) {
+    pub fn build_headers(&mut self) -> Result<()> {
         let mut section_offs = mem::size_of::<Elf64Ehdr>();
         let mut section_idx = HashMap::new();
         section_idx.insert(String::new(), 0);
@@ -339,29 +588,89 @@ impl Elf<'_> {
             ..Default::default()
         });
 
+        // .group
+        let symtab_idx = self.groups.len() + self.user_defined_section_names.len() + 2;
+        for group in &mut self.groups {
+            group.contents = vec![if group.is_comdat { GRP_COMDAT } else { 0 }];
+            for member in &group.members {
+                group
+                    .contents
+                    .push(self.user_defined_section_idx[member] as u32);
+            }
+            for member in &group.members {
+                let rela_name = format!(".rela{member}");
+                if let Some(pos) = self.rela_section_names.iter().position(|n| *n == rela_name) {
+                    group.contents.push((symtab_idx + 1 + pos) as u32);
+                }
+            }
+
+            let size = mem::size_of::<u32>() * group.contents.len();
+            self.section_headers.push(Elf64Shdr {
+                sh_name: self.section_name_offs[".group"] as u32,
+                sh_type: SHT_GROUP,
+                sh_offset: section_offs,
+                sh_size: size,
+                sh_link: symtab_idx as u32,
+                sh_info: self.symtab_symbol_indexes[&group.signature] as u32,
+                sh_addralign: 4,
+                sh_entsize: mem::size_of::<u32>(),
+                ..Default::default()
+            });
+            section_offs += size;
+            // All the groups are named `.group`, so they are indexed by the signatures.
+            section_idx.insert(format!(".group {}", group.signature), section_idx.len());
+        }
+
         // user-defined sections
         for name in &self.user_defined_section_names {
-            let user_symbols = USER_DEFINED_SECTIONS.lock().unwrap();
-            let section = match user_symbols.get(name) {
+            let section = match self.encoder.user_defined_sections.get(name) {
                 Some(section) => section,
                 None => panic!("unkown section {name}"),
             };
 
+            let sh_type = section_type(name);
+            // The linker places the section at this alignment, and the file offset follows it as GNU as does.
+            let sh_addralign = section.max_align.max(1);
+            if sh_type != SHT_NOBITS {
+                section_offs = align_to(section_offs, sh_addralign);
+            }
+            // `SHF_LINK_ORDER` section is linked to the section of the symbol.
+            let sh_link = match &section.linked_to {
+                Some(symbol) => match self.user_defined_section_idx.get(symbol).or_else(|| {
+                    let s = self.encoder.user_defined_symbols.get(symbol)?;
+                    self.user_defined_section_idx.get(&s.section_name)
+                }) {
+                    Some(idx) => *idx as u32,
+                    None => bail!("section `{name}` is linked to undefined symbol `{symbol}`"),
+                },
+                None => 0,
+            };
             self.section_headers.push(Elf64Shdr {
                 sh_name: self.section_name_offs[name] as u32,
-                sh_type: SHT_PROGBITS,
+                sh_type,
                 sh_flags: section.flags,
+                sh_link,
                 sh_offset: section_offs,
-                sh_size: section.code.len(),
-                sh_addralign: 1,
+                // The bytes of a nobits section are not in `code`, but counted in its laid out size.
+                sh_size: match sh_type {
+                    SHT_NOBITS => section.addr,
+                    _ => section.code.len(),
+                },
+                sh_addralign,
+                sh_entsize: section.entsize,
                 ..Default::default()
             });
-            section_offs += section.code.len();
+            if sh_type != SHT_NOBITS {
+                section_offs += section.code.len();
+            }
             section_idx.insert(name.clone(), section_idx.len());
         }
 
+        // `.symtab` entries must be 8 bytes aligned.
+        section_offs = align_to(section_offs, 8);
         let strtab_ofs = section_offs;
         let strtab_size = self.strtab.len();
+        section_offs += strtab_size;
         section_idx.insert(".strtab".to_string(), section_idx.len());
 
         // strtab
@@ -375,7 +684,8 @@ impl Elf<'_> {
         });
 
         let symtab_ofs = section_offs;
-        let symtab_size = mem::size_of::<Elf64Sym>() * self.strtab.len();
+        let symtab_size = mem::size_of::<Elf64Sym>() * self.symtab.len();
+        section_offs += symtab_size;
         section_idx.insert(".symtab".to_string(), section_idx.len());
 
         // .symbtab
@@ -393,22 +703,61 @@ impl Elf<'_> {
 
         // Add rela ... to section headers
         for name in &self.rela_section_names {
+            // target section index. if `.rela.text.hot` the target will be `.text.hot`
+            let target = match name.strip_prefix(".rela").and_then(|t| section_idx.get(t)) {
+                Some(idx) => *idx as u32,
+                None => bail!("rela section `{name}` has no target section"),
+            };
+            // The relocations of a member section are discarded together with it.
+            let group_flag = self.section_headers[target as usize].sh_flags & SHF_GROUP;
             let size = self.rela[name].len() * mem::size_of::<Elf64Rela>();
             self.section_headers.push(Elf64Shdr {
                 sh_name: self.section_name_offs[name] as u32,
                 sh_type: SHT_RELA,
-                sh_flags: SHF_INFO_LINK,
+                sh_flags: SHF_INFO_LINK | group_flag,
                 sh_addr: 0,
                 sh_offset: section_offs,
                 sh_size: size,
                 sh_link: section_idx[".symtab"] as u32,
-                sh_info: section_idx[&name[5..]] as u32, // target section index. if `.rela.text` the target will be `.text`
+                sh_info: target,
                 sh_addralign: 8,
                 sh_entsize: mem::size_of::<Elf64Rela>(),
             });
             section_offs += size;
         }
 
+        // .symtab_shndx
+        if !self.symtab_shndx.is_empty() {
+            let size = mem::size_of::<u32>() * self.symtab_shndx.len();
+            self.section_headers.push(Elf64Shdr {
+                sh_name: self.section_name_offs[".symtab_shndx"] as u32,
+                sh_type: SHT_SYMTAB_SHNDX,
+                sh_offset: section_offs,
+                sh_size: size,
+                sh_link: section_idx[".symtab"] as u32,
+                sh_addralign: 4,
+                sh_entsize: mem::size_of::<u32>(),
+                ..Default::default()
+            });
+            // The entries are 4 bytes, so an odd number of symbols leaves the offset unaligned.
+            section_offs = align_to(section_offs + size, 8);
+        }
+
+        // .comment
+        if !self.comment.is_empty() {
+            self.section_headers.push(Elf64Shdr {
+                sh_name: self.section_name_offs[".comment"] as u32,
+                sh_type: SHT_PROGBITS,
+                sh_flags: SHF_MERGE | SHF_STRINGS,
+                sh_offset: section_offs,
+                sh_size: self.comment.len(),
+                sh_addralign: 1,
+                sh_entsize: 1,
+                ..Default::default()
+            });
+            section_offs += self.comment.len();
+        }
+
         // .shstrtab
         self.section_headers.push(Elf64Shdr {
             sh_name: self.section_name_offs[".shstrtab"] as u32,
@@ -423,7 +772,35 @@ impl Elf<'_> {
             sh_entsize: 0,
         });
 
-        let sectionheader_ofs = section_offs + self.shstrtab.len();
+        // The section headers must be 8 bytes aligned, but `.comment` and `.shstrtab` are not.
+        let sectionheader_ofs = align_to(section_offs + self.shstrtab.len(), 8);
+        // Over the reserved range, the null section header has the number of sections and the index of
+        // `.shstrtab`.
+        let shnum = self.section_headers.len();
+        let shstrndx = shnum - 1;
+        let e_shnum = match shnum < SHN_LORESERVE {
+            true => shnum as u16,
+            false => {
+                self.section_headers[0].sh_size = shnum;
+                0
+            }
+        };
+        let e_shstrndx = match shstrndx < SHN_LORESERVE {
+            true => shstrndx as u16,
+            false => {
+                self.section_headers[0].sh_link = shstrndx as u32;
+                SHN_XINDEX
+            }
+        };
+        // `SHF_GNU_RETAIN` is a GNU extension, so the object declares the GNU ABI as GNU as does.
+        let os_abi = match self
+            .section_headers
+            .iter()
+            .any(|sh| sh.sh_flags & SHF_GNU_RETAIN != 0)
+        {
+            true => ELFOSABI_GNU,
+            false => 0,
+        };
 
         // elf header
         self.ehdr = Elf64Ehdr {
@@ -431,7 +808,8 @@ impl Elf<'_> {
                 0x7f, 0x45, 0x4c, 0x46, // Magic number ' ELF' in ascii format
                 0x02, // 2 = 64-bit
                 0x01, // 1 = little endian
-                0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, // ELF version
+                os_abi, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             ],
             e_type: 1, // 1 = re allocatable
             e_machine: 0x3e,
@@ -444,56 +822,673 @@ impl Elf<'_> {
             e_phentsize: mem::size_of::<Elf64Phdr>() as u16,
             e_phnum: 0,
             e_shentsize: mem::size_of::<Elf64Shdr>() as u16,
-            e_shnum: self.section_headers.len() as u16,
-            e_shstrndx: (self.section_headers.len() - 1) as u16,
+            e_shnum,
+            e_shstrndx,
+        };
+        Ok(())
+    }
+
+    /// File offset of each user-defined section which has bytes in the object, after [`Self::build_headers`].
+    pub fn section_offsets(&self) -> HashMap<String, usize> {
+        self.user_defined_section_names
+            .iter()
+            .zip(&self.section_headers[self.groups.len() + 1..])
+            .filter(|(_, sh)| sh.sh_type != SHT_NOBITS)
+            .map(|(name, sh)| (name.clone(), sh.sh_offset))
+            .collect()
+    }
+
+    /// Check the consistency of the headers built by `build_headers` not to write a corrupt object.
+    pub fn validate(&self) -> Result<()> {
+        let shnum = self.section_headers.len();
+        let e_shnum = match self.ehdr.e_shnum {
+            0 => self.section_headers[0].sh_size,
+            e_shnum => e_shnum as usize,
+        };
+        if e_shnum != shnum {
+            bail!("e_shnum is {e_shnum}, but there are {shnum} section headers");
+        }
+
+        let shstrndx = match self.ehdr.e_shstrndx {
+            SHN_XINDEX => self.section_headers[0].sh_link as usize,
+            e_shstrndx => e_shstrndx as usize,
+        };
+        match self.section_headers.get(shstrndx) {
+            Some(shdr)
+                if shdr.sh_type == SHT_STRTAB
+                    && shdr.sh_name as usize == self.section_name_offs[".shstrtab"] => {}
+            _ => bail!("e_shstrndx {shstrndx} does not point at `.shstrtab`"),
+        }
+
+        for (idx, shdr) in self.section_headers.iter().enumerate() {
+            if shdr.sh_link as usize >= shnum {
+                bail!("sh_link {} of section {idx} is out of range", shdr.sh_link);
+            }
+
+            match shdr.sh_type {
+                SHT_SYMTAB if shdr.sh_info as usize > self.symtab.len() => bail!(
+                    "sh_info {} of `.symtab` exceeds the number of symbols",
+                    shdr.sh_info
+                ),
+                SHT_SYMTAB_SHNDX if shdr.sh_size / mem::size_of::<u32>() != self.symtab.len() => {
+                    bail!("`.symtab_shndx` does not have an entry for each symbol")
+                }
+                SHT_GROUP if shdr.sh_info as usize >= self.symtab.len() => bail!(
+                    "sh_info {} of group section {idx} is not a symbol",
+                    shdr.sh_info
+                ),
+                SHT_RELA => {
+                    let target = shdr.sh_info as usize;
+                    if target == 0 || target >= shnum {
+                        bail!("sh_info {target} of rela section {idx} is not a section");
+                    }
+                }
+                _ => {}
+            }
         }
+        Ok(())
     }
 
-    pub fn write_elf(&self) {
-        let mut fp = fs::File::create(self.out_file)
-            .unwrap_or_else(|_| panic!("Error opening file '{}'", self.out_file));
+    /// Write the ELF object into a byte buffer.
+    pub fn write_elf(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
 
         // Write ELF header
-        fp.write_all(unsafe { any_as_u8_slice(&self.ehdr) })
-            .expect("Error writing ELF header");
+        buf.extend_from_slice(unsafe { any_as_u8_slice(&self.ehdr) });
+
+        // Write .group
+        for (group, sh) in self.groups.iter().zip(&self.section_headers[1..]) {
+            buf.resize(sh.sh_offset, 0);
+            for word in &group.contents {
+                buf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
 
         // Write user-defined sections
-        let user_sections = USER_DEFINED_SECTIONS.lock().unwrap();
-        for name in &self.user_defined_section_names {
-            let section = user_sections
-                .get(name)
-                .unwrap_or_else(|| panic!("Unknown section '{}'", name));
-            fp.write_all(&section.code)
-                .unwrap_or_else(|_| panic!("Error writing section '{}'", name));
+        for (name, sh) in self
+            .user_defined_section_names
+            .iter()
+            .zip(&self.section_headers[self.groups.len() + 1..])
+        {
+            if sh.sh_type == SHT_NOBITS {
+                continue;
+            }
+            buf.resize(sh.sh_offset, 0);
+            buf.extend_from_slice(&self.encoder.user_defined_sections[name].code);
         }
 
         // Write .strtab
-        fp.write_all(&self.strtab).expect("Error writing '.strtab'");
+        let strtab_idx = self.groups.len() + self.user_defined_section_names.len() + 1;
+        buf.resize(self.section_headers[strtab_idx].sh_offset, 0);
+        buf.extend_from_slice(&self.strtab);
 
         // Write .symtab
         for s in &self.symtab {
-            fp.write_all(unsafe { any_as_u8_slice(&s) })
-                .expect("Error writing '.symtab'");
+            buf.extend_from_slice(unsafe { any_as_u8_slice(s) });
         }
 
         // Write relocation sections
         for name in &self.rela_section_names {
             if let Some(rela_section) = self.rela.get(name) {
                 for r in rela_section {
-                    fp.write_all(unsafe { any_as_u8_slice(&r) })
-                        .expect("Error writing '.rela.text'");
+                    buf.extend_from_slice(unsafe { any_as_u8_slice(r) });
                 }
             }
         }
 
+        // Write .symtab_shndx
+        for idx in &self.symtab_shndx {
+            buf.extend_from_slice(&idx.to_le_bytes());
+        }
+        if !self.symtab_shndx.is_empty() {
+            buf.resize(align_to(buf.len(), 8), 0);
+        }
+
+        // Write .comment
+        buf.extend_from_slice(&self.comment);
+
         // Write .shstrtab
-        fp.write_all(&self.shstrtab)
-            .expect("Error writing '.shstrtab'");
+        buf.extend_from_slice(&self.shstrtab);
 
         // Write section headers
+        buf.resize(self.ehdr.e_shoff, 0);
         for sh in &self.section_headers {
-            fp.write_all(unsafe { any_as_u8_slice(sh) })
-                .expect("Error writing section headers");
+            buf.extend_from_slice(unsafe { any_as_u8_slice(sh) });
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::constants::{
+        R_X86_64_16, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_8, R_X86_64_GOTPCREL,
+        R_X86_64_PC32, R_X86_64_PC64, R_X86_64_PLT32, R_X86_64_REX_GOTPCRELX, SHF_ALLOC,
+        SHF_EXECINSTR, SHF_TLS, SHF_WRITE,
+    };
+    use crate::encoder::{parse, parse_with};
+    use crate::error::Result;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    /// Lay out `encoder`, and build its ELF object up to the validation.
+    fn build(encoder: &mut Encoder) -> Result<Elf<'_>> {
+        encoder.assign_addresses()?;
+        let mut e = Elf::new(encoder, false);
+        if encoder.options.emit_relocs {
+            e.emit_relocs();
+        }
+        e.collect_rela_symbols();
+        e.build_symtab_strtab();
+        e.rela_text_users();
+        e.build_shstrtab();
+        e.build_headers()?;
+        e.validate()?;
+        Ok(e)
+    }
+
+    #[test]
+    fn should_assign_bss_symbol_values() -> Result<()> {
+        let mut encoder = parse(tokenize(".lcomm a, 8\n.lcomm b, 16\n.lcomm c, 3\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let symbol = |name: &str| &e.symtab[e.symtab_symbol_indexes[name]];
+        assert_eq!((0, 8), (symbol("a").st_value, symbol("a").st_size));
+        assert_eq!((8, 16), (symbol("b").st_value, symbol("b").st_size));
+        assert_eq!((24, 3), (symbol("c").st_value, symbol("c").st_size));
+
+        let bss_idx = e.user_defined_section_idx[".bss"];
+        let bss = &e.section_headers[bss_idx];
+        assert_eq!(SHT_NOBITS, bss.sh_type);
+        assert_eq!(27, bss.sh_size);
+        assert_eq!(8, bss.sh_addralign);
+        // `.bss` occupies no space in the file. So `.strtab` starts at the same offset.
+        assert_eq!(bss.sh_offset, e.section_headers[bss_idx + 1].sh_offset);
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_default_section_flags() -> Result<()> {
+        let src = ".data\n.byte 1\n.rodata\n.byte 2\n.bss\n.skip 4\n.text\nnop\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let section = |name: &str| &e.section_headers[e.user_defined_section_idx[name]];
+        assert_eq!(SHF_ALLOC | SHF_EXECINSTR, section(".text").sh_flags);
+        assert_eq!(SHF_ALLOC | SHF_WRITE, section(".data").sh_flags);
+        assert_eq!(SHF_ALLOC, section(".rodata").sh_flags);
+        assert_eq!(SHF_ALLOC | SHF_WRITE, section(".bss").sh_flags);
+        assert_eq!(SHT_NOBITS, section(".bss").sh_type);
+        Ok(())
+    }
+
+    #[test]
+    fn should_emit_section_group() -> Result<()> {
+        let src = ".section .text.foo,\"axG\",@progbits,foo,comdat\nfoo:\ncall bar\n\
+                   .section .data.foo,\"awG\",@progbits,foo,comdat\n.quad 1\n\
+                   .section __patch,\"ao\",@progbits,foo\n.byte 1\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let idx = |name: &str| e.user_defined_section_idx[name];
+        assert_eq!(
+            SHF_ALLOC | SHF_EXECINSTR | SHF_GROUP,
+            e.section_headers[idx(".text.foo")].sh_flags
+        );
+        assert_eq!(
+            SHF_ALLOC | SHF_WRITE | SHF_GROUP,
+            e.section_headers[idx(".data.foo")].sh_flags
+        );
+        assert_eq!(
+            idx(".text.foo") as u32,
+            e.section_headers[idx("__patch")].sh_link
+        );
+
+        // The group precedes its members, and the relocations of `.text.foo` are in it too.
+        let group = &e.section_headers[1];
+        assert_eq!(SHT_GROUP, group.sh_type);
+        assert_eq!(e.symtab_symbol_indexes["foo"] as u32, group.sh_info);
+        let rela_idx = e
+            .section_headers
+            .iter()
+            .position(|header| header.sh_name as usize == e.section_name_offs[".rela.text.foo"])
+            .unwrap();
+        assert_eq!(
+            SHF_INFO_LINK | SHF_GROUP,
+            e.section_headers[rela_idx].sh_flags
+        );
+        assert_eq!(
+            vec![
+                GRP_COMDAT,
+                idx(".text.foo") as u32,
+                idx(".data.foo") as u32,
+                rela_idx as u32
+            ],
+            e.groups[0].contents
+        );
+        assert_eq!(group.sh_size, 4 * e.groups[0].contents.len());
+        Ok(())
+    }
+
+    #[test]
+    fn should_propagate_strongest_alignment() -> Result<()> {
+        let src = "nop\n.align 32\nnop\n.p2align 3\n.data\n.byte 1\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let section = |name: &str| &e.section_headers[e.user_defined_section_idx[name]];
+        assert_eq!(32, section(".text").sh_addralign);
+        assert_eq!(0, section(".text").sh_offset % 32);
+        assert_eq!(1, section(".data").sh_addralign);
+        Ok(())
+    }
+
+    #[test]
+    fn should_inherit_flags_of_suffixed_sections() -> Result<()> {
+        let src = ".section .text.a\nnop\n.section .text.b\nnop\n.section .rodata.str\n.byte 1\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let section = |name: &str| &e.section_headers[e.user_defined_section_idx[name]];
+        assert_eq!(SHF_ALLOC | SHF_EXECINSTR, section(".text.a").sh_flags);
+        assert_eq!(SHF_ALLOC | SHF_EXECINSTR, section(".text.b").sh_flags);
+        assert_eq!(SHF_ALLOC, section(".rodata.str").sh_flags);
+        assert_ne!(
+            e.user_defined_section_idx[".text.a"],
+            e.user_defined_section_idx[".text.b"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_link_rela_to_dotted_section() -> Result<()> {
+        let src = ".section .text.hot\ncall foo\n.text\nnop\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let rela = e
+            .section_headers
+            .iter()
+            .find(|shdr| shdr.sh_type == SHT_RELA)
+            .unwrap();
+        assert_eq!(e.section_name_offs[".rela.text.hot"] as u32, rela.sh_name);
+        assert_eq!(e.user_defined_section_idx[".text.hot"] as u32, rela.sh_info);
+        Ok(())
+    }
+
+    #[test]
+    fn should_extend_section_indexes_over_reserved_range() -> Result<()> {
+        let mut src: String = (0..SHN_LORESERVE)
+            .map(|i| format!(".section .t{i},\"a\"\n"))
+            .collect();
+        src.push_str("last:\n.quad .Lhere\n.Lhere:\n");
+        let mut encoder = parse(tokenize(&src)?)?;
+        let e = build(&mut encoder)?;
+
+        let last_section = format!(".t{}", SHN_LORESERVE - 1);
+        let last_idx = e.user_defined_section_idx[&last_section];
+        assert!(last_idx >= SHN_LORESERVE);
+        // The section symbol for `.Lhere`, and `last`
+        for name in [last_section.as_str(), "last"] {
+            let sym_idx = e.symtab_symbol_indexes[name];
+            assert_eq!(SHN_XINDEX, e.symtab[sym_idx].st_shndx);
+            assert_eq!(last_idx as u32, e.symtab_shndx[sym_idx]);
+        }
+        assert_eq!(e.symtab.len(), e.symtab_shndx.len());
+        assert_eq!(0, e.symtab_shndx[0]);
+
+        let shndx = e
+            .section_headers
+            .iter()
+            .find(|shdr| shdr.sh_type == SHT_SYMTAB_SHNDX)
+            .unwrap();
+        assert_eq!(
+            SHT_SYMTAB,
+            e.section_headers[shndx.sh_link as usize].sh_type
+        );
+        // The number of sections and the index of `.shstrtab` are in the null section header.
+        assert_eq!(0, e.ehdr.e_shnum);
+        assert_eq!(SHN_XINDEX, e.ehdr.e_shstrndx);
+        assert_eq!(e.section_headers.len(), e.section_headers[0].sh_size);
+        assert_eq!(
+            e.section_headers.len() - 1,
+            e.section_headers[0].sh_link as usize
+        );
+        // The section headers follow `.symtab_shndx` of 4 bytes entries at an 8 bytes aligned offset.
+        assert_eq!(1, e.symtab.len() % 2);
+        assert_eq!(0, e.ehdr.e_shoff % 8);
+        let headers_size = e.section_headers.len() * mem::size_of::<Elf64Shdr>();
+        assert_eq!(e.ehdr.e_shoff + headers_size, e.write_elf().len());
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_declared_undefined_symbols() -> Result<()> {
+        let src = ".globl ext\n.weak wext\n.globl used\ncall used\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let symbol = |name: &str| {
+            let sym = &e.symtab[e.symtab_symbol_indexes[name]];
+            (sym.st_info >> 4, sym.st_shndx)
+        };
+        assert_eq!((STB_GLOBAL, 0), symbol("ext"));
+        assert_eq!((STB_WEAK, 0), symbol("wext"));
+        assert_eq!((STB_GLOBAL, 0), symbol("used"));
+        assert_eq!(1, e.local_symbols_count);
+
+        // `.local` cannot declare an external symbol.
+        let mut encoder = parse(tokenize(".local ext\n")?)?;
+        assert!(encoder.assign_addresses().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_pc_relative_data() -> Result<()> {
+        let mut encoder = parse(tokenize(".data\n.byte 1, 2\n.long 0, target - .\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let target_idx = e.symtab_symbol_indexes["target"] as u64;
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 6,
+                r_info: (target_idx << 32) + R_X86_64_PC32,
+                r_addend: 0,
+            }],
+            e.rela[".rela.data"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_pc_relative_data_by_modifier() -> Result<()> {
+        let src = ".data\n.long target@PC32\n.long target - . + 4\n.quad target@PC\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let target_idx = e.symtab_symbol_indexes["target"] as u64;
+        let rela = |r_offset, rtype, r_addend| Elf64Rela {
+            r_offset,
+            r_info: (target_idx << 32) + rtype,
+            r_addend,
+        };
+        assert_eq!(
+            vec![
+                rela(0, R_X86_64_PC32, 0),
+                rela(4, R_X86_64_PC32, 4),
+                rela(8, R_X86_64_PC64, 0)
+            ],
+            e.rela[".rela.data"]
+        );
+
+        assert!(parse(tokenize(".long target@GOT\n")?).is_err());
+        assert!(parse(tokenize(".quad target@PC32\n")?).is_err());
+        assert!(parse(tokenize(".long target@PC - .\n")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_location_counter() -> Result<()> {
+        let src = ".data\n.quad 1, .\n.text\nlea .(%rip), %rax\njmp .\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        // `.` is the place of the data slot, and it is not a symbol.
+        assert!(!e.symtab_symbol_indexes.contains_key("."));
+        let data_idx = e.symtab_symbol_indexes[".data"] as u64;
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 8,
+                r_info: (data_idx << 32) + R_X86_64_64,
+                r_addend: 8,
+            }],
+            e.rela[".rela.data"]
+        );
+        assert!(e.rela.get(".rela.text").is_none_or(Vec::is_empty));
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_local_reference_by_section_symbol() -> Result<()> {
+        let src = ".data\n.skip 8\n.Lfoo:\n.quad 0\n.text\nmovq .Lfoo+4, %rax\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let data_idx = e.symtab_symbol_indexes[".data"];
+        let symbol = &e.symtab[data_idx];
+        assert_eq!((STB_LOCAL << 4) + STT_SECTION, symbol.st_info);
+        assert_eq!(e.user_defined_section_idx[".data"] as u16, symbol.st_shndx);
+        assert_eq!(2, e.local_symbols_count);
+        // `.text` has no local symbol referred, so it has no section symbol.
+        assert!(!e.symtab_symbol_indexes.contains_key(".text"));
+
+        // The offset of `.Lfoo` in `.data` is carried by the addend.
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 4,
+                r_info: ((data_idx as u64) << 32) + R_X86_64_32S,
+                r_addend: 12,
+            }],
+            e.rela[".rela.text"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_thread_local_variable_by_itself() -> Result<()> {
+        let src = ".section .tbss,\"awT\",@nobits\n.skip 4\ncounter:\n.skip 4\n\
+                   .text\nmovl %fs:counter@tpoff, %eax\nmovq ext@gottpoff(%rip), %rax\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        let tbss = &e.section_headers[e.user_defined_section_idx[".tbss"]];
+        assert_eq!(SHT_NOBITS, tbss.sh_type);
+        assert_eq!(SHF_ALLOC | SHF_WRITE | SHF_TLS, tbss.sh_flags);
+
+        // The offset in the TLS block is not relative to the section, so no section symbol is used.
+        let counter_idx = e.symtab_symbol_indexes["counter"];
+        let ext_idx = e.symtab_symbol_indexes["ext"];
+        assert_eq!((STB_LOCAL << 4) + STT_TLS, e.symtab[counter_idx].st_info);
+        assert_eq!((STB_GLOBAL << 4) + STT_TLS, e.symtab[ext_idx].st_info);
+        assert!(!e.symtab_symbol_indexes.contains_key(".tbss"));
+        assert_eq!(
+            vec![
+                Elf64Rela {
+                    r_offset: 4,
+                    r_info: ((counter_idx as u64) << 32) + R_X86_64_TPOFF32,
+                    r_addend: 0,
+                },
+                Elf64Rela {
+                    r_offset: 11,
+                    r_info: ((ext_idx as u64) << 32) + R_X86_64_GOTTPOFF,
+                    r_addend: -4,
+                },
+            ],
+            e.rela[".rela.text"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_resolved_relocation_by_emit_relocs() -> Result<()> {
+        let src = "jmp .L1\nnop\n.L1:\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+        assert!(!e.rela.contains_key(".rela.text"));
+        assert_eq!(vec![0xe9, 0x01, 0x00, 0x00, 0x00], encoder.instrs[0].code);
+
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.options.emit_relocs = true;
+        let e = build(&mut encoder)?;
+
+        // `.L1` is at 6 in `.text`, so the addend is 6 - 4.
+        let text_idx = e.symtab_symbol_indexes[".text"] as u64;
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 1,
+                r_info: (text_idx << 32) + R_X86_64_PLT32,
+                r_addend: 2,
+            }],
+            e.rela[".rela.text"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_carry_addend_of_symbol_in_data() -> Result<()> {
+        let mut encoder = parse(tokenize(".data\n.quad foo+16\n.quad foo-8\n.long foo\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let foo_idx = e.symtab_symbol_indexes["foo"] as u64;
+        let rela = |r_offset, rtype, r_addend| Elf64Rela {
+            r_offset,
+            r_info: (foo_idx << 32) + rtype,
+            r_addend,
+        };
+        assert_eq!(
+            vec![
+                rela(0, R_X86_64_64, 16),
+                rela(8, R_X86_64_64, -8),
+                rela(16, R_X86_64_32, 0),
+            ],
+            e.rela[".rela.data"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_emit_narrow_relocations_for_data() -> Result<()> {
+        let mut encoder = parse(tokenize(".data\n.word foo\n.byte foo\n.2byte foo+1\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let foo_idx = e.symtab_symbol_indexes["foo"] as u64;
+        let rela = |r_offset, rtype, r_addend| Elf64Rela {
+            r_offset,
+            r_info: (foo_idx << 32) + rtype,
+            r_addend,
+        };
+        assert_eq!(
+            vec![
+                rela(0, R_X86_64_16, 0),
+                rela(2, R_X86_64_8, 0),
+                rela(3, R_X86_64_16, 1),
+            ],
+            e.rela[".rela.data"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_inconsistent_headers() -> Result<()> {
+        let mut encoder = parse(tokenize("lea msg(%rip), %rsi\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let mut corrupt = e.clone();
+        corrupt.ehdr.e_shnum += 1;
+        assert!(corrupt.validate().is_err());
+
+        let mut corrupt = e.clone();
+        let rela_idx = corrupt
+            .section_headers
+            .iter()
+            .position(|shdr| shdr.sh_type == SHT_RELA)
+            .unwrap();
+        corrupt.section_headers[rela_idx].sh_info = 0;
+        assert!(corrupt.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_emit_manual_relocation() -> Result<()> {
+        let mut encoder = parse(tokenize(".quad 0\n.reloc 0, R_X86_64_64, foo\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let foo_idx = e.symtab_symbol_indexes["foo"] as u64;
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 0,
+                r_info: (foo_idx << 32) + R_X86_64_64,
+                r_addend: 0,
+            }],
+            e.rela[".rela.text"]
+        );
+
+        let err = parse(tokenize(".reloc 0, R_X86_64_FOO, foo\n")?).unwrap_err();
+        assert_eq!("unknown relocation type `R_X86_64_FOO`", err.message);
+
+        // The relocated field must be within the section after layout.
+        for src in [
+            "nop\n.reloc 100, R_X86_64_64, foo\n",
+            "nop\n.reloc 0, R_X86_64_64, foo\n",
+        ] {
+            let mut encoder = parse(tokenize(src)?)?;
+            let err = build(&mut encoder).err().unwrap();
+            assert_eq!(Some(1), err.location.map(|loc| loc.line));
         }
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_rip_relative_lea_by_pc32() -> Result<()> {
+        let mut encoder = parse(tokenize("nop\nlea msg(%rip), %rsi\n")?)?;
+        let e = build(&mut encoder)?;
+
+        let msg_idx = e.symtab_symbol_indexes["msg"] as u64;
+        assert_eq!(
+            vec![Elf64Rela {
+                r_offset: 4,
+                r_info: (msg_idx << 32) + R_X86_64_PC32,
+                r_addend: -4,
+            }],
+            e.rela[".rela.text"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_account_for_trailing_imm_in_pc32_addend() -> Result<()> {
+        let src = "andl $1, flag(%rip)\nandl $0x1000, flag(%rip)\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        let e = build(&mut encoder)?;
+
+        // The same as GNU as. `$1` is imm8(83 /4 ib), so -4 - 1. `$0x1000` is imm32(81 /4 id), so -4 - 4.
+        let flag_idx = e.symtab_symbol_indexes["flag"] as u64;
+        assert_eq!(
+            vec![
+                Elf64Rela {
+                    r_offset: 2,
+                    r_info: (flag_idx << 32) + R_X86_64_PC32,
+                    r_addend: -5,
+                },
+                Elf64Rela {
+                    r_offset: 9,
+                    r_info: (flag_idx << 32) + R_X86_64_PC32,
+                    r_addend: -8,
+                },
+            ],
+            e.rela[".rela.text"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_downgrade_gotpcrel_without_relax_relocations() -> Result<()> {
+        let rela_of = |relax_relocations: bool| -> Result<Vec<Elf64Rela>> {
+            let mut encoder = Encoder::default();
+            encoder.options.relax_relocations = relax_relocations;
+            let mut encoder = parse_with(encoder, tokenize("movq foo@GOTPCREL(%rip), %rax\n")?)?;
+            let e = build(&mut encoder)?;
+            Ok(e.rela[".rela.text"].clone())
+        };
+
+        let rela = |rtype| Elf64Rela {
+            r_offset: 3,
+            r_info: (1 << 32) + rtype,
+            r_addend: -4,
+        };
+        assert_eq!(vec![rela(R_X86_64_REX_GOTPCRELX)], rela_of(true)?);
+        assert_eq!(vec![rela(R_X86_64_GOTPCREL)], rela_of(false)?);
+        Ok(())
     }
 }
@@ -1,107 +1,685 @@
-use byteorder::{LittleEndian, WriteBytesExt};
-
-use crate::elf::{
-    R_X86_64_PC32, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, STB_GLOBAL, STB_LOCAL, STV_HIDDEN,
-    STV_INTERNAL, STV_PROTECTED,
+use crate::elf::constants::{
+    R_X86_64_16, R_X86_64_64, R_X86_64_8, R_X86_64_NONE, R_X86_64_PC16, R_X86_64_PC64,
+    R_X86_64_PC8, SHF_ALLOC, SHF_EXECINSTR, SHF_GNU_RETAIN, SHF_GROUP, SHF_LINK_ORDER, SHF_MERGE,
+    SHF_STRINGS, SHF_TLS, SHF_WRITE, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_NOTYPE, STT_OBJECT,
+    STT_TLS, STV_HIDDEN, STV_INTERNAL, STV_PROTECTED,
 };
+use crate::elf::{align_to, is_nobits_section};
 use crate::encoder::{Encoder, Instr, InstrKind, UserDefinedSection};
-use crate::globals::{RELA_TEXT_USERS, USER_DEFINED_SECTIONS, USER_DEFINED_SYMBOLS};
+use crate::error::{ErrorKind, Result};
+use crate::utils::HashMap;
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
+
+/// Recommended multi-byte NOP sequences, indexed by length - 1.
+///
+/// These are the same as what `gas` chooses for x86_64.
+const NOPS: [&[u8]; 11] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[
+        0x66, 0x66, 0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ],
+];
 
-fn section_flags(flags: &str) -> u64 {
+/// Fill `n` bytes with the longest NOPs, instead of a run of slow single-byte `0x90`.
+pub(crate) fn nop_pad(out: &mut Vec<u8>, mut n: usize) {
+    while n > 0 {
+        let len = n.min(NOPS.len());
+        out.extend_from_slice(NOPS[len - 1]);
+        n -= len;
+    }
+}
+
+/// Flags of the sections that are used without flags. e.g. `.data`, `.section .rodata`
+///
+/// A suffixed section(e.g. `.text.foo` by `-ffunction-sections`) inherits the flags of its base section.
+fn default_section_flags(name: &str) -> u64 {
+    let base = [".text", ".data", ".rodata", ".bss", ".tdata", ".tbss"]
+        .into_iter()
+        .find(|base| {
+            name.strip_prefix(base)
+                .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('.'))
+        })
+        .unwrap_or(name);
+    match base {
+        ".text" => SHF_ALLOC | SHF_EXECINSTR,
+        ".data" | ".bss" => SHF_ALLOC | SHF_WRITE,
+        ".rodata" => SHF_ALLOC,
+        ".tdata" | ".tbss" => SHF_ALLOC | SHF_WRITE | SHF_TLS,
+        _ => 0,
+    }
+}
+
+fn section_flags(instr: &Instr) -> Result<u64> {
     let mut val = 0;
-    for c in flags.chars() {
+    for c in instr.flags.chars() {
         match c {
             'a' => val |= SHF_ALLOC,
             'x' => val |= SHF_EXECINSTR,
             'w' => val |= SHF_WRITE,
-            _ => panic!("unknown attribute '{}'", c),
+            'M' => val |= SHF_MERGE,
+            'S' => val |= SHF_STRINGS,
+            'T' => val |= SHF_TLS,
+            'o' => val |= SHF_LINK_ORDER,
+            'G' => val |= SHF_GROUP,
+            'R' => val |= SHF_GNU_RETAIN,
+            _ => bail!(instr.loc, "unknown attribute '{c}'"),
         }
     }
-    val
+    Ok(val)
 }
 
-fn change_symbol_binding(instr: &Instr, binding: u8) {
-    let mut user_symbols = USER_DEFINED_SYMBOLS.lock().unwrap();
-    let cache_instr = user_symbols.get_mut(instr.symbol_name).unwrap_or_else(|| {
-        panic!("{} undefined symbol '{}'", instr.loc, instr.symbol_name);
-    });
+/// NUL terminated strings in a mergeable string section(`"aMS"`). string => offset
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct StringPool(HashMap<Vec<u8>, usize>);
 
-    if binding == STB_GLOBAL && cache_instr.kind == InstrKind::Section {
-        panic!("{} sections cannot be global", instr.loc);
+impl StringPool {
+    /// Offset of the same string if it is already in the pool. Otherwise, `offset` is recorded for it.
+    pub(crate) fn intern(&mut self, string: &[u8], offset: usize) -> Option<usize> {
+        match self.0.get(string) {
+            Some(&offset) => Some(offset),
+            None => {
+                self.0.insert(string.to_vec(), offset);
+                None
+            }
+        }
     }
-
-    cache_instr.binding = binding;
 }
 
-fn change_symbol_visibility(instr: &Instr, visibility: u8) {
-    let mut bindings = USER_DEFINED_SYMBOLS.lock().unwrap();
-    let s = bindings.get_mut(instr.symbol_name).unwrap_or_else(|| {
-        panic!("{} undefined symbol '{}'", instr.loc, instr.symbol_name);
-    });
+/// Is `code` a string which the linker can merge? It must have one NUL only at the end.
+fn is_mergeable_string(code: &[u8]) -> bool {
+    code.iter().position(|&b| b == 0) == Some(code.len().wrapping_sub(1))
+}
 
-    s.visibility = visibility;
+/// Bytes of the field relocated by `rtype`. e.g. `8` for `R_X86_64_64`
+fn reloc_size(rtype: u64) -> usize {
+    match rtype {
+        R_X86_64_NONE => 0,
+        R_X86_64_8 | R_X86_64_PC8 => 1,
+        R_X86_64_16 | R_X86_64_PC16 => 2,
+        R_X86_64_64 | R_X86_64_PC64 => 8,
+        _ => 4,
+    }
 }
 
-fn fix_same_section_relocations() {
-    for mut rela in RELA_TEXT_USERS.lock().unwrap().iter_mut() {
-        if let Some(symbol) = USER_DEFINED_SYMBOLS.lock().unwrap().get(rela.uses) {
-            if symbol.section != rela.instr.section {
-                continue;
+impl Encoder {
+    fn change_symbol_binding(&mut self, instr: &Instr, binding: u8) -> Result<()> {
+        let symbol = match self.user_defined_symbols.get_mut(&instr.symbol_name) {
+            Some(symbol) => symbol,
+            // An external symbol. e.g. `.globl printf`
+            None if binding != STB_LOCAL => {
+                self.undefined_bindings
+                    .insert(instr.symbol_name.clone(), binding);
+                return Ok(());
             }
-            if symbol.binding == STB_GLOBAL {
-                continue;
+            None => bail!(
+                instr.loc,
+                kind = ErrorKind::UndefinedSymbol(instr.symbol_name.clone()),
+                "undefined symbol '{}'",
+                instr.symbol_name
+            ),
+        };
+
+        if binding != STB_LOCAL && symbol.kind == InstrKind::Section {
+            bail!(instr.loc, "sections cannot be global or weak");
+        }
+
+        symbol.binding = binding;
+        Ok(())
+    }
+
+    fn change_symbol_visibility(&mut self, instr: &Instr, visibility: u8) -> Result<()> {
+        let symbol = match self.user_defined_symbols.get_mut(&instr.symbol_name) {
+            Some(symbol) => symbol,
+            None => bail!(
+                instr.loc,
+                kind = ErrorKind::UndefinedSymbol(instr.symbol_name.clone()),
+                "undefined symbol '{}'",
+                instr.symbol_name
+            ),
+        };
+
+        symbol.visibility = visibility;
+        Ok(())
+    }
+
+    /// Apply the binding and visibility directives to the symbols.
+    ///
+    /// This runs after all labels are collected, so `.globl foo` may appear before or after `foo:`.
+    fn apply_symbol_attributes(&mut self) -> Result<()> {
+        let instrs = core::mem::take(&mut self.instrs);
+        let result = instrs.iter().try_for_each(|instr| match instr.kind {
+            InstrKind::Global => self.change_symbol_binding(instr, STB_GLOBAL),
+            InstrKind::Local => self.change_symbol_binding(instr, STB_LOCAL),
+            InstrKind::Weak => self.change_symbol_binding(instr, STB_WEAK),
+            InstrKind::Hidden => self.change_symbol_visibility(instr, STV_HIDDEN),
+            InstrKind::Internal => self.change_symbol_visibility(instr, STV_INTERNAL),
+            InstrKind::Protected => self.change_symbol_visibility(instr, STV_PROTECTED),
+            _ => Ok(()),
+        });
+        self.instrs = instrs;
+        result
+    }
+
+    /// Section names in order of first appearance.
+    pub(crate) fn section_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        // Function sections may make tens of thousands of sections. e.g. `-ffunction-sections`
+        let mut seen = BTreeSet::new();
+        for instr in &self.instrs {
+            if seen.insert(instr.section_name.as_str()) {
+                names.push(instr.section_name.clone());
             }
+        }
+        // `.debug_line` is made by the assembler after layout, so it has no instruction.
+        if self.user_defined_sections.contains_key(".debug_line")
+            && !names.iter().any(|name| name == ".debug_line")
+        {
+            names.push(".debug_line".into());
+        }
+        names
+    }
 
-            if !rela.instr.is_jmp_or_call && rela.rtype != R_X86_64_PC32 {
-                continue;
+    /// Does any executable section have bytes?
+    pub(crate) fn has_executable_code(&self) -> bool {
+        self.user_defined_sections
+            .values()
+            .any(|section| section.flags & SHF_EXECINSTR != 0 && !section.code.is_empty())
+    }
+
+    /// Lay out each section in source order and assign addresses to instructions and symbols.
+    pub(crate) fn assign_addresses(&mut self) -> Result<()> {
+        self.place_imm64_constants()?;
+        self.apply_symbol_attributes()?;
+        let mut instrs = core::mem::take(&mut self.instrs);
+
+        for instr in instrs.iter_mut() {
+            let section = self
+                .user_defined_sections
+                .entry(instr.section_name.clone())
+                .or_insert_with(|| UserDefinedSection {
+                    flags: default_section_flags(&instr.section_name),
+                    ..Default::default()
+                });
+            match instr.kind {
+                // Without flags, re-entering a section keeps its flags.
+                InstrKind::Section if !instr.flags.is_empty() => {
+                    section.flags = section_flags(instr)?;
+                    section.entsize = instr.entsize;
+                    section.group = instr.group.clone();
+                    section.linked_to = instr.linked_to.clone();
+                }
+                // An identical string is laid out only once, and the labels on it point to the first one.
+                InstrKind::String
+                    if section.flags & (SHF_MERGE | SHF_STRINGS) == SHF_MERGE | SHF_STRINGS
+                        && section.entsize == 1
+                        && is_mergeable_string(&instr.code) =>
+                {
+                    let pool = self
+                        .string_pools
+                        .entry(instr.section_name.clone())
+                        .or_default();
+                    if let Some(offset) = pool.intern(&instr.code, section.addr) {
+                        instr.code.clear();
+                        for symbol in self.user_defined_symbols.values_mut() {
+                            if symbol.kind == InstrKind::Label
+                                && symbol.section_name == instr.section_name
+                                && symbol.addr == section.addr
+                            {
+                                symbol.addr = offset;
+                            }
+                        }
+                    }
+                }
+                InstrKind::Align => {
+                    section.max_align = section.max_align.max(instr.align);
+                    let mut padding = align_to(section.addr, instr.align) - section.addr;
+                    if instr
+                        .align_max_skip
+                        .is_some_and(|max_skip| padding > max_skip)
+                    {
+                        padding = 0;
+                    }
+
+                    instr.code.clear();
+                    match (instr.align_fill, section.flags & SHF_EXECINSTR != 0) {
+                        // Only zero fill is allowed in nobits sections, and it is not allocated.
+                        _ if is_nobits_section(&instr.section_name) => instr.reserved = padding,
+                        (Some(fill), _) => instr.code.resize(padding, fill),
+                        (None, true) => nop_pad(&mut instr.code, padding),
+                        (None, false) => instr.code.resize(padding, 0),
+                    }
+                }
+                // A jump shorter than its reserved `rel32` form is padded, so that the bytes stay in step with the addresses.
+                _ if instr.is_jmp_or_call => {
+                    let padding = instr.encoded_len() - instr.code.len();
+                    nop_pad(&mut instr.code, padding);
+                }
+                _ => {}
             }
 
-            let num =
-                ((symbol.addr - rela.instr.addr) - rela.instr.code.len()) + rela.adjust as usize;
+            instr.addr = section.addr;
+            section.addr = match section.addr.checked_add(instr.encoded_len()) {
+                Some(addr) => addr,
+                None => bail!(
+                    instr.loc,
+                    "section `{}` is too large to lay out",
+                    instr.section_name
+                ),
+            };
+            section.code.extend_from_slice(&instr.code);
 
-            let mut hex = vec![0u8; 4];
-            let mut binding = USER_DEFINED_SECTIONS.lock().unwrap();
-            let user = binding.get_mut(rela.instr.section).unwrap();
-            hex.write_u32::<LittleEndian>(num as u32).unwrap();
-            user.code[rela.instr.addr + rela.offset] = hex[0];
-            user.code[rela.instr.addr + rela.offset + 1] = hex[1];
-            user.code[rela.instr.addr + rela.offset + 2] = hex[2];
-            user.code[rela.instr.addr + rela.offset + 3] = hex[3];
+            if instr.kind == InstrKind::Label {
+                if let Some(symbol) = self.user_defined_symbols.get_mut(&instr.symbol_name) {
+                    symbol.addr = instr.addr;
+                }
+            }
+        }
 
-            rela.is_already_resolved = true;
+        for rela in &mut self.rela_text_users {
+            if let Some(idx) = rela.instr_idx {
+                rela.instr = instrs[idx].clone();
+            }
         }
-    }
-}
 
-impl Encoder<'_> {
-    pub fn assign_addresses(&mut self) {
-        for (name, mut instrs) in self.instrs.clone() {
-            if !USER_DEFINED_SECTIONS.lock().unwrap().contains_key(name) {
-                USER_DEFINED_SECTIONS
-                    .lock()
-                    .unwrap()
-                    .insert(name.to_owned(), UserDefinedSection::default());
+        // The labels in TLS sections(e.g. `.tbss`) are thread-local variables.
+        for symbol in self.user_defined_symbols.values_mut() {
+            let is_tls = self
+                .user_defined_sections
+                .get(&symbol.section_name)
+                .is_some_and(|section| section.flags & SHF_TLS != 0);
+            if is_tls && matches!(symbol.symbol_type, STT_NOTYPE | STT_OBJECT) {
+                symbol.symbol_type = STT_TLS;
             }
-            let mut bindings = USER_DEFINED_SECTIONS.lock().unwrap();
-            let mut section = bindings.get_mut(name).unwrap();
-
-            for mut i in instrs.iter_mut() {
-                match i.kind {
-                    InstrKind::Section => section.flags = section_flags(i.flags),
-                    InstrKind::Global => change_symbol_binding(i, STB_GLOBAL),
-                    InstrKind::Local => change_symbol_binding(i, STB_LOCAL),
-                    InstrKind::Hidden => change_symbol_visibility(i, STV_HIDDEN),
-                    InstrKind::Internal => change_symbol_visibility(i, STV_INTERNAL),
-                    InstrKind::Protected => change_symbol_visibility(i, STV_PROTECTED),
-                    _ => {}
+        }
+
+        self.instrs = instrs;
+        // In order of the section headers.
+        for name in self.section_names() {
+            let section = &self.user_defined_sections[&name];
+            log::debug!(
+                "section laid out {name}: {:#x} bytes, flags {:#x}",
+                section.addr,
+                section.flags
+            );
+        }
+
+        self.resolve_symbol_aliases()?;
+        self.fix_same_section_relocations()?;
+        self.check_narrow_local_relocations()?;
+        self.check_relocation_offsets()
+    }
+
+    /// Place each alias of `.set alias, target` at its target. An alias may refer to an earlier alias.
+    ///
+    /// The binding and visibility of the alias are its own.
+    fn resolve_symbol_aliases(&mut self) -> Result<()> {
+        for (alias, target) in &self.symbol_aliases {
+            let (addr, section_name, symbol_type) = match self.user_defined_symbols.get(target) {
+                Some(target) if !target.section_name.is_empty() => {
+                    (target.addr, target.section_name.clone(), target.symbol_type)
+                }
+                _ => {
+                    let loc = self.user_defined_symbols[alias].loc;
+                    bail!(
+                        loc,
+                        kind = ErrorKind::UndefinedSymbol(target.clone()),
+                        "`{alias}` is an alias of undefined `{target}`"
+                    );
                 }
+            };
+            let symbol = self.user_defined_symbols.get_mut(alias).unwrap();
+            symbol.addr = addr;
+            symbol.section_name = section_name;
+            symbol.symbol_type = symbol_type;
+        }
+        Ok(())
+    }
 
-                i.addr = section.addr;
-                section.addr += i.code.len();
-                section.code.extend_from_slice(&i.code);
+    /// Check that `R_X86_64_8`/`R_X86_64_16` to local symbols can fit. e.g. `.byte foo`
+    ///
+    /// The final address is at least the offset in the section, so it never fits if the offset does not.
+    fn check_narrow_local_relocations(&self) -> Result<()> {
+        for rela in &self.rela_text_users {
+            let bits = match rela.rtype {
+                R_X86_64_8 => 8,
+                R_X86_64_16 => 16,
+                _ => continue,
+            };
+            let Some(symbol) = self.user_defined_symbols.get(&rela.uses) else {
+                continue;
+            };
+            let value = symbol.addr as i64 + rela.addend();
+            if symbol.binding == STB_LOCAL && !(0..1 << bits).contains(&value) {
+                bail!(
+                    rela.instr.loc,
+                    "`{}` at {value:#x} does not fit in {bits}bit relocation",
+                    rela.uses
+                );
             }
         }
+        Ok(())
+    }
+
+    /// Check that each relocated field is within its section after layout. e.g. `.reloc 100, R_X86_64_64, foo`
+    fn check_relocation_offsets(&self) -> Result<()> {
+        for rela in &self.rela_text_users {
+            let section_size = self
+                .user_defined_sections
+                .get(&rela.instr.section_name)
+                .map_or(0, |section| section.addr);
+            let end = rela.instr.addr + rela.offset + reloc_size(rela.rtype);
+            if end > section_size {
+                bail!(
+                    rela.instr.loc,
+                    "relocation at {:#x} is not within section `{}` of {section_size:#x} bytes",
+                    rela.instr.addr + rela.offset,
+                    rela.instr.section_name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::constants::R_X86_64_PC32;
+    use crate::encoder::{parse, parse_with};
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_pad_with_multi_byte_nops() {
+        let mut out = Vec::new();
+        nop_pad(&mut out, 11);
+        assert_eq!(
+            vec![0x66, 0x66, 0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+            out
+        );
+
+        let mut out = Vec::new();
+        nop_pad(&mut out, 14);
+        assert_eq!(
+            vec![
+                0x66, 0x66, 0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, // 11 bytes
+                0x0f, 0x1f, 0x00, // 3 bytes
+            ],
+            out
+        );
+    }
+
+    #[test]
+    fn should_align_text_with_nops() -> Result<()> {
+        let mut encoder = parse(tokenize("nop\n.p2align 3\n.byte 1\n")?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![0x90, 0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01],
+            encoder.user_defined_sections[".text"].code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_honor_align_max_skip() -> Result<()> {
+        let mut encoder = parse(tokenize(".skip 13\n.p2align 4,,3\n.byte 1\n")?)?;
+        encoder.assign_addresses()?;
+        let code = &encoder.user_defined_sections[".text"].code;
+        assert_eq!(17, code.len());
+        assert_eq!(1, code[16]);
+
+        let mut encoder = parse(tokenize(".skip 12\n.p2align 4,,3\n.byte 1\n")?)?;
+        encoder.assign_addresses()?;
+        let code = &encoder.user_defined_sections[".text"].code;
+        assert_eq!(13, code.len());
+        assert_eq!(1, code[12]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_pad_short_jump_to_reserved_len() -> Result<()> {
+        let mut encoder = parse(tokenize("jmp ext\nfoo: nop\n")?)?;
+        // e.g. `jmp` in the `rel8` form
+        encoder.instrs[0].code = vec![0xeb, 0x00];
+        encoder.assign_addresses()?;
+
+        assert_eq!(5, encoder.user_defined_symbols["foo"].addr);
+        assert_eq!(
+            vec![0xeb, 0x00, 0x0f, 0x1f, 0x00, 0x90],
+            encoder.user_defined_sections[".text"].code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_too_large_alignment() -> Result<()> {
+        let src = ".data\n.byte 1\n.p2align 31\n";
+        assert!(parse(tokenize(src)?).is_ok());
+        for src in [".p2align 32\n", ".p2align 63\n", ".balign 0x100000000\n"] {
+            let err = parse(tokenize(src)?).unwrap_err();
+            assert_eq!(Some(0), err.location.map(|loc| loc.line));
+            assert!(err.message.contains("too large"), "{}", err.message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_reserve_nobits_without_allocating() -> Result<()> {
+        let src = ".bss\n.skip 1\n.skip 99999999999999\n.lcomm buf, 8\n.fill 3, 2\n.p2align 4\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        let bss = &encoder.user_defined_sections[".bss"];
+        assert!(bss.code.is_empty());
+        assert_eq!(16, bss.max_align);
+        assert_eq!(100000000000016, bss.addr);
+        let buf = &encoder.user_defined_symbols["buf"];
+        assert_eq!((100000000000000, 8), (buf.addr, buf.size));
+
+        let err = parse(tokenize(".data\n.skip 99999999999999\n")?).unwrap_err();
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+        assert!(parse(tokenize(".fill 0x100000000, 8\n")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_align_in_the_current_section() -> Result<()> {
+        let src = ".text\nnop\n.data\n.byte 1, 2, 3\n.balign 4\n.byte 4\n.text\n.balign 2\nnop\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![0x90, 0x90, 0x90],
+            encoder.user_defined_sections[".text"].code
+        );
+        assert_eq!(
+            vec![1, 2, 3, 0, 4],
+            encoder.user_defined_sections[".data"].code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_align_data_naturally() -> Result<()> {
+        let src = ".data\n.byte 1, 2, 3\nfoo:\n.quad 4\n.byte 5\n.word 6\n";
+        let mut encoder = Encoder::default();
+        encoder.options.natural_align = true;
+        let mut encoder = parse_with(encoder, tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![1, 2, 3, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 6, 0],
+            encoder.user_defined_sections[".data"].code
+        );
+        // The label points to the aligned data, not to the padding.
+        assert_eq!(8, encoder.user_defined_symbols["foo"].addr);
+
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(14, encoder.user_defined_sections[".data"].code.len());
+        Ok(())
+    }
+
+    #[test]
+    fn should_load_imm64_from_rodata() -> Result<()> {
+        let src = ".rodata\n.byte 1\n.text\nmovq $0x1122334455667788, %rax\nmovq $0x1122334455667788, %r9\nmovq $1, %rax\n";
+        let mut encoder = Encoder::default();
+        encoder.options.imm64_in_rodata = true;
+        let mut encoder = parse_with(encoder, tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![
+                0x48, 0x8b, 0x05, 0, 0, 0, 0, // movq .Limm64.0(%rip), %rax
+                0x4c, 0x8b, 0x0d, 0, 0, 0, 0, // movq .Limm64.0(%rip), %r9
+                0x48, 0xc7, 0xc0, 1, 0, 0, 0, // movq $1, %rax
+            ],
+            encoder.user_defined_sections[".text"].code
+        );
+        // The same values share an 8-byte aligned constant.
+        assert_eq!(
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+            encoder.user_defined_sections[".rodata"].code
+        );
+        assert_eq!(8, encoder.user_defined_symbols[".Limm64.0"].addr);
+        let relas: Vec<_> = encoder
+            .rela_text_users
+            .iter()
+            .map(|rela| (rela.uses.as_str(), rela.offset, rela.rtype))
+            .collect();
+        assert_eq!(
+            vec![
+                (".Limm64.0", 3, R_X86_64_PC32),
+                (".Limm64.0", 3, R_X86_64_PC32)
+            ],
+            relas
+        );
+
+        let mut encoder = parse(tokenize("movq $0x1122334455667788, %rax\n")?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+            encoder.user_defined_sections[".text"].code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_bind_symbols_declared_before_definition() -> Result<()> {
+        let src = ".globl foo, bar\n.hidden foo\nfoo:\nnop\nbar:\nbaz:\n.weak baz\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+
+        let symbol = |name: &str| &encoder.user_defined_symbols[name];
+        assert_eq!(STB_GLOBAL, symbol("foo").binding);
+        assert_eq!(STV_HIDDEN, symbol("foo").visibility);
+        assert_eq!(STB_GLOBAL, symbol("bar").binding);
+        assert_eq!(1, symbol("bar").addr);
+        assert_eq!(STB_WEAK, symbol("baz").binding);
+
+        let mut encoder = parse(tokenize(".hidden missing\nnop\n")?)?;
+        let err = encoder.assign_addresses().unwrap_err();
+        assert_eq!("undefined symbol 'missing'", err.message);
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_range_of_narrow_local_relocations() -> Result<()> {
+        let mut encoder = parse(tokenize(".data\n.skip 300\nfoo:\n.byte foo\n")?)?;
+        let err = encoder.assign_addresses().unwrap_err();
+        assert_eq!(
+            "`foo` at 0x12c does not fit in 8bit relocation",
+            err.message
+        );
+
+        let mut encoder = parse(tokenize(".data\n.skip 300\nfoo:\n.word foo\n")?)?;
+        assert!(encoder.assign_addresses().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_merge_identical_strings() -> Result<()> {
+        let src = r#".section .rodata.str1.1, "aMS", @progbits, 1
+.LC0:
+.asciz "hi"
+.LC1:
+.string "yo"
+.LC2:
+.asciz "hi"
+.text
+lea .LC0(%rip), %rax
+lea .LC2(%rip), %rcx
+"#;
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+
+        let section = &encoder.user_defined_sections[".rodata.str1.1"];
+        assert_eq!(b"hi\0yo\0".as_slice(), section.code);
+        assert_eq!(SHF_ALLOC | SHF_MERGE | SHF_STRINGS, section.flags);
+        assert_eq!(1, section.entsize);
+        assert_eq!(0, encoder.user_defined_symbols[".LC0"].addr);
+        assert_eq!(3, encoder.user_defined_symbols[".LC1"].addr);
+        assert_eq!(0, encoder.user_defined_symbols[".LC2"].addr);
+
+        // Without `M`, the strings are kept as they are.
+        let mut encoder = parse(tokenize(
+            ".section .rodata\n.asciz \"hi\"\n.asciz \"hi\"\n",
+        )?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(6, encoder.user_defined_sections[".rodata"].code.len());
+
+        assert!(parse(tokenize(".section .rodata.str, \"aMS\", @progbits\n")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_place_alias_at_its_target() -> Result<()> {
+        let mut encoder = parse(tokenize("nop\nb:\nnop\n.set a, b\n.globl a\n")?)?;
+        encoder.assign_addresses()?;
+
+        let symbol = |name: &str| &encoder.user_defined_symbols[name];
+        assert_eq!(1, symbol("a").addr);
+        assert_eq!(1, symbol("b").addr);
+        assert_eq!(".text", symbol("a").section_name);
+        assert_eq!(STB_GLOBAL, symbol("a").binding);
+        assert_eq!(STB_LOCAL, symbol("b").binding);
+
+        let mut encoder = parse(tokenize(".set a, missing\nnop\n")?)?;
+        let err = encoder.assign_addresses().unwrap_err();
+        assert_eq!("`a` is an alias of undefined `missing`", err.message);
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_order_of_interleaved_sections() -> Result<()> {
+        let src = ".data\n.long 1\n.text\nnop\n.data\n.quad 2\n.text\npush %rax\nret_here:\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
 
-        fix_same_section_relocations();
+        let laid_out: Vec<_> = encoder
+            .instrs
+            .iter()
+            .filter(|instr| !instr.code.is_empty())
+            .map(|instr| (instr.section_name.as_str(), instr.addr))
+            .collect();
+        assert_eq!(
+            vec![(".data", 0), (".text", 0), (".data", 4), (".text", 1)],
+            laid_out
+        );
+        assert_eq!(
+            vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0],
+            encoder.user_defined_sections[".data"].code
+        );
+        assert_eq!(
+            vec![0x90, 0x50],
+            encoder.user_defined_sections[".text"].code
+        );
+        assert_eq!(2, encoder.user_defined_symbols["ret_here"].addr);
+        Ok(())
     }
 }
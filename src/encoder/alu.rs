@@ -0,0 +1,875 @@
+use crate::encoder::arch::x86_64::{
+    bin_const::{MOD_REGI, OPCODE_MAP_0F38},
+    registers::{DataSizeSuffix, Register},
+    Expr,
+};
+use crate::encoder::mem::Memory;
+use crate::encoder::{
+    compose_mod_rm, expect, parse_operand, parse_two_operand, Encoder, InstrKind,
+};
+use crate::error::Result;
+use crate::lexer::{Token, TokenKind};
+use crate::CpuFeature;
+use alloc::borrow::ToOwned;
+
+/// Arithmetic and logical instructions which share the encoding of `/digit` group.
+///
+/// (mnemonic, kind, opcode extension)
+const ALU_OPS: [(&str, InstrKind, u8); 8] = [
+    ("add", InstrKind::Add, 0),
+    ("or", InstrKind::InstrOr, 1),
+    ("adc", InstrKind::Adc, 2),
+    ("sbb", InstrKind::Sbb, 3),
+    ("and", InstrKind::And, 4),
+    ("sub", InstrKind::Sub, 5),
+    ("xor", InstrKind::Xor, 6),
+    ("cmp", InstrKind::Cmp, 7),
+];
+
+/// Instructions which take a single register or memory operand of the `/digit` group.
+///
+/// (mnemonic, kind, opcode for 8bit operand, opcode extension). `opcode + 1` is used for the others.
+const UNARY_OPS: [(&str, InstrKind, u8, u8); 8] = [
+    ("inc", InstrKind::Inc, 0xfe, 0),
+    ("dec", InstrKind::Dec, 0xfe, 1),
+    ("not", InstrKind::Not, 0xf6, 2),
+    ("neg", InstrKind::Neg, 0xf6, 3),
+    ("mul", InstrKind::Mul, 0xf6, 4),
+    ("imul", InstrKind::Imul, 0xf6, 5),
+    ("div", InstrKind::Div, 0xf6, 6),
+    ("idiv", InstrKind::Idiv, 0xf6, 7),
+];
+
+/// Split a mnemonic into the base name and the size suffix. e.g. `addq` => (`add`, Quad)
+pub(crate) fn split_suffix<'a>(
+    mnemonic: &'a str,
+    base_names: &[&str],
+) -> Option<(&'a str, DataSizeSuffix)> {
+    if base_names.contains(&mnemonic) {
+        return Some((mnemonic, DataSizeSuffix::Unknown));
+    }
+
+    let (base, suffix) = mnemonic.split_at(mnemonic.len().checked_sub(1)?);
+    // `s` and `d` are the floating point sizes, which the integer instructions do not have.
+    let size = DataSizeSuffix::from_suffix_char(suffix.chars().next()?)
+        .filter(|size| !matches!(size, DataSizeSuffix::Single | DataSizeSuffix::Double))?;
+    base_names.contains(&base).then_some((base, size))
+}
+
+/// Get ALU instruction by mnemonic. e.g. `andl`
+///
+/// Returns (kind, opcode extension, size)
+pub(crate) fn alu_op_by(mnemonic: &str) -> Option<(InstrKind, u8, DataSizeSuffix)> {
+    let base_names = ALU_OPS.map(|(name, _, _)| name);
+    let (base, size) = split_suffix(mnemonic, &base_names)?;
+    let (_, kind, ext) = ALU_OPS.into_iter().find(|(name, _, _)| *name == base)?;
+    Some((kind, ext, size))
+}
+
+/// Get the single operand instruction by mnemonic. e.g. `negb`
+///
+/// Returns (kind, opcode for 8bit operand, opcode extension, size)
+pub(crate) fn unary_op_by(mnemonic: &str) -> Option<(InstrKind, u8, u8, DataSizeSuffix)> {
+    let base_names = UNARY_OPS.map(|(name, _, _, _)| name);
+    let (base, size) = split_suffix(mnemonic, &base_names)?;
+    let (_, kind, opcode, ext) = UNARY_OPS.into_iter().find(|(name, ..)| *name == base)?;
+    Some((kind, opcode, ext, size))
+}
+
+/// Is `mnemonic` a `test` instruction? Returns its size suffix.
+pub(crate) fn test_op_by(mnemonic: &str) -> Option<DataSizeSuffix> {
+    split_suffix(mnemonic, &["test"]).map(|(_, size)| size)
+}
+
+/// Is `mnemonic` a `nop` with an operand? Returns its size suffix. e.g. `nopl`
+pub(crate) fn nop_op_by(mnemonic: &str) -> Option<DataSizeSuffix> {
+    split_suffix(mnemonic, &["nop"]).map(|(_, size)| size)
+}
+
+/// Get `xadd` or `cmpxchg` by mnemonic. e.g. `xaddq`
+///
+/// Returns (kind, opcode for 8bit operand after `0f`, size)
+pub(crate) fn exchange_op_by(mnemonic: &str) -> Option<(InstrKind, u8, DataSizeSuffix)> {
+    let (base, size) = split_suffix(mnemonic, &["xadd", "cmpxchg"])?;
+    match base {
+        "xadd" => Some((InstrKind::Xadd, 0xc0, size)),
+        _ => Some((InstrKind::Cmpxchg, 0xb0, size)),
+    }
+}
+
+/// Get `shld` or `shrd` by mnemonic. e.g. `shldq`
+///
+/// Returns (kind, opcode with imm8 count after `0f`, size). `opcode + 1` takes the count in `%cl`.
+pub(crate) fn double_shift_op_by(mnemonic: &str) -> Option<(InstrKind, u8, DataSizeSuffix)> {
+    let (base, size) = split_suffix(mnemonic, &["shld", "shrd"])?;
+    match base {
+        "shld" => Some((InstrKind::Shld, 0xa4, size)),
+        _ => Some((InstrKind::Shrd, 0xac, size)),
+    }
+}
+
+/// Get a bit scan or population count instruction by mnemonic. e.g. `popcntq`
+///
+/// Returns (kind, whether the mandatory `f3` prefix is needed, opcode after `0f`, size)
+pub(crate) fn bit_count_op_by(mnemonic: &str) -> Option<(InstrKind, bool, u8, DataSizeSuffix)> {
+    let (base, size) = split_suffix(mnemonic, &["bsf", "bsr", "popcnt", "lzcnt", "tzcnt"])?;
+    let (kind, f3, opcode) = match base {
+        "bsf" => (InstrKind::Bsf, false, 0xbc),
+        "bsr" => (InstrKind::Bsr, false, 0xbd),
+        "popcnt" => (InstrKind::Popcnt, true, 0xb8),
+        "lzcnt" => (InstrKind::Lzcnt, true, 0xbd),
+        _ => (InstrKind::Tzcnt, true, 0xbc),
+    };
+    Some((kind, f3, opcode, size))
+}
+
+/// Is `mnemonic` a `movbe` instruction? Returns its size suffix.
+pub(crate) fn movbe_op_by(mnemonic: &str) -> Option<DataSizeSuffix> {
+    split_suffix(mnemonic, &["movbe"]).map(|(_, size)| size)
+}
+
+impl Encoder {
+    /// e.g. `addq $1, %rax`, `andl $0xff, %eax`, `xorq %rax, %rax`, `cmpq 8(%rsp), %rdi`
+    ///
+    /// - `ext`: opcode extension of the `/digit` group. It also decides the opcode of the other forms.
+    pub(crate) fn encode_alu(
+        &mut self,
+        kind: InstrKind,
+        ext: u8,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind.clone())?;
+
+        match parse_two_operand(index, tokens)? {
+            (Expr::Immediate(imm), Expr::Register(dst)) => {
+                let size = self.operand_size(size, &dst)?;
+                let (imm, symbol) = self.eval_imm_with_symbol(*imm, size)?;
+                // The value of a symbol is unknown, so it always takes the full size immediate.
+                let is_imm8 =
+                    size != DataSizeSuffix::Byte && symbol.is_none() && i8::try_from(imm).is_ok();
+
+                self.add_prefix_rm(size, &dst);
+                match (size, dst.base_offset == 0 && !dst.rex_required) {
+                    // The short form is chosen only when it is not longer than the `imm8` form.
+                    // AL, imm8: 04+8*ext ib
+                    (DataSizeSuffix::Byte, true) => self.current_instr.code.push(ext << 3 | 0x04),
+                    // AX/EAX/RAX, imm: 05+8*ext iw/id
+                    (_, true) if !is_imm8 => self.current_instr.code.push(ext << 3 | 0x05),
+                    // r/m8, imm8: 80 /digit ib
+                    (DataSizeSuffix::Byte, false) => self.push_opcode_reg(0x80, ext, &dst),
+                    // r/m, imm8: 83 /digit ib (sign extended)
+                    _ if is_imm8 => self.push_opcode_reg(0x83, ext, &dst),
+                    // r/m, imm: 81 /digit iw/id
+                    _ => self.push_opcode_reg(0x81, ext, &dst),
+                }
+                match symbol {
+                    Some((symbol, rtype)) => {
+                        self.push_imm_reloc(symbol, rtype, imm, size.size_in_bytes().min(4));
+                    }
+                    None => {
+                        self.push_imm(imm, if is_imm8 { DataSizeSuffix::Byte } else { size })?
+                    }
+                }
+            }
+            // r/m8, imm8: 80 /digit ib / r/m, imm8: 83 /digit ib / r/m, imm: 81 /digit iw/id
+            (Expr::Immediate(imm), mem) => {
+                let (imm, symbol) = self.eval_imm_with_symbol(*imm, size)?;
+                let (opcode, imm_size) = match size {
+                    DataSizeSuffix::Byte => (0x80, DataSizeSuffix::Byte),
+                    _ if symbol.is_none() && i8::try_from(imm).is_ok() => {
+                        (0x83, DataSizeSuffix::Byte)
+                    }
+                    _ => (0x81, size),
+                };
+                match symbol {
+                    Some((symbol, rtype)) => {
+                        self.encode_digit_mem(size, opcode, ext, &mem)?;
+                        self.push_imm_reloc(symbol, rtype, imm, size.size_in_bytes().min(4));
+                    }
+                    None => self.encode_imm_mem(size, opcode, ext, imm, imm_size, &mem)?,
+                }
+            }
+            // r/m, r: 00+8*ext(8bit), 01+8*ext
+            (Expr::Register(src), Expr::Register(dst)) => {
+                let size = self.operand_size(size, &src)?;
+                self.operand_size(size, &dst)?;
+                self.add_prefix_reg_rm(size, &src, &dst);
+                self.push_opcode_for(size, ext << 3);
+                self.current_instr.code.push(compose_mod_rm(
+                    MOD_REGI,
+                    src.base_offset & 7,
+                    dst.base_offset & 7,
+                ));
+            }
+            // r/m, r: 00+8*ext(8bit), 01+8*ext
+            (Expr::Register(src), mem) => self.encode_reg_mem(size, ext << 3, src, &mem)?,
+            // r, r/m: 02+8*ext(8bit), 03+8*ext
+            (mem, Expr::Register(dst)) => self.encode_reg_mem(size, ext << 3 | 0x02, dst, &mem)?,
+            _ => bail!(loc, "unsupported operands for `{kind:?}`"),
+        }
+        Ok(())
+    }
+
+    /// e.g. `negb (%rax)`, `notq %rax`, `incl 8(%rsp)`, `idivq %rcx`
+    ///
+    /// INC/DEC r/m: fe /0, /1(8bit), ff /0, /1 / NOT, NEG, MUL, IMUL, DIV, IDIV r/m: f6 /2../7(8bit), f7 /2../7
+    ///
+    /// The size of a memory operand must be given by the mnemonic suffix.
+    pub(crate) fn encode_unary(
+        &mut self,
+        kind: InstrKind,
+        opcode: u8,
+        ext: u8,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.set_kind(kind)?;
+        let operand = parse_operand(index, tokens)?;
+        let size = match &operand {
+            Expr::Register(reg) => self.operand_size(size, reg)?,
+            _ => size,
+        };
+        let opcode = match size {
+            DataSizeSuffix::Byte => opcode,
+            _ => opcode + 1,
+        };
+        match operand {
+            Expr::Register(reg) => {
+                self.add_prefix_rm(size, &reg);
+                self.push_opcode_reg(opcode, ext, &reg);
+                Ok(())
+            }
+            mem => self.encode_digit_mem(size, opcode, ext, &mem),
+        }
+    }
+
+    /// Multi-byte NOP. e.g. `nopl (%rax)`, `nopw %cs:0x0(%rax,%rax,1)`
+    ///
+    /// NOP r/m16, r/m32, r/m64: 0f 1f /0
+    pub(crate) fn encode_nop(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Nop)?;
+
+        match parse_operand(index, tokens)? {
+            Expr::Register(reg) => {
+                let size = self.operand_size(size, &reg)?;
+                if size == DataSizeSuffix::Byte {
+                    bail!(loc, "`nop` does not take 8bit operand");
+                }
+                self.add_prefix_rm(size, &reg);
+                self.current_instr.code.push(0x0f);
+                self.push_opcode_reg(0x1f, 0, &reg);
+            }
+            operand => {
+                let Some(mem) = Memory::from_expr(&operand, loc)? else {
+                    bail!(loc, "`nop` expects a register or memory operand");
+                };
+                let size = match size {
+                    DataSizeSuffix::Word | DataSizeSuffix::Long | DataSizeSuffix::Quad => size,
+                    DataSizeSuffix::Unknown => {
+                        self.warn(
+                            loc,
+                            "no suffix given for `nop` with memory operand. using `nopl`"
+                                .to_owned(),
+                        );
+                        DataSizeSuffix::Long
+                    }
+                    _ => bail!(loc, "`nop` takes 16, 32 or 64bit operand"),
+                };
+                self.add_prefix(
+                    Register::default(),
+                    mem.index.clone().unwrap_or_default(),
+                    mem.base.clone().unwrap_or_default(),
+                    &[size],
+                );
+                self.current_instr.code.extend_from_slice(&[0x0f, 0x1f]);
+                self.add_modrm_sib_disp(&mem, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// e.g. `testb $1, %al`, `testq %rax, %rax`
+    pub(crate) fn encode_test(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Test)?;
+
+        match parse_two_operand(index, tokens)? {
+            (Expr::Immediate(imm), Expr::Register(dst)) => {
+                let size = self.operand_size(size, &dst)?;
+                let imm = self.eval_abs_expr(*imm, loc)?;
+
+                self.add_prefix_rm(size, &dst);
+                match dst.base_offset == 0 && !dst.rex_required {
+                    // AL, imm8: a8 ib / AX/EAX/RAX, imm: a9 iw/id
+                    true => self.push_opcode_for(size, 0xa8),
+                    // r/m8, imm8: f6 /0 ib / r/m, imm: f7 /0 iw/id
+                    false => {
+                        let opcode = if size == DataSizeSuffix::Byte {
+                            0xf6
+                        } else {
+                            0xf7
+                        };
+                        self.push_opcode_reg(opcode, 0, &dst);
+                    }
+                }
+                self.push_imm(imm, size)?;
+            }
+            // r/m8, imm8: f6 /0 ib / r/m, imm: f7 /0 iw/id
+            (Expr::Immediate(imm), mem) => {
+                let imm = self.eval_abs_expr(*imm, loc)?;
+                let opcode = match size {
+                    DataSizeSuffix::Byte => 0xf6,
+                    _ => 0xf7,
+                };
+                self.encode_imm_mem(size, opcode, 0, imm, size, &mem)?;
+            }
+            // TEST r/m, r: 84 /r(8bit), 85 /r
+            (Expr::Register(src), Expr::Register(dst)) => {
+                let size = self.operand_size(size, &src)?;
+                self.operand_size(size, &dst)?;
+                self.add_prefix_reg_rm(size, &src, &dst);
+                self.push_opcode_for(size, 0x84);
+                self.current_instr.code.push(compose_mod_rm(
+                    MOD_REGI,
+                    src.base_offset & 7,
+                    dst.base_offset & 7,
+                ));
+            }
+            (Expr::Register(src), mem) => self.encode_reg_mem(size, 0x84, src, &mem)?,
+            _ => bail!(loc, "unsupported operands for `test`"),
+        }
+        Ok(())
+    }
+
+    /// e.g. `lock xaddq %rax, (%rbx)`, `lock cmpxchgl %ecx, (%rdx)`
+    ///
+    /// XADD r/m, r: 0f c0 /r(8bit), 0f c1 /r / CMPXCHG r/m, r: 0f b0 /r(8bit), 0f b1 /r
+    pub(crate) fn encode_exchange(
+        &mut self,
+        kind: InstrKind,
+        opcode: u8,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind.clone())?;
+
+        let (src, dst) = parse_two_operand(index, tokens)?;
+        let Expr::Register(src) = src else {
+            bail!(loc, "unsupported operands for `{kind:?}`");
+        };
+        let size = self.operand_size(size, &src)?;
+        match dst {
+            Expr::Register(dst) => {
+                self.operand_size(size, &dst)?;
+                self.add_prefix_reg_rm(size, &src, &dst);
+                self.current_instr.code.push(0x0f);
+                self.push_opcode_for(size, opcode);
+                self.current_instr.code.push(compose_mod_rm(
+                    MOD_REGI,
+                    src.base_offset & 7,
+                    dst.base_offset & 7,
+                ));
+                Ok(())
+            }
+            mem => {
+                let mem = match Memory::from_expr(&mem, loc)? {
+                    Some(mem) => mem,
+                    None => bail!(loc, "unsupported operands for `{kind:?}`"),
+                };
+                self.add_prefix(
+                    src.clone(),
+                    mem.index.clone().unwrap_or_default(),
+                    mem.base.clone().unwrap_or_default(),
+                    &[size],
+                );
+                self.current_instr.code.push(0x0f);
+                self.push_opcode_for(size, opcode);
+                self.add_modrm_sib_disp(&mem, src.base_offset)
+            }
+        }
+    }
+
+    /// e.g. `shldl $4, %ebx, %eax`, `shrdq %cl, %rbx, (%rax)`
+    ///
+    /// SHLD r/m, r, imm8: 0f a4 /r ib / SHLD r/m, r, CL: 0f a5 /r / SHRD: 0f ac, 0f ad
+    pub(crate) fn encode_double_shift(
+        &mut self,
+        kind: InstrKind,
+        opcode: u8,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind.clone())?;
+
+        let count = match parse_operand(index, tokens)? {
+            Expr::Immediate(imm) => Some(self.eval_abs_expr(*imm, loc)?),
+            Expr::Register(reg) if reg.lit == "CL" => None,
+            _ => bail!(loc, "shift count must be an 8bit immediate or `%cl`"),
+        };
+        expect(TokenKind::Comma, index, tokens)?;
+        let (src, dst) = parse_two_operand(index, tokens)?;
+        let Expr::Register(src) = src else {
+            bail!(loc, "unsupported operands for `{kind:?}`");
+        };
+        let size = self.operand_size(size, &src)?;
+        if size == DataSizeSuffix::Byte {
+            bail!(loc, "`{kind:?}` does not support 8bit operands");
+        }
+        let opcode = match count {
+            Some(_) => opcode,
+            None => opcode + 1,
+        };
+
+        match dst {
+            Expr::Register(dst) => {
+                self.operand_size(size, &dst)?;
+                self.add_prefix_reg_rm(size, &src, &dst);
+                self.current_instr.code.extend_from_slice(&[
+                    0x0f,
+                    opcode,
+                    compose_mod_rm(MOD_REGI, src.base_offset & 7, dst.base_offset & 7),
+                ]);
+            }
+            mem => {
+                let Some(mem) = Memory::from_expr(&mem, loc)? else {
+                    bail!(loc, "unsupported operands for `{kind:?}`");
+                };
+                self.add_prefix(
+                    src.clone(),
+                    mem.index.clone().unwrap_or_default(),
+                    mem.base.clone().unwrap_or_default(),
+                    &[size],
+                );
+                self.current_instr.code.extend_from_slice(&[0x0f, opcode]);
+                self.add_modrm_sib_disp(&mem, src.base_offset)?;
+            }
+        }
+        match count {
+            Some(count) => self.push_imm(count, DataSizeSuffix::Byte),
+            None => Ok(()),
+        }
+    }
+
+    /// e.g. `bsfq %rax, %rbx`, `popcntl (%rdi), %ecx`
+    ///
+    /// BSF r, r/m: 0f bc /r / BSR: 0f bd / POPCNT: f3 0f b8 / LZCNT: f3 0f bd / TZCNT: f3 0f bc
+    ///
+    /// `lzcnt` and `tzcnt` are `bsr` and `bsf` with `f3`. 8bit is not encodable.
+    pub(crate) fn encode_bit_count(
+        &mut self,
+        kind: InstrKind,
+        f3: bool,
+        opcode: u8,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind.clone())?;
+
+        let (src, dst) = parse_two_operand(index, tokens)?;
+        let Expr::Register(dst) = dst else {
+            bail!(loc, "destination of `{kind:?}` must be a register");
+        };
+        let size = self.operand_size(size, &dst)?;
+        if size == DataSizeSuffix::Byte {
+            bail!(loc, "`{kind:?}` does not support 8bit operands");
+        }
+        // The mandatory prefix follows `66` and precedes REX.
+        if f3 {
+            self.current_instr.code.push(0xf3);
+        }
+
+        match src {
+            Expr::Register(src) => {
+                self.operand_size(size, &src)?;
+                self.add_prefix_reg_rm(size, &dst, &src);
+                self.current_instr.code.extend_from_slice(&[
+                    0x0f,
+                    opcode,
+                    compose_mod_rm(MOD_REGI, dst.base_offset & 7, src.base_offset & 7),
+                ]);
+                Ok(())
+            }
+            mem => {
+                let Some(mem) = Memory::from_expr(&mem, loc)? else {
+                    bail!(loc, "unsupported operands for `{kind:?}`");
+                };
+                self.add_prefix(
+                    dst.clone(),
+                    mem.index.clone().unwrap_or_default(),
+                    mem.base.clone().unwrap_or_default(),
+                    &[size],
+                );
+                self.current_instr.code.extend_from_slice(&[0x0f, opcode]);
+                self.add_modrm_sib_disp(&mem, dst.base_offset)
+            }
+        }
+    }
+
+    /// e.g. `movbe (%rax), %ecx`, `movbeq %rcx, 8(%rdi)`
+    ///
+    /// MOVBE r, m: 0f 38 f0 /r / MOVBE m, r: 0f 38 f1 /r
+    ///
+    /// One operand must be memory, and 8bit is not encodable.
+    pub(crate) fn encode_movbe(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Movbe)?;
+        self.required_feature = Some(CpuFeature::Movbe);
+
+        let (opcode, reg, mem) = match parse_two_operand(index, tokens)? {
+            (Expr::Register(_), Expr::Register(_)) => {
+                bail!(loc, "`movbe` needs a memory operand")
+            }
+            (mem, Expr::Register(dst)) => (0xf0, dst, mem),
+            (Expr::Register(src), mem) => (0xf1, src, mem),
+            _ => bail!(loc, "unsupported operands for `movbe`"),
+        };
+        let mem = match Memory::from_expr(&mem, loc)? {
+            Some(mem) => mem,
+            None => bail!(loc, "unsupported operands for `movbe`"),
+        };
+        let size = self.operand_size(size, &reg)?;
+        if size == DataSizeSuffix::Byte {
+            bail!(loc, "`movbe` does not support 8bit operands");
+        }
+
+        self.add_prefix(
+            reg.clone(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[size],
+        );
+        self.current_instr.code.extend_from_slice(&OPCODE_MAP_0F38);
+        self.current_instr.code.push(opcode);
+        self.add_modrm_sib_disp(&mem, reg.base_offset)
+    }
+
+    /// Decide the operand size from the suffix and the register.
+    fn operand_size(&self, size: DataSizeSuffix, reg: &Register) -> Result<DataSizeSuffix> {
+        let size = match size {
+            DataSizeSuffix::Unknown => reg.size,
+            size => size,
+        };
+        if reg.size != size {
+            bail!(self.current_instr.loc, "operand size mismatch. `{reg}`");
+        }
+        Ok(size)
+    }
+
+    /// Prefixes for `/digit` form. `reg` is r/m and the reg field is an opcode extension.
+    fn add_prefix_rm(&mut self, size: DataSizeSuffix, reg: &Register) {
+        self.add_prefix(
+            Register::default(),
+            Register::default(),
+            reg.clone(),
+            &[size],
+        );
+    }
+
+    /// Prefixes for register to register form.
+    fn add_prefix_reg_rm(&mut self, size: DataSizeSuffix, src: &Register, dst: &Register) {
+        self.add_prefix(src.clone(), Register::default(), dst.clone(), &[size]);
+    }
+
+    /// Push `opcode` for 8bit operand, otherwise `opcode + 1`.
+    fn push_opcode_for(&mut self, size: DataSizeSuffix, opcode: u8) {
+        self.current_instr.code.push(match size {
+            DataSizeSuffix::Byte => opcode,
+            _ => opcode + 1,
+        });
+    }
+
+    /// Push `opcode` and ModR/M of `opcode /digit` with register operand.
+    fn push_opcode_reg(&mut self, opcode: u8, ext: u8, reg: &Register) {
+        self.current_instr
+            .code
+            .extend_from_slice(&[opcode, compose_mod_rm(MOD_REGI, ext, reg.base_offset & 7)]);
+    }
+
+    /// Push the immediate of the operand size. 64bit operand takes sign extended 32bit immediate.
+    fn push_imm(&mut self, imm: i64, size: DataSizeSuffix) -> Result<()> {
+        if size == DataSizeSuffix::Quad && i32::try_from(imm).is_err() {
+            bail!(
+                self.current_instr.loc,
+                "immediate must be sign extended 32bit value. Got {imm:#x}"
+            );
+        }
+        self.push_truncated(imm, size.size_in_bytes().min(4));
+        Ok(())
+    }
+
+    /// `reg, r/m` form with memory operand.
+    ///
+    /// - `opcode`: opcode for 8bit operand. `opcode + 1` is used for the others.
+    pub(crate) fn encode_reg_mem(
+        &mut self,
+        size: DataSizeSuffix,
+        opcode: u8,
+        reg: Register,
+        mem: &Expr,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let mem = match Memory::from_expr(mem, loc)? {
+            Some(mem) => mem,
+            None => bail!(loc, "expected memory operand"),
+        };
+        let size = self.operand_size(size, &reg)?;
+
+        self.add_prefix(
+            reg.clone(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[size],
+        );
+        self.push_opcode_for(size, opcode);
+        self.add_modrm_sib_disp(&mem, reg.base_offset)
+    }
+
+    /// `imm, r/m` form with memory operand. e.g. `movl $1, (%rax)`, `addq $4, 8(%rsp)`
+    ///
+    /// The operand size must be given by the mnemonic suffix, since there is no register operand.
+    ///
+    /// - `ext`: opcode extension of the `/digit` form
+    /// - `imm_size`: size of the immediate which follows the memory operand
+    pub(crate) fn encode_imm_mem(
+        &mut self,
+        size: DataSizeSuffix,
+        opcode: u8,
+        ext: u8,
+        imm: i64,
+        imm_size: DataSizeSuffix,
+        mem: &Expr,
+    ) -> Result<()> {
+        self.encode_digit_mem(size, opcode, ext, mem)?;
+        self.push_imm(imm, imm_size)
+    }
+
+    /// `opcode /digit` form with memory operand. e.g. `negb (%rax)`
+    ///
+    /// The operand size must be given by the mnemonic suffix, since there is no register operand.
+    fn encode_digit_mem(
+        &mut self,
+        size: DataSizeSuffix,
+        opcode: u8,
+        ext: u8,
+        mem: &Expr,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let mem = match Memory::from_expr(mem, loc)? {
+            Some(mem) => mem,
+            None => bail!(loc, "expected memory operand"),
+        };
+        if size == DataSizeSuffix::Unknown {
+            bail!(
+                loc,
+                "no instruction mnemonic suffix given and no register operands"
+            );
+        }
+
+        self.add_prefix(
+            Register::default(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[size],
+        );
+        self.current_instr.code.push(opcode);
+        self.add_modrm_sib_disp(&mem, ext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::code;
+    use crate::error::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_prefer_accumulator_short_form() -> Result<()> {
+        assert_eq!(
+            vec![0x25, 0xff, 0x00, 0x00, 0x00],
+            code("andl $0xff, %eax")?
+        );
+        assert_eq!(vec![0xa8, 0x01], code("testb $1, %al")?);
+        assert_eq!(vec![0x0c, 0x01], code("orb $1, %al")?);
+        assert_eq!(
+            vec![0x48, 0x35, 0x00, 0x01, 0x00, 0x00],
+            code("xorq $256, %rax")?
+        );
+        assert_eq!(vec![0x66, 0xa9, 0x34, 0x12], code("testw $0x1234, %ax")?);
+        // `imm8` form is shorter than the accumulator form.
+        assert_eq!(vec![0x83, 0xe0, 0x01], code("andl $1, %eax")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_alu_general_form() -> Result<()> {
+        assert_eq!(
+            vec![0x81, 0xe3, 0xff, 0x00, 0x00, 0x00],
+            code("andl $0xff, %ebx")?
+        );
+        assert_eq!(vec![0x48, 0x83, 0xec, 0x08], code("subq $8, %rsp")?);
+        assert_eq!(vec![0x48, 0x31, 0xc0], code("xorq %rax, %rax")?);
+        assert_eq!(
+            vec![0x48, 0x3b, 0x7c, 0x24, 0x08],
+            code("cmpq 8(%rsp), %rdi")?
+        );
+        assert_eq!(vec![0x48, 0x85, 0xc0], code("testq %rax, %rax")?);
+        assert_eq!(vec![0xf6, 0xc3, 0x01], code("testb $1, %bl")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_imm_to_mem() -> Result<()> {
+        assert_eq!(
+            vec![0xc7, 0x00, 0x01, 0x00, 0x00, 0x00],
+            code("movl $1,(%rax)")?
+        );
+        assert_eq!(
+            vec![0x48, 0x83, 0x44, 0x24, 0x08, 0x04],
+            code("addq $4,8(%rsp)")?
+        );
+        assert_eq!(vec![0xc6, 0x45, 0xff, 0x41], code("movb $0x41, -1(%rbp)")?);
+        assert_eq!(
+            vec![0x66, 0x81, 0x38, 0x34, 0x12],
+            code("cmpw $0x1234, (%rax)")?
+        );
+        assert_eq!(
+            vec![0x41, 0xf7, 0x00, 0x00, 0x01, 0x00, 0x00],
+            code("testl $256, (%r8)")?
+        );
+        assert!(code("add $1, (%rax)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_multi_byte_nop() -> Result<()> {
+        assert_eq!(vec![0x90], code("nop")?);
+        assert_eq!(vec![0x0f, 0x1f, 0x00], code("nopl (%rax)")?);
+        // The segment override precedes the operand size prefix.
+        assert_eq!(
+            vec![0x2e, 0x66, 0x0f, 0x1f, 0x04, 0x00],
+            code("nopw %cs:0x0(%rax,%rax,1)")?
+        );
+        assert_eq!(vec![0x49, 0x0f, 0x1f, 0xc1], code("nopq %r9")?);
+        assert!(code("nopb (%rax)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_locked_exchange() -> Result<()> {
+        assert_eq!(
+            vec![0xf0, 0x48, 0x0f, 0xc1, 0x03],
+            code("lock xaddq %rax,(%rbx)")?
+        );
+        assert_eq!(
+            vec![0xf0, 0x0f, 0xb1, 0x0a],
+            code("lock cmpxchgl %ecx,(%rdx)")?
+        );
+        assert_eq!(vec![0x0f, 0xc0, 0xd8], code("xaddb %bl, %al")?);
+        assert_eq!(
+            vec![0x66, 0xf0, 0x44, 0x0f, 0xb1, 0x00],
+            code("lock cmpxchgw %r8w, (%rax)")?
+        );
+        assert!(code("lock").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_bit_count() -> Result<()> {
+        // Compared with GNU as
+        assert_eq!(vec![0x48, 0x0f, 0xbc, 0xd8], code("bsfq %rax,%rbx")?);
+        assert_eq!(vec![0xf3, 0x0f, 0xb8, 0xc8], code("popcntl %eax,%ecx")?);
+        assert_eq!(vec![0x44, 0x0f, 0xbd, 0x0f], code("bsrl (%rdi),%r9d")?);
+        assert_eq!(
+            vec![0x66, 0xf3, 0x44, 0x0f, 0xb8, 0xd0],
+            code("popcntw %ax,%r10w")?
+        );
+        assert_eq!(
+            vec![0xf3, 0x4c, 0x0f, 0xbd, 0x7c, 0x24, 0x08],
+            code("lzcntq 8(%rsp),%r15")?
+        );
+        assert_eq!(
+            vec![0xf3, 0x49, 0x0f, 0xbc, 0x0c, 0x8c],
+            code("tzcntq (%r12,%rcx,4),%rcx")?
+        );
+        assert_eq!(vec![0xf3, 0x0f, 0xbd, 0xd8], code("lzcnt %eax,%ebx")?);
+        assert!(code("bsfb %al,%cl").is_err());
+        assert!(code("popcntl %eax,(%rax)").is_err());
+        assert!(code("tzcntq %eax,%rcx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_movbe_both_directions() -> Result<()> {
+        assert_eq!(vec![0x0f, 0x38, 0xf0, 0x08], code("movbe (%rax),%ecx")?);
+        assert_eq!(vec![0x0f, 0x38, 0xf1, 0x08], code("movbe %ecx,(%rax)")?);
+        assert_eq!(
+            vec![0x48, 0x0f, 0x38, 0xf1, 0x4f, 0x08],
+            code("movbeq %rcx, 8(%rdi)")?
+        );
+        assert_eq!(
+            vec![0x66, 0x44, 0x0f, 0x38, 0xf0, 0x06],
+            code("movbew (%rsi), %r8w")?
+        );
+        assert!(code("movbe %eax, %ecx").is_err());
+        assert!(code("movbe (%rax), %cl").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_double_shift() -> Result<()> {
+        assert_eq!(vec![0x0f, 0xa4, 0xd8, 0x04], code("shldl $4,%ebx,%eax")?);
+        assert_eq!(vec![0x48, 0x0f, 0xad, 0x18], code("shrdq %cl,%rbx,(%rax)")?);
+        assert_eq!(
+            vec![0x66, 0x45, 0x0f, 0xa5, 0xca],
+            code("shldw %cl,%r9w,%r10w")?
+        );
+        assert_eq!(
+            vec![0x4d, 0x0f, 0xac, 0x44, 0x8c, 0x08, 0x3f],
+            code("shrd $63,%r8,8(%r12,%rcx,4)")?
+        );
+        assert!(code("shld %dl,%ebx,%eax").is_err());
+        assert!(code("shldb $1,%bl,%al").is_err());
+        assert!(code("shldl $1,%rbx,%rax").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_size_single_memory_operand_by_suffix() -> Result<()> {
+        assert_eq!(vec![0xf6, 0x18], code("negb (%rax)")?);
+        assert_eq!(vec![0x66, 0xf7, 0x10], code("notw (%rax)")?);
+        assert_eq!(vec![0x48, 0xff, 0x00], code("incq (%rax)")?);
+        assert_eq!(vec![0xfe, 0x08], code("decb (%rax)")?);
+        assert_eq!(vec![0x49, 0xf7, 0x71, 0x08], code("divq 8(%r9)")?);
+        assert_eq!(vec![0x48, 0xf7, 0xd8], code("neg %rax")?);
+        assert_eq!(vec![0x41, 0xff, 0xc0], code("incl %r8d")?);
+        assert!(code("neg (%rax)").is_err());
+        assert!(code("negl %ax").is_err());
+        Ok(())
+    }
+}
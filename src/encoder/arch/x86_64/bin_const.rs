@@ -4,6 +4,10 @@ pub(crate) const MOD_INDIRECTION_WITH_DISP32: u8 = 2;
 pub(crate) const MOD_REGI: u8 = 3;
 pub(crate) const REX_W: u8 = 0x48;
 pub(crate) const OPERAND_SIZE_PREFIX16: u8 = 0x66;
+/// Toggle the address size of the mode. e.g. `(%eax)` in 64bit mode
+pub(crate) const ADDRESS_SIZE_PREFIX: u8 = 0x67;
+/// Escape bytes of the three-byte opcode map `0f 38`
+pub(crate) const OPCODE_MAP_0F38: [u8; 2] = [0x0f, 0x38];
 pub(crate) const SLASH_0: usize = 0;
 pub(crate) const SLASH_1: usize = 1;
 pub(crate) const SLASH_2: usize = 2;
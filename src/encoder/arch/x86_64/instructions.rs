@@ -5,6 +5,7 @@ pub(crate) enum InstrKind {
     Section,
     Global,
     Local,
+    Weak,
     Hidden,
     Internal,
     Protected,
@@ -13,6 +14,11 @@ pub(crate) enum InstrKind {
     Word,
     Long,
     Quad,
+    Skip,
+    Fill,
+    Align,
+    /// A row of the line information. e.g. `.loc 1 2 3`
+    Loc,
     Add,
     Sub,
     InstrOr,
@@ -21,6 +27,8 @@ pub(crate) enum InstrKind {
     Xor,
     And,
     Imul,
+    Inc,
+    Dec,
     Idiv,
     Div,
     Neg,
@@ -37,13 +45,40 @@ pub(crate) enum InstrKind {
     Cltq,
     Cltd,
     Cwtl,
+    Cbtw,
+    Cwtd,
     Cmp,
+    Xadd,
+    Cmpxchg,
+    Shld,
+    Shrd,
+    Bsf,
+    Bsr,
+    Popcnt,
+    Lzcnt,
+    Tzcnt,
+    Movbe,
+    Kmov,
+    Vadd,
+    Vsub,
+    Vmul,
+    Vdiv,
+    In,
+    Out,
     Shl,
     Shr,
     Sar,
     Sal,
     Pop,
     Push,
+    Popf,
+    Pushf,
+    Lfence,
+    Endbr,
+    Mfence,
+    Sfence,
+    Clflush,
+    Prefetch,
     Call,
     Seto,
     Setno,
@@ -60,6 +95,8 @@ pub(crate) enum InstrKind {
     Sete,
     Setne,
     Jmp,
+    Loop,
+    Jrcxz,
     Jne,
     Je,
     Jl,
@@ -74,8 +111,19 @@ pub(crate) enum InstrKind {
     Js,
     Jb,
     Jns,
+    Jo,
+    Jno,
+    Jnp,
     Ret,
     Syscall,
+    Sysret,
+    Sysenter,
+    Sysexit,
+    Swapgs,
+    Rdtsc,
+    Rdtscp,
+    Rdpmc,
+    Cpuid,
     Nop,
     Hlt,
     Leave,
@@ -107,5 +155,147 @@ pub(crate) enum InstrKind {
     Xorpd,
     Xorps,
     Pxor,
+    Por,
+    Pand,
     Label,
 }
+
+impl InstrKind {
+    /// The numbers of operands the instruction takes. `None` for directives and labels, which are not counted.
+    pub(crate) fn operand_counts(&self) -> Option<&'static [usize]> {
+        let counts: &[usize] = match self {
+            Self::Cqto
+            | Self::Cltq
+            | Self::Cltd
+            | Self::Cwtl
+            | Self::Cbtw
+            | Self::Cwtd
+            | Self::Rep
+            | Self::Popf
+            | Self::Pushf
+            | Self::Lfence
+            | Self::Mfence
+            | Self::Sfence
+            | Self::Endbr
+            | Self::Syscall
+            | Self::Sysret
+            | Self::Sysenter
+            | Self::Sysexit
+            | Self::Swapgs
+            | Self::Rdtsc
+            | Self::Rdtscp
+            | Self::Rdpmc
+            | Self::Cpuid
+            | Self::Hlt
+            | Self::Leave => &[0],
+            // e.g. `nop`, `nopl (%rax)`
+            Self::Nop | Self::Ret => &[0, 1],
+            Self::Imul
+            | Self::Inc
+            | Self::Dec
+            | Self::Idiv
+            | Self::Div
+            | Self::Neg
+            | Self::Mul
+            | Self::Not
+            | Self::Pop
+            | Self::Push
+            | Self::Clflush
+            | Self::Prefetch
+            | Self::Call
+            | Self::Seto
+            | Self::Setno
+            | Self::Setb
+            | Self::Setnb
+            | Self::Setae
+            | Self::Setbe
+            | Self::Seta
+            | Self::Setpo
+            | Self::Setl
+            | Self::Setg
+            | Self::Setle
+            | Self::Setge
+            | Self::Sete
+            | Self::Setne
+            | Self::Jmp
+            | Self::Loop
+            | Self::Jrcxz
+            | Self::Jne
+            | Self::Je
+            | Self::Jl
+            | Self::Jg
+            | Self::Jle
+            | Self::Jge
+            | Self::Jnb
+            | Self::Jnbe
+            | Self::Jp
+            | Self::Ja
+            | Self::Js
+            | Self::Jb
+            | Self::Jns
+            | Self::Jo
+            | Self::Jno
+            | Self::Jnp => &[1],
+            // The count is 1 if omitted. e.g. `shlq %rax`
+            Self::Shl | Self::Shr | Self::Sar | Self::Sal => &[1, 2],
+            Self::Add
+            | Self::Sub
+            | Self::InstrOr
+            | Self::Adc
+            | Self::Sbb
+            | Self::Xor
+            | Self::And
+            | Self::Cmp
+            | Self::Test
+            | Self::Lea
+            | Self::Mov
+            | Self::Movabsq
+            | Self::Movzx
+            | Self::Movsx
+            | Self::Xadd
+            | Self::Cmpxchg
+            | Self::Bsf
+            | Self::Bsr
+            | Self::Popcnt
+            | Self::Lzcnt
+            | Self::Tzcnt
+            | Self::Movbe
+            | Self::Kmov
+            | Self::In
+            | Self::Out
+            | Self::Cmovs
+            | Self::Cmovns
+            | Self::Cmovge
+            | Self::Cvttss2sil
+            | Self::Cvtsi2ssq
+            | Self::Cvtsi2sdq
+            | Self::Cvtsd2ss
+            | Self::Cvtss2sd
+            | Self::Movss
+            | Self::Movsd
+            | Self::Movd
+            | Self::Ucomiss
+            | Self::Ucomisd
+            | Self::Comisd
+            | Self::Comiss
+            | Self::Subss
+            | Self::Subsd
+            | Self::Addss
+            | Self::Addsd
+            | Self::Mulss
+            | Self::Mulsd
+            | Self::Divss
+            | Self::Divsd
+            | Self::Movaps
+            | Self::Movups
+            | Self::Xorpd
+            | Self::Xorps
+            | Self::Pxor
+            | Self::Por
+            | Self::Pand => &[2],
+            Self::Shld | Self::Shrd | Self::Vadd | Self::Vsub | Self::Vmul | Self::Vdiv => &[3],
+            _ => return None,
+        };
+        Some(counts)
+    }
+}
@@ -4,11 +4,19 @@ pub mod registers;
 
 use self::registers::Register;
 use crate::lexer::TokenKind;
+use alloc::{boxed::Box, string::String};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) enum Expr {
     Ident(String),
+    /// Symbol with a relocation modifier. e.g. `foo@GOTPCREL`
+    Modified {
+        symbol: String,
+        modifier: String,
+    },
     Number(String),
+    /// String literal. e.g. `"AB"` of `.byte "AB"`
+    Str(String),
     /// unary minus
     Neg(Box<Expr>),
     Binop {
@@ -29,11 +37,28 @@ pub(crate) enum Expr {
         scale: Option<Box<Expr>>,
         has_base: bool,
         has_index_scale: bool,
+        /// Segment override. e.g. `%fs` of `%fs:8(%rax)`
+        segment: Option<Register>,
     },
     /// General purpose registers
     Register(Register),
     /// Single instruction, multiple data registers(https://en.wikipedia.org/wiki/Single_instruction,_multiple_data)
     Xmm(Register),
+    /// AVX vector registers. e.g. `%ymm0`
+    Ymm(Register),
+    /// Segment registers. e.g. `%fs`
+    Segment(Register),
+    /// AVX-512 vector registers. e.g. `%zmm0`
+    Zmm(Register),
+    /// AVX-512 opmask registers. e.g. `%k1`
+    Opmask(Register),
+    /// Operand with the opmask decoration. e.g. `%zmm0{%k1}{z}`
+    Masked {
+        operand: Box<Expr>,
+        mask: Register,
+        /// `{z}`: zero the masked elements instead of keeping them.
+        zeroing: bool,
+    },
     /// Expected Register
     Star(Box<Expr>),
 }
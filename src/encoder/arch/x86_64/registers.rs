@@ -1,7 +1,8 @@
 use crate::error::{bail, Result};
+use core::fmt::{self, Write as _};
 use seq_macro::seq;
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Default, PartialEq, Eq)]
 pub(crate) struct Register {
     pub(crate) lit: &'static str,
     pub(crate) size: DataSizeSuffix,
@@ -10,14 +11,33 @@ pub(crate) struct Register {
     pub(crate) rex_required: bool,
 }
 
+/// AT&T spelling. e.g. `%rax`
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('%')?;
+        self.lit
+            .chars()
+            .try_for_each(|c| f.write_char(c.to_ascii_lowercase()))
+    }
+}
+
+/// Same as [`fmt::Display`]. The other fields are decided by the name.
+impl fmt::Debug for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 impl Register {
+    /// Is it the high byte of a 16bit register? e.g. `%ah`
+    pub(crate) fn is_high_byte(&self) -> bool {
+        matches!(self.lit, "AH" | "CH" | "DH" | "BH")
+    }
+
     pub(crate) fn check_reg_size(&self, size: DataSizeSuffix) -> Result<()> {
         match self.size == size {
             true => Ok(()),
-            false => bail!(
-                "invalid size of register for instruction. reg name {}",
-                self.lit
-            ),
+            false => bail!("invalid size of register for instruction. reg name {self}"),
         }
     }
 }
@@ -34,21 +54,32 @@ pub(crate) enum DataSizeSuffix {
     Unknown,
 }
 
-impl TryFrom<char> for DataSizeSuffix {
-    type Error = DataSizeSuffix;
-
-    /// char to DataSizeSuffix enum
+impl DataSizeSuffix {
+    /// Size of the mnemonic suffix letter. e.g. `'q'` of `movq` => Quad
     ///
-    /// name: expected 'Q' | 'L' | 'W' | 'B' (else DataSize enum return `Unknown`)
-    fn try_from(name: char) -> Result<Self, Self::Error> {
-        Ok(match name {
-            'Q' => DataSizeSuffix::Quad,
-            'L' => DataSizeSuffix::Long,
-            'W' => DataSizeSuffix::Word,
-            'B' => DataSizeSuffix::Byte,
-            _ => return Err(DataSizeSuffix::Unknown),
+    /// Both of lower and upper cases are accepted.
+    pub(crate) fn from_suffix_char(c: char) -> Option<Self> {
+        Some(match c.to_ascii_lowercase() {
+            'b' => DataSizeSuffix::Byte,
+            'w' => DataSizeSuffix::Word,
+            'l' => DataSizeSuffix::Long,
+            'q' => DataSizeSuffix::Quad,
+            's' => DataSizeSuffix::Single,
+            'd' => DataSizeSuffix::Double,
+            _ => return None,
         })
     }
+
+    /// Byte length of the operand. `Unknown` is 0.
+    pub(crate) fn size_in_bytes(self) -> usize {
+        match self {
+            DataSizeSuffix::Byte => 1,
+            DataSizeSuffix::Word => 2,
+            DataSizeSuffix::Long | DataSizeSuffix::Single => 4,
+            DataSizeSuffix::Quad | DataSizeSuffix::Double => 8,
+            DataSizeSuffix::Unknown => 0,
+        }
+    }
 }
 
 macro_rules! register_tuple {
@@ -172,6 +203,60 @@ const XMM_REGISTERS: [(&str, Register); 16] = [
 ];
 });
 
+seq!(N in 0..16 {
+const YMM_REGISTERS: [(&str, Register); 16] = [
+    #((
+        concat!("YMM", stringify!(N)),
+        Register {
+            lit: concat!("YMM", stringify!(N)),
+            base_offset: N,
+            size: DataSizeSuffix::Unknown,
+            rex_required: false,
+        },
+    ),)*
+];
+});
+
+seq!(N in 0..32 {
+const ZMM_REGISTERS: [(&str, Register); 32] = [
+    #((
+        concat!("ZMM", stringify!(N)),
+        Register {
+            lit: concat!("ZMM", stringify!(N)),
+            base_offset: N,
+            size: DataSizeSuffix::Unknown,
+            rex_required: false,
+        },
+    ),)*
+];
+});
+
+seq!(N in 0..8 {
+/// AVX-512 opmask registers. `%k0` means no masking in the decoration.
+const K_REGISTERS: [(&str, Register); 8] = [
+    #((
+        concat!("K", stringify!(N)),
+        Register {
+            lit: concat!("K", stringify!(N)),
+            base_offset: N,
+            size: DataSizeSuffix::Unknown,
+            rex_required: false,
+        },
+    ),)*
+];
+});
+
+/// Segment registers. `base_offset` is the `sreg` encoding.
+#[rustfmt::skip]
+const SEGMENT_REGISTERS: [(&str, Register); 6] = [
+    register_tuple!("ES", 0, DataSizeSuffix::Word),
+    register_tuple!("CS", 1, DataSizeSuffix::Word),
+    register_tuple!("SS", 2, DataSizeSuffix::Word),
+    register_tuple!("DS", 3, DataSizeSuffix::Word),
+    register_tuple!("FS", 4, DataSizeSuffix::Word),
+    register_tuple!("GS", 5, DataSizeSuffix::Word),
+];
+
 /// Get(Copy) general register info from GENERAL global const by register name.
 pub(crate) fn get_reg_info_by(reg_name: &str) -> Result<Register> {
     let e = GENERAL_REGISTERS.iter().find(|(reg, _)| reg == &reg_name);
@@ -190,6 +275,42 @@ pub(crate) fn get_xmm_by(reg_name: &str) -> Result<Register> {
     }
 }
 
+/// Get(Copy) YMM register info from YMM global const by register name.
+pub(crate) fn get_ymm_by(reg_name: &str) -> Result<Register> {
+    let e = YMM_REGISTERS.iter().find(|(reg, _)| *reg == reg_name);
+    match e {
+        Some(v) => Ok(v.1.clone()),
+        None => bail!("No such YMM register could be found."),
+    }
+}
+
+/// Get(Copy) ZMM register info from ZMM global const by register name.
+pub(crate) fn get_zmm_by(reg_name: &str) -> Result<Register> {
+    let e = ZMM_REGISTERS.iter().find(|(reg, _)| *reg == reg_name);
+    match e {
+        Some(v) => Ok(v.1.clone()),
+        None => bail!("No such ZMM register could be found."),
+    }
+}
+
+/// Get(Copy) opmask register info from K global const by register name.
+pub(crate) fn get_opmask_by(reg_name: &str) -> Result<Register> {
+    let e = K_REGISTERS.iter().find(|(reg, _)| *reg == reg_name);
+    match e {
+        Some(v) => Ok(v.1.clone()),
+        None => bail!("No such opmask register could be found."),
+    }
+}
+
+/// Get(Copy) segment register info from SEGMENT global const by register name.
+pub(crate) fn get_segment_reg_by(reg_name: &str) -> Result<Register> {
+    let e = SEGMENT_REGISTERS.iter().find(|(reg, _)| *reg == reg_name);
+    match e {
+        Some(v) => Ok(v.1.clone()),
+        None => bail!("No such segment register could be found."),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,4 +338,66 @@ mod tests {
             get_xmm_by("XMM11")
         );
     }
+
+    #[test]
+    fn should_map_suffix_char_to_size() {
+        let sizes = "bwlqsd".chars().map(DataSizeSuffix::from_suffix_char);
+        assert_eq!(
+            vec![
+                Some(DataSizeSuffix::Byte),
+                Some(DataSizeSuffix::Word),
+                Some(DataSizeSuffix::Long),
+                Some(DataSizeSuffix::Quad),
+                Some(DataSizeSuffix::Single),
+                Some(DataSizeSuffix::Double),
+            ],
+            sizes.collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(DataSizeSuffix::Quad),
+            DataSizeSuffix::from_suffix_char('Q')
+        );
+        assert_eq!(None, DataSizeSuffix::from_suffix_char('x'));
+    }
+
+    #[test]
+    fn should_get_size_in_bytes() {
+        let sizes = [
+            DataSizeSuffix::Byte,
+            DataSizeSuffix::Word,
+            DataSizeSuffix::Long,
+            DataSizeSuffix::Quad,
+            DataSizeSuffix::Single,
+            DataSizeSuffix::Double,
+            DataSizeSuffix::Unknown,
+        ];
+        assert_eq!(
+            [1, 2, 4, 8, 4, 8, 0],
+            sizes.map(DataSizeSuffix::size_in_bytes)
+        );
+    }
+
+    #[test]
+    fn should_get_opmask_registers() {
+        assert_eq!(
+            Ok(Register {
+                lit: "K7",
+                size: DataSizeSuffix::Unknown,
+                base_offset: 7,
+                rex_required: false
+            }),
+            get_opmask_by("K7")
+        );
+        assert!(get_opmask_by("K8").is_err());
+        assert_eq!(Ok(31), get_zmm_by("ZMM31").map(|zmm| zmm.base_offset));
+        assert_eq!(Ok(15), get_ymm_by("YMM15").map(|ymm| ymm.base_offset));
+        assert!(get_ymm_by("YMM16").is_err());
+    }
+
+    #[test]
+    fn should_format_register_in_att_syntax() {
+        let rax = get_reg_info_by("RAX").unwrap();
+        assert_eq!("%rax", alloc::format!("{rax}"));
+        assert_eq!("%rax", alloc::format!("{rax:?}"));
+    }
 }
@@ -0,0 +1,400 @@
+//! Minimal DWARF line information by `.file 1 "a.c"` and `.loc 1 2 3`. e.g. for `objdump -l`
+use crate::elf::constants::{R_X86_64_64, STB_LOCAL};
+use crate::encoder::arch::x86_64::instructions::InstrKind;
+use crate::encoder::{
+    is_end_of_statement, parse_expr, skip_statement, Encoder, Instr, Rela, UserDefinedSection,
+};
+use crate::error::Result;
+use crate::lexer::{Token, TokenKind};
+use alloc::{format, string::String, vec::Vec};
+
+/// Version of `.debug_line`. Version 3 has the file names in the header, and is read by every tool.
+const DWARF_VERSION: u16 = 3;
+/// The special opcodes are not used, so the parameters are only for the consumers.
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+/// Number of the operands of each standard opcode. (1..OPCODE_BASE)
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// A row of the line table requested by `.loc`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LineRow {
+    pub(crate) file: u64,
+    pub(crate) line: u64,
+    pub(crate) column: u64,
+    /// Index of the zero sized instruction of `.loc` in `Encoder::instrs`. It gives the address after layout.
+    pub(crate) instr_idx: usize,
+}
+
+/// Append `value` in unsigned LEB128.
+pub(crate) fn push_uleb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Append `value` in signed LEB128.
+pub(crate) fn push_sleb128(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Done if the rest is only the sign extension of the last byte.
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+impl Encoder {
+    /// Register a file for `.loc`. e.g. `.file 1 "a.c"`, `.file 1 "src" "a.c"`
+    ///
+    /// `.file "a.c"` without number names the source file of the symbol table, and is ignored.
+    pub(crate) fn encode_file(
+        &mut self,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        if matches!(
+            tokens.get(*index),
+            Some(Token {
+                kind: TokenKind::Token(_),
+                ..
+            })
+        ) {
+            skip_statement(index, tokens, line);
+            return Ok(());
+        }
+
+        if is_end_of_statement(*index, tokens, line) {
+            bail!(loc, "`.file` expects `number \"name\"`");
+        }
+        let number = self.eval_abs_expr(parse_expr(index, tokens)?, loc)?;
+        let mut names = Vec::new();
+        while let Some(Token {
+            kind: TokenKind::Token(name),
+            loc: name_loc,
+        }) = tokens.get(*index)
+        {
+            if name_loc.line != line {
+                break;
+            }
+            names.push(name.as_str());
+            *index += 1;
+        }
+        let name = match names.as_slice() {
+            [name] => String::from(*name),
+            [dir, name] => format!("{dir}/{name}"),
+            _ => bail!(loc, "`.file` expects `number \"name\"`"),
+        };
+        if number < 1 {
+            bail!(loc, "file number must be positive. Got {number}");
+        }
+        self.debug_files.insert(number as u64, name);
+        Ok(())
+    }
+
+    /// Add a row to the line table at the current address. e.g. `.loc 1 2 3`
+    ///
+    /// The options after the column(e.g. `is_stmt 0`) are ignored.
+    pub(crate) fn encode_loc(
+        &mut self,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let mut numbers = Vec::new();
+        while numbers.len() < 3 {
+            match tokens.get(*index) {
+                Some(Token {
+                    kind: TokenKind::Number(_),
+                    loc: number_loc,
+                }) if number_loc.line == line => {
+                    let number = parse_expr(index, tokens)?;
+                    numbers.push(self.eval_abs_expr(number, loc)?);
+                }
+                _ => break,
+            }
+        }
+        skip_statement(index, tokens, line);
+
+        let (file, row_line, column) = match *numbers.as_slice() {
+            [file, row_line] => (file, row_line, 0),
+            [file, row_line, column] => (file, row_line, column),
+            _ => bail!(loc, "`.loc` expects `file line [column]`"),
+        };
+        if !self.debug_files.contains_key(&(file as u64)) {
+            bail!(
+                loc,
+                "file number {file} of `.loc` is not defined by `.file`"
+            );
+        }
+        if row_line < 0 || column < 0 {
+            bail!(loc, "line and column of `.loc` must not be negative");
+        }
+
+        self.current_instr.kind = InstrKind::Loc;
+        self.line_rows.push(LineRow {
+            file: file as u64,
+            line: row_line as u64,
+            column: column as u64,
+            instr_idx: self.instrs.len(),
+        });
+        Ok(())
+    }
+
+    /// Make `.debug_line` from the rows of `.loc` after layout. Nothing is made without `.loc`.
+    ///
+    /// Each section which has rows is a sequence, which starts at the section relocated by `R_X86_64_64`.
+    pub(crate) fn emit_debug_line(&mut self) -> Result<()> {
+        if self.line_rows.is_empty() {
+            return Ok(());
+        }
+        if self.user_defined_sections.contains_key(".debug_line") {
+            crate::error::bail!("`.debug_line` of the source conflicts with the one by `.loc`");
+        }
+
+        let mut files: Vec<_> = self.debug_files.iter().collect();
+        files.sort();
+        let mut header = Vec::new();
+        header.extend_from_slice(&[1, 1, LINE_BASE as u8, LINE_RANGE, OPCODE_BASE]);
+        header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+        header.push(0); // No include directories
+        for (expected, (&number, name)) in (1..).zip(&files) {
+            if number != expected {
+                crate::error::bail!("file number {expected} of `.file` is missing");
+            }
+            header.extend_from_slice(name.as_bytes());
+            // NUL, directory index, modification time, file size
+            header.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        header.push(0);
+
+        // The sections in order of first appearance of their rows
+        let mut sections: Vec<&str> = Vec::new();
+        for row in &self.line_rows {
+            let section_name = self.instrs[row.instr_idx].section_name.as_str();
+            if !sections.contains(&section_name) {
+                sections.push(section_name);
+            }
+        }
+
+        let mut program = Vec::new();
+        // (offset of the address in the program, section name)
+        let mut set_addresses = Vec::new();
+        for &section_name in &sections {
+            program.extend_from_slice(&[0, 9, DW_LNE_SET_ADDRESS]);
+            set_addresses.push((program.len(), section_name));
+            program.extend_from_slice(&[0; 8]);
+
+            let (mut addr, mut file, mut line, mut column) = (0, 1, 1, 0);
+            for row in &self.line_rows {
+                let instr = &self.instrs[row.instr_idx];
+                if instr.section_name != section_name {
+                    continue;
+                }
+                if row.file != file {
+                    program.push(DW_LNS_SET_FILE);
+                    push_uleb128(&mut program, row.file);
+                    file = row.file;
+                }
+                if row.column != column {
+                    program.push(DW_LNS_SET_COLUMN);
+                    push_uleb128(&mut program, row.column);
+                    column = row.column;
+                }
+                if row.line != line {
+                    program.push(DW_LNS_ADVANCE_LINE);
+                    push_sleb128(&mut program, row.line as i64 - line as i64);
+                    line = row.line;
+                }
+                if instr.addr != addr {
+                    program.push(DW_LNS_ADVANCE_PC);
+                    push_uleb128(&mut program, (instr.addr - addr) as u64);
+                    addr = instr.addr;
+                }
+                program.push(DW_LNS_COPY);
+            }
+
+            // The sequence ends at the end of the section.
+            let end = self.user_defined_sections[section_name].addr;
+            if end != addr {
+                program.push(DW_LNS_ADVANCE_PC);
+                push_uleb128(&mut program, (end - addr) as u64);
+            }
+            program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+        }
+
+        // unit_length and header_length exclude themselves.
+        let mut code = Vec::new();
+        let unit_length = 2 + 4 + header.len() + program.len();
+        code.extend_from_slice(&(unit_length as u32).to_le_bytes());
+        code.extend_from_slice(&DWARF_VERSION.to_le_bytes());
+        code.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        code.extend_from_slice(&header);
+        let program_offset = code.len();
+        code.extend_from_slice(&program);
+
+        let debug_line = Instr {
+            section_name: ".debug_line".into(),
+            ..Default::default()
+        };
+        for (offset, section_name) in set_addresses {
+            // The start of the section is a local symbol, so it is relocated by the section symbol.
+            let start = format!(".Ldebug_line{section_name}");
+            let rela = Rela {
+                uses: start.clone(),
+                instr: debug_line.clone(),
+                offset: program_offset + offset,
+                rtype: R_X86_64_64,
+                addend: Some(0),
+                ..Default::default()
+            };
+            let symbol = Instr {
+                kind: InstrKind::Label,
+                symbol_name: start.clone(),
+                section_name: section_name.into(),
+                binding: STB_LOCAL,
+                ..Default::default()
+            };
+            self.user_defined_symbols.insert(start, symbol);
+            self.rela_text_users.push(rela);
+        }
+        self.user_defined_sections.insert(
+            ".debug_line".into(),
+            UserDefinedSection {
+                addr: code.len(),
+                code,
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::parse;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_encode_leb128() {
+        let uleb = |value| {
+            let mut buf = Vec::new();
+            push_uleb128(&mut buf, value);
+            buf
+        };
+        let sleb = |value| {
+            let mut buf = Vec::new();
+            push_sleb128(&mut buf, value);
+            buf
+        };
+        assert_eq!(vec![0x02], uleb(2));
+        assert_eq!(vec![0x7f], uleb(127));
+        assert_eq!(vec![0x80, 0x01], uleb(128));
+        assert_eq!(vec![0xe5, 0x8e, 0x26], uleb(624485));
+        assert_eq!(vec![0x02], sleb(2));
+        assert_eq!(vec![0x7e], sleb(-2));
+        assert_eq!(vec![0xff, 0x00], sleb(127));
+        assert_eq!(vec![0x80, 0x7f], sleb(-128));
+    }
+
+    /// Number of the rows appended by `DW_LNS_copy` in the line program.
+    fn count_rows(program: &[u8]) -> usize {
+        let (mut i, mut rows) = (0, 0);
+        while i < program.len() {
+            i = match program[i] {
+                // An extended opcode. The length is less than 128 here.
+                0 => i + 2 + program[i + 1] as usize,
+                DW_LNS_COPY => {
+                    rows += 1;
+                    i + 1
+                }
+                // The other opcodes used here take an LEB128 operand.
+                _ => {
+                    i += 1;
+                    while program[i] & 0x80 != 0 {
+                        i += 1;
+                    }
+                    i + 1
+                }
+            };
+        }
+        rows
+    }
+
+    #[test]
+    fn should_emit_row_for_each_loc() -> Result<()> {
+        let src = ".file 1 \"a.c\"\n.text\n.loc 1 3 5\nnop\nnop\n.loc 1 4\nsyscall\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+        encoder.emit_debug_line()?;
+
+        let code = &encoder.user_defined_sections[".debug_line"].code;
+        let header_length = u32::from_le_bytes(code[6..10].try_into().unwrap()) as usize;
+        let program = &code[10 + header_length..];
+        let expected = [
+            // `.text` from 0
+            &[0, 9, DW_LNE_SET_ADDRESS, 0, 0, 0, 0, 0, 0, 0, 0][..],
+            // 3:5 at 0
+            &[DW_LNS_SET_COLUMN, 5, DW_LNS_ADVANCE_LINE, 2, DW_LNS_COPY],
+            // 4:0 at 2
+            &[DW_LNS_SET_COLUMN, 0, DW_LNS_ADVANCE_LINE, 1],
+            &[DW_LNS_ADVANCE_PC, 2, DW_LNS_COPY],
+            // The end of `.text` at 4
+            &[DW_LNS_ADVANCE_PC, 2, 0, 1, DW_LNE_END_SEQUENCE],
+        ]
+        .concat();
+        assert_eq!(expected, program);
+        assert_eq!(2, count_rows(program));
+        assert_eq!(
+            code.len() - 4,
+            u32::from_le_bytes(code[..4].try_into().unwrap()) as usize
+        );
+        // After the standard opcode lengths and the empty include directories
+        assert_eq!(b"a.c\0", &code[10 + 18..10 + 22]);
+
+        let rela = &encoder.rela_text_users[0];
+        assert_eq!(".debug_line", rela.instr.section_name);
+        assert_eq!(
+            (R_X86_64_64, 10 + header_length + 3),
+            (rela.rtype, rela.offset)
+        );
+        assert_eq!(
+            ".text",
+            encoder.user_defined_symbols[&rela.uses].section_name
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_loc_of_undefined_file() -> Result<()> {
+        let err = parse(tokenize(".file 1 \"a.c\"\n.loc 2 1\n")?).unwrap_err();
+        assert_eq!(
+            "file number 2 of `.loc` is not defined by `.file`",
+            err.message
+        );
+        Ok(())
+    }
+}
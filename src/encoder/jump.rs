@@ -0,0 +1,341 @@
+use crate::elf::constants::{R_X86_64_PC32, R_X86_64_PC8, R_X86_64_PLT32, STB_LOCAL};
+use crate::encoder::mem::Memory;
+use crate::encoder::{parse_operand, Encoder, InstrKind, Rela};
+use crate::error::Result;
+use crate::lexer::Token;
+use alloc::{vec, vec::Vec};
+
+/// Conditional jumps. (mnemonic, kind, condition code)
+///
+/// The opcode is `0f 80+cc` for `rel32`.
+const JCC_OPS: [(&str, InstrKind, u8); 30] = [
+    ("jo", InstrKind::Jo, 0x0),
+    ("jno", InstrKind::Jno, 0x1),
+    ("jb", InstrKind::Jb, 0x2),
+    ("jc", InstrKind::Jb, 0x2),
+    ("jnae", InstrKind::Jb, 0x2),
+    ("jnb", InstrKind::Jnb, 0x3),
+    ("jae", InstrKind::Jnb, 0x3),
+    ("jnc", InstrKind::Jnb, 0x3),
+    ("je", InstrKind::Je, 0x4),
+    ("jz", InstrKind::Je, 0x4),
+    ("jne", InstrKind::Jne, 0x5),
+    ("jnz", InstrKind::Jne, 0x5),
+    ("jbe", InstrKind::Jbe, 0x6),
+    ("jna", InstrKind::Jbe, 0x6),
+    ("ja", InstrKind::Ja, 0x7),
+    ("jnbe", InstrKind::Jnbe, 0x7),
+    ("js", InstrKind::Js, 0x8),
+    ("jns", InstrKind::Jns, 0x9),
+    ("jp", InstrKind::Jp, 0xa),
+    ("jpe", InstrKind::Jp, 0xa),
+    ("jnp", InstrKind::Jnp, 0xb),
+    ("jpo", InstrKind::Jnp, 0xb),
+    ("jl", InstrKind::Jl, 0xc),
+    ("jnge", InstrKind::Jl, 0xc),
+    ("jge", InstrKind::Jge, 0xd),
+    ("jnl", InstrKind::Jge, 0xd),
+    ("jle", InstrKind::Jle, 0xe),
+    ("jng", InstrKind::Jle, 0xe),
+    ("jg", InstrKind::Jg, 0xf),
+    ("jnle", InstrKind::Jg, 0xf),
+];
+
+/// Get the opcode of a jump or call by mnemonic. e.g. `jne` => (Jne, [0x0f, 0x85])
+pub(crate) fn jump_op_by(mnemonic: &str) -> Option<(InstrKind, Vec<u8>)> {
+    match mnemonic {
+        "jmp" | "jmpq" => Some((InstrKind::Jmp, vec![0xe9])),
+        "call" | "callq" => Some((InstrKind::Call, vec![0xe8])),
+        _ => JCC_OPS
+            .into_iter()
+            .find(|(name, _, _)| *name == mnemonic)
+            .map(|(_, kind, cc)| (kind, vec![0x0f, 0x80 + cc])),
+    }
+}
+
+/// Get the opcode of a jump which has only the `rel8` form by mnemonic. e.g. `loop` => (Loop, [0xe2])
+///
+/// `jecxz` tests `%ecx` by the address size prefix.
+pub(crate) fn loop_op_by(mnemonic: &str) -> Option<(InstrKind, &'static [u8])> {
+    Some(match mnemonic {
+        "loop" => (InstrKind::Loop, &[0xe2]),
+        "loope" | "loopz" => (InstrKind::Loop, &[0xe1]),
+        "loopne" | "loopnz" => (InstrKind::Loop, &[0xe0]),
+        "jrcxz" => (InstrKind::Jrcxz, &[0xe3]),
+        "jecxz" => (InstrKind::Jrcxz, &[0x67, 0xe3]),
+        _ => return None,
+    })
+}
+
+impl Encoder {
+    /// e.g. `jmp .L1`, `call foo`, `jne .L2`
+    ///
+    /// The target is always encoded as `rel32` with `R_X86_64_PLT32`.
+    /// The ones to local labels of the same section are resolved by [`Self::fix_same_section_relocations`].
+    pub(crate) fn encode_jump(
+        &mut self,
+        kind: InstrKind,
+        opcode: &[u8],
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.current_instr.is_jmp_or_call = true;
+        self.encode_relative_target(kind, opcode, 4, R_X86_64_PLT32, index, tokens)
+    }
+
+    /// e.g. `loop .L1`, `jrcxz .L2`
+    ///
+    /// The target is `rel8` with `R_X86_64_PC8`, and must be resolved by [`Self::fix_same_section_relocations`].
+    pub(crate) fn encode_loop(
+        &mut self,
+        kind: InstrKind,
+        opcode: &[u8],
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.encode_relative_target(kind, opcode, 1, R_X86_64_PC8, index, tokens)
+    }
+
+    /// Encode `opcode` followed by the displacement of `disp_size` bytes to the target, which is relocated by `rtype`.
+    fn encode_relative_target(
+        &mut self,
+        kind: InstrKind,
+        opcode: &[u8],
+        disp_size: usize,
+        rtype: u64,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind)?;
+        self.check_no_data16()?;
+
+        let target = match Memory::from_expr(&parse_operand(index, tokens)?, loc)? {
+            Some(Memory {
+                disp: Some(disp),
+                base: None,
+                index: None,
+                ..
+            }) => disp,
+            _ => bail!(loc, "expected a label as the jump target"),
+        };
+        let mut used_symbols = Vec::new();
+        let adjust = self.eval_expr_with_symbols(target, &mut used_symbols)?;
+        let symbol = match used_symbols.as_slice() {
+            [symbol] => symbol.clone(),
+            _ => bail!(loc, "jump target must refer to exactly one symbol"),
+        };
+
+        self.current_instr.code.extend_from_slice(opcode);
+        let offset = self.current_instr.code.len();
+        self.current_instr.code.resize(offset + disp_size, 0);
+        self.rela_text_users.push(Rela {
+            uses: symbol,
+            instr: self.current_instr.clone(),
+            offset,
+            rtype,
+            adjust: adjust as i32,
+            instr_idx: Some(self.instrs.len()),
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// `data16` would truncate the target to 16bit `IP`, which is never intended in 64bit mode.
+    fn check_no_data16(&self) -> Result<()> {
+        if self.explicit_prefix.data16 {
+            bail!(
+                self.current_instr.loc,
+                "16bit relative jumps are not supported in 64bit mode"
+            );
+        }
+        Ok(())
+    }
+
+    /// Resolve the `rel32` and `rel8` relocations to the local symbols in the same section, after the addresses are assigned.
+    ///
+    /// The displacement is computed in `i64`, so backward references get negative values.
+    /// It is reported at the instruction if it does not fit, instead of being truncated.
+    pub(crate) fn fix_same_section_relocations(&mut self) -> Result<()> {
+        for rela in &mut self.rela_text_users {
+            let size = match rela.rtype {
+                R_X86_64_PC32 | R_X86_64_PLT32 => 4,
+                R_X86_64_PC8 if matches!(rela.instr.kind, InstrKind::Loop | InstrKind::Jrcxz) => 1,
+                _ => continue,
+            };
+            if rela.is_already_resolved {
+                continue;
+            }
+            let Some(symbol) = self.user_defined_symbols.get(&rela.uses) else {
+                continue;
+            };
+            if symbol.binding != STB_LOCAL || symbol.section_name != rela.instr.section_name {
+                continue;
+            }
+
+            // S + A - P
+            let place = (rela.instr.addr + rela.offset) as i64;
+            let value = symbol.addr as i64 + rela.addend() - place;
+            let fits = match size {
+                1 => i8::try_from(value).is_ok(),
+                _ => i32::try_from(value).is_ok(),
+            };
+            if !fits {
+                bail!(
+                    rela.instr.loc,
+                    "target `{}` is out of range of rel{}. {value} bytes away",
+                    rela.uses,
+                    size * 8
+                );
+            }
+            let bytes = &value.to_le_bytes()[..size];
+
+            if let Some(idx) = rela.instr_idx {
+                self.instrs[idx].code[rela.offset..rela.offset + size].copy_from_slice(bytes);
+                rela.instr = self.instrs[idx].clone();
+            }
+            if let Some(section) = self.user_defined_sections.get_mut(&rela.instr.section_name) {
+                let place = place as usize;
+                section.code[place..place + size].copy_from_slice(bytes);
+            }
+            rela.is_already_resolved = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::constants::R_X86_64_64;
+    use crate::encoder::parse;
+    use crate::error::Result;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_resolve_backward_jump() -> Result<()> {
+        let mut encoder = parse(tokenize("top:\nnop\n.p2align 2\njmp top\n")?)?;
+        encoder.assign_addresses()?;
+
+        // `jmp` is at 4 after the alignment, and `top` is at 0. So rel32 = 0 - (4 + 5) = -9
+        assert_eq!(
+            vec![0xe9, 0xf7, 0xff, 0xff, 0xff],
+            encoder.instrs.last().unwrap().code
+        );
+        assert!(encoder.rela_text_users[0].is_already_resolved);
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_forward_conditional_jump() -> Result<()> {
+        let mut encoder = parse(tokenize("jne .L1\nnop\n.L1:\ncall foo\n")?)?;
+        encoder.assign_addresses()?;
+
+        assert_eq!(
+            vec![0x0f, 0x85, 0x01, 0x00, 0x00, 0x00],
+            encoder.instrs[0].code
+        );
+        // `foo` is undefined, so it is left to the linker.
+        assert!(!encoder.rela_text_users[1].is_already_resolved);
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_rel8_loop() -> Result<()> {
+        let mut encoder = parse(tokenize(
+            "top:
+nop
+loop top
+jecxz .L1
+.L1:
+",
+        )?)?;
+        encoder.assign_addresses()?;
+
+        assert_eq!(vec![0xe2, 0xfd], encoder.instrs[2].code);
+        assert_eq!(vec![0x67, 0xe3, 0x00], encoder.instrs[3].code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_out_of_range_loop_at_its_line() -> Result<()> {
+        let mut encoder = parse(tokenize(
+            "top:
+.skip 200
+nop
+loop top
+",
+        )?)?;
+
+        let err = encoder.assign_addresses().unwrap_err().to_string();
+        assert!(err.contains("4:"), "{err}");
+        assert!(err.contains("`top` is out of range of rel8"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_out_of_range_rel32_at_its_line() -> Result<()> {
+        let mut encoder = parse(tokenize("nop\njmp far\nfar:\n")?)?;
+        encoder.assign_addresses()?;
+
+        // A gap over 2GiB is too large to be laid out in a test, so `far` is moved by hand.
+        encoder.rela_text_users[0].is_already_resolved = false;
+        encoder.user_defined_symbols.get_mut("far").unwrap().addr = 1 << 32;
+        let err = encoder.fix_same_section_relocations().unwrap_err();
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+        assert!(err.to_string().contains("out of range of rel32"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn should_lay_out_data_between_code() -> Result<()> {
+        let mut encoder = parse(tokenize(
+            "foo:
+nop
+.quad foo
+bar:
+nop
+jmp bar
+leaq table(%rip), %rax
+.byte 1, 2
+table:
+.quad bar
+",
+        )?)?;
+        encoder.assign_addresses()?;
+
+        let addrs: Vec<_> = encoder.instrs.iter().map(|instr| instr.addr).collect();
+        assert_eq!(vec![0, 0, 1, 9, 9, 10, 15, 22, 24, 24], addrs);
+        // `jmp` to the code after data: 9 - (10 + 5) = -6
+        assert_eq!(vec![0xe9, 0xfa, 0xff, 0xff, 0xff], encoder.instrs[5].code);
+        // `leaq` to the data after code: 24 - (15 + 7) = 2
+        assert_eq!(
+            vec![0x48, 0x8d, 0x05, 0x02, 0x00, 0x00, 0x00],
+            encoder.instrs[6].code
+        );
+
+        // The absolute addresses in data are left to the linker.
+        let data_relas: Vec<_> = encoder
+            .rela_text_users
+            .iter()
+            .filter(|rela| rela.rtype == R_X86_64_64)
+            .map(|rela| {
+                (
+                    rela.uses.as_str(),
+                    rela.instr.addr,
+                    rela.is_already_resolved,
+                )
+            })
+            .collect();
+        assert_eq!(vec![("foo", 1, false), ("bar", 24, false)], data_relas);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_16bit_relative_jump() -> Result<()> {
+        assert!(parse(tokenize("data16 jmp foo\n")?).is_err());
+        assert!(parse(tokenize("data16 call foo\n")?).is_err());
+        assert!(parse(tokenize("top:\ndata16 loop top\n")?).is_err());
+        Ok(())
+    }
+}
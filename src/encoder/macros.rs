@@ -0,0 +1,280 @@
+//! `.macro` definitions and their expansion. e.g. `.macro load reg, value` ... `.endm`
+use crate::encoder::{is_end_of_statement, skip_statement, Encoder};
+use crate::error::Result;
+use crate::lexer::{tokenize_from, Location, Token, TokenKind};
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+
+/// Macros may invoke other macros, but not deeper than this. e.g. a macro which invokes itself
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+/// A macro defined by `.macro name params...` and `.endm`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Macro {
+    pub(crate) params: Vec<String>,
+    /// Source lines between `.macro` and `.endm`. The parameters are substituted as text. e.g. `\reg`
+    pub(crate) body: String,
+    /// Location of the first line of the body. The diagnostics in expansions point into the body.
+    pub(crate) loc: Location,
+}
+
+/// Split the arguments of a macro invocation by the commas out of parentheses and strings.
+///
+/// e.g. `%rax, 8(%rsp, %rcx, 4)` => [`%rax`, `8(%rsp, %rcx, 4)`]
+fn split_macro_args(text: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let (mut start, mut depth, mut quote) = (0, 0usize, None);
+    for (idx, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ',') if depth == 0 => {
+                args.push(text[start..idx].trim());
+                start = idx + 1;
+            }
+            // A comment ends the arguments.
+            (None, '#') => return finish_args(args, &text[start..idx]),
+            _ => {}
+        }
+    }
+    finish_args(args, &text[start..])
+}
+
+fn finish_args<'a>(mut args: Vec<&'a str>, last: &'a str) -> Vec<&'a str> {
+    if !args.is_empty() || !last.trim().is_empty() {
+        args.push(last.trim());
+    }
+    args
+}
+
+/// Replace `\param` in `body` with the argument of the same position, and `\()` with nothing.
+///
+/// The other backslashes are kept. e.g. `\n` in a string
+fn substitute(body: &str, params: &[String], args: &[&str]) -> String {
+    let mut expanded = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(idx) = rest.find('\\') {
+        expanded.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        if let Some(after) = after.strip_prefix("()") {
+            rest = after;
+            continue;
+        }
+        let len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(after.len());
+        match params.iter().position(|param| *param == after[..len]) {
+            Some(pos) => {
+                expanded.push_str(args.get(pos).copied().unwrap_or_default());
+                rest = &after[len..];
+            }
+            None => {
+                expanded.push('\\');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+impl Encoder {
+    /// Define a macro by the statements until `.endm`.
+    ///
+    /// e.g. `.macro load reg, value` or `.macro load, reg value`
+    pub(crate) fn define_macro(
+        &mut self,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        if !self.expansion_texts.is_empty() {
+            bail!(loc, "`.macro` cannot be defined in a macro expansion");
+        }
+        let name = match tokens.get(*index) {
+            Some(Token {
+                kind: TokenKind::Ident(name),
+                ..
+            }) if !is_end_of_statement(*index, tokens, line) => name.clone(),
+            _ => bail!(loc, "expected macro name"),
+        };
+        *index += 1;
+        let mut params = Vec::new();
+        while !is_end_of_statement(*index, tokens, line) {
+            match &tokens[*index].kind {
+                TokenKind::Ident(param) => params.push(param.clone()),
+                TokenKind::Comma => {}
+                kind => bail!(tokens[*index].loc, "invalid macro parameter. Got {kind:?}"),
+            }
+            *index += 1;
+        }
+        if self.macros.contains_key(&name) {
+            bail!(loc, "macro `{name}` is already defined");
+        }
+
+        // The body ends at `.endm` of the same depth.
+        let mut depth = 0;
+        let endm = loop {
+            let Some(token) = tokens.get(*index) else {
+                bail!(loc, "unterminated macro `{name}`. expected `.endm`");
+            };
+            match &token.kind {
+                TokenKind::Ident(name) if name == ".macro" => depth += 1,
+                TokenKind::Ident(name) if name == ".endm" && depth == 0 => break token.loc,
+                TokenKind::Ident(name) if name == ".endm" => depth -= 1,
+                _ => {}
+            }
+            skip_statement(index, tokens, token.loc.line);
+        };
+        skip_statement(index, tokens, endm.line);
+
+        let start = match self.source[loc.offset..].find('\n') {
+            Some(newline) => loc.offset + newline + 1,
+            None => self.source.len(),
+        };
+        let end = self.source[..endm.offset]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        let body = self.source[start..end.max(start)].to_owned();
+        let body_loc = Location {
+            line: line + 1,
+            column: 0,
+            offset: start,
+        };
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                loc: body_loc,
+            },
+        );
+        Ok(())
+    }
+
+    /// Expand the macro invoked by the statement at `index`, and encode the statements of the expansion.
+    ///
+    /// e.g. `load %rax, 1`
+    pub(crate) fn expand_macro(&mut self, index: &mut usize, tokens: &[Token]) -> Result<()> {
+        let Token { kind, loc } = &tokens[*index];
+        let TokenKind::Ident(name) = kind else {
+            bail!(*loc, "expected macro name");
+        };
+        if self.expansion_texts.len() >= MAX_EXPANSION_DEPTH {
+            bail!(
+                *loc,
+                "macro `{name}` is expanded too deeply. Does it invoke itself?"
+            );
+        }
+        let mac = self.macros[name].clone();
+
+        *index += 1;
+        let args = match tokens.get(*index) {
+            Some(first) if !is_end_of_statement(*index, tokens, loc.line) => {
+                let (start, line) = self.line_of(first.loc);
+                split_macro_args(&line[first.loc.offset - start..])
+            }
+            _ => Vec::new(),
+        };
+        if args.len() > mac.params.len() {
+            bail!(
+                *loc,
+                "macro `{name}` takes {} argument(s), but got {}",
+                mac.params.len(),
+                args.len()
+            );
+        }
+        let expanded = substitute(&mac.body, &mac.params, &args);
+        skip_statement(index, tokens, loc.line);
+
+        let body_tokens = tokenize_from(&expanded, mac.loc)?;
+        self.expansion_texts.push((mac.loc.offset, expanded));
+        self.recorded_line = None;
+        let mut body_index = 0;
+        let mut result = Ok(());
+        while body_index < body_tokens.len() && result.is_ok() {
+            result = self.encode_statement(&mut body_index, &body_tokens);
+        }
+        self.expansion_texts.pop();
+        self.recorded_line = None;
+        result
+    }
+
+    /// The line of `loc` and the offset of its start, in the macro expansion being encoded if any.
+    pub(crate) fn line_of(&self, loc: Location) -> (usize, &str) {
+        let (base, text) = match self.expansion_texts.last() {
+            Some((base, text)) => (*base, text.as_str()),
+            None => (0, self.source.as_str()),
+        };
+        let offset = loc.offset - base;
+        let start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+        let end = text[offset..]
+            .find('\n')
+            .map_or(text.len(), |newline| offset + newline);
+        (base + start, &text[start..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn feed(src: &str) -> Result<Encoder> {
+        let mut encoder = Encoder::default();
+        encoder.feed(src)?;
+        Ok(encoder)
+    }
+
+    #[test]
+    fn should_split_macro_args() {
+        assert_eq!(
+            vec!["%rax", "8(%rsp, %rcx, 4)", "\"a, b\""],
+            split_macro_args(" %rax, 8(%rsp, %rcx, 4), \"a, b\" # comment, ignored")
+        );
+        assert_eq!(vec!["", "1"], split_macro_args(", 1"));
+        assert!(split_macro_args("  # comment").is_empty());
+    }
+
+    #[test]
+    fn should_substitute_macro_params() {
+        let params = ["reg".to_owned(), "num".to_owned()];
+        assert_eq!(
+            "movq $1, %rax\nlabel_1:\n.ascii \"\\t\\n\"\n",
+            substitute(
+                "movq $\\num, %\\reg\nlabel_\\num\\():\n.ascii \"\\t\\n\"\n",
+                &params,
+                &["rax", "1"]
+            )
+        );
+    }
+
+    #[test]
+    fn should_expand_macro() -> Result<()> {
+        let src = ".macro zero reg\n  xorl %\\reg, %\\reg\n.endm\n.macro twice a, b\n  zero \\a\n  zero \\b\n.endm\nzero eax\ntwice ecx, edx\nzero eax\n";
+        let encoder = feed(src)?;
+        let code: Vec<_> = encoder.instrs.iter().map(|i| i.code.clone()).collect();
+        assert_eq!(
+            vec![
+                vec![0x31, 0xc0],
+                vec![0x31, 0xc9],
+                vec![0x31, 0xd2],
+                vec![0x31, 0xc0]
+            ],
+            code
+        );
+        // The errors in the expansion point to the body.
+        assert_eq!(1, encoder.instrs[0].loc.line);
+
+        let err = feed(".macro m\n  movq %rax,\n.endm\nm\n").unwrap_err();
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+        assert!(feed(".macro m\nnop\n").is_err());
+        assert!(feed(".macro m\nm\n.endm\nm\n").is_err());
+        assert!(feed(".macro m a\n.endm\nm 1, 2\n").is_err());
+        assert!(feed(".endm\n").is_err());
+        Ok(())
+    }
+}
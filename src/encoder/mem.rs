@@ -0,0 +1,450 @@
+use crate::elf::constants::{
+    R_X86_64_32S, R_X86_64_DTPOFF32, R_X86_64_GOTPCREL, R_X86_64_GOTPCRELX, R_X86_64_GOTTPOFF,
+    R_X86_64_PC32, R_X86_64_REX_GOTPCRELX, R_X86_64_TPOFF32,
+};
+use crate::encoder::arch::x86_64::{
+    bin_const::{
+        ADDRESS_SIZE_PREFIX, MOD_INDIRECTION_WITH_DISP32, MOD_INDIRECTION_WITH_DISP8,
+        MOD_INDIRECTION_WITH_NO_DISP,
+    },
+    registers::{DataSizeSuffix, Register},
+    Expr,
+};
+use crate::encoder::{compose_mod_rm, parse_operand, CodeMode, Encoder, InstrKind, Rela};
+use crate::error::Result;
+use crate::lexer::{Location, Token};
+use alloc::{boxed::Box, vec::Vec};
+
+/// r/m or base field value which means "SIB byte follows"(and "no index" in the index field).
+const RM_SIB: u8 = 0b100;
+/// r/m or base field value which means `disp32` with mod=00. (`%rip` relative for r/m)
+const RM_DISP32: u8 = 0b101;
+
+/// Segment override prefixes, indexed by the `sreg` encoding. (es, cs, ss, ds, fs, gs)
+const SEGMENT_OVERRIDE_PREFIXES: [u8; 6] = [0x26, 0x2e, 0x36, 0x3e, 0x64, 0x65];
+
+/// Memory operand. e.g. `disp(%base, %index, scale)`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Memory {
+    pub(crate) disp: Option<Expr>,
+    pub(crate) base: Option<Register>,
+    pub(crate) index: Option<Register>,
+    /// 1, 2, 4 or 8
+    pub(crate) scale: u8,
+    /// Segment override. e.g. `%fs`
+    pub(crate) segment: Option<Register>,
+}
+
+impl Memory {
+    /// Returns `None` if `expr` is not a memory operand.
+    pub(crate) fn from_expr(expr: &Expr, loc: Location) -> Result<Option<Self>> {
+        let Expr::Indirection {
+            disp,
+            base,
+            index,
+            scale,
+            segment,
+            ..
+        } = expr
+        else {
+            return Ok(None);
+        };
+
+        let register = |expr: &Option<Box<Expr>>| -> Result<Option<Register>> {
+            match expr.as_deref() {
+                Some(Expr::Register(reg)) => Ok(Some(reg.clone())),
+                Some(_) => bail!(loc, "expected general purpose register in memory operand"),
+                None => Ok(None),
+            }
+        };
+        let scale = match scale.as_deref() {
+            Some(Expr::Number(scale)) => match scale.as_str() {
+                "1" => 1,
+                "2" => 2,
+                "4" => 4,
+                "8" => 8,
+                _ => bail!(loc, "scale factor must be 1, 2, 4 or 8. Got {scale}"),
+            },
+            Some(_) => bail!(loc, "scale factor must be a number"),
+            None => 1,
+        };
+
+        let memory = Memory {
+            disp: disp.as_deref().cloned(),
+            base: register(base)?,
+            index: register(index)?,
+            scale,
+            segment: segment.clone(),
+        };
+        if memory
+            .index
+            .as_ref()
+            .is_some_and(|index| index.lit == "RSP")
+        {
+            bail!(loc, "`%rsp` cannot be used as index register");
+        }
+        Ok(Some(memory))
+    }
+
+    pub(crate) fn is_rip_relative(&self) -> bool {
+        self.base
+            .as_ref()
+            .is_some_and(|base| base.lit == "RIP" || base.lit == "EIP")
+    }
+}
+
+impl Encoder {
+    /// Encode an instruction whose only operand is memory. e.g. `clflush (%rax)`, `prefetcht0 (%rdi)`
+    ///
+    /// - `ext`: opcode extension of the `/digit` form
+    pub(crate) fn encode_mem_operand(
+        &mut self,
+        kind: InstrKind,
+        opcode: &[u8],
+        ext: u8,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind)?;
+        let mem = match Memory::from_expr(&parse_operand(index, tokens)?, loc)? {
+            Some(mem) => mem,
+            None => bail!(loc, "expected memory operand"),
+        };
+
+        self.add_prefix(
+            Register::default(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[],
+        );
+        self.current_instr.code.extend_from_slice(opcode);
+        self.add_modrm_sib_disp(&mem, ext)
+    }
+
+    /// Does `mem` use the address size other than the default one of [`CodeMode`]? e.g. `(%eax)` in 64bit mode
+    ///
+    /// 16bit addressing(e.g. `(%bx,%si)`) has another ModR/M table, and is not supported.
+    /// So the absolute address in 16bit mode is encoded in 32bit addressing.
+    fn needs_address_size_prefix(&self, mem: &Memory) -> Result<bool> {
+        let loc = self.current_instr.loc;
+        if let (Some(base), Some(index)) = (&mem.base, &mem.index) {
+            if base.size != index.size {
+                bail!(
+                    loc,
+                    "base `{base}` and index `{index}` must be the same size"
+                );
+            }
+        }
+        let size = mem.base.iter().chain(&mem.index).map(|reg| reg.size).next();
+        Ok(match (self.code_mode, size) {
+            (CodeMode::Code64, Some(DataSizeSuffix::Long)) => true,
+            (CodeMode::Code64, _) => false,
+            (_, Some(DataSizeSuffix::Quad)) => {
+                bail!(loc, "64bit address is only available in `.code64`")
+            }
+            (_, Some(DataSizeSuffix::Word)) => bail!(loc, "16bit addressing is not supported"),
+            (CodeMode::Code16, _) => true,
+            (CodeMode::Code32, _) => false,
+        })
+    }
+
+    /// Append ModR/M, SIB and displacement of a memory operand.
+    ///
+    /// - `reg_op`: reg field of ModR/M. A register operand or an opcode extension(e.g. `/0`)
+    ///
+    /// A symbol in the displacement is relocated by `R_X86_64_PC32` for `%rip` relative, otherwise `R_X86_64_32S`.
+    /// `foo@GOTPCREL(%rip)` is relocated by [`Self::gotpcrel_type`].
+    /// The thread-local variables are accessed by `%fs:foo@tpoff`, `foo@dtpoff(%rax)` and `foo@gottpoff(%rip)`.
+    pub(crate) fn add_modrm_sib_disp(&mut self, mem: &Memory, reg_op: u8) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let reg_op = reg_op & 7;
+
+        if self.needs_address_size_prefix(mem)? {
+            self.current_instr.code.insert(0, ADDRESS_SIZE_PREFIX);
+        }
+        // The segment override precedes all the other prefixes, as GNU as does.
+        if let Some(segment) = &mem.segment {
+            let prefix = SEGMENT_OVERRIDE_PREFIXES[segment.base_offset as usize];
+            self.current_instr.code.insert(0, prefix);
+        }
+
+        let mut used_symbols = Vec::new();
+        let disp = match &mem.disp {
+            Some(disp) => self.eval_expr_with_symbols(disp.clone(), &mut used_symbols)?,
+            None => 0,
+        };
+        let symbol = match used_symbols.as_slice() {
+            [] => None,
+            [symbol] => Some(symbol.clone()),
+            _ => bail!(loc, "displacement must refer to at most one symbol"),
+        };
+        let disp = match i32::try_from(disp) {
+            Ok(disp) => disp,
+            Err(_) => bail!(loc, "displacement out of range. Got {disp}"),
+        };
+        let modifier = mem.disp.as_ref().and_then(relocation_modifier);
+        // The modifiers are case insensitive. e.g. `@GOTPCREL`, `@tpoff`
+        let upper = modifier.map(str::to_ascii_uppercase);
+        let rtype = match (upper.as_deref(), mem.is_rip_relative()) {
+            (None, true) => R_X86_64_PC32,
+            (None, false) => R_X86_64_32S,
+            (Some("GOTPCREL"), true) => self.gotpcrel_type(reg_op),
+            (Some("GOTTPOFF"), true) => R_X86_64_GOTTPOFF,
+            (Some("TPOFF"), false) => R_X86_64_TPOFF32,
+            (Some("DTPOFF"), false) => R_X86_64_DTPOFF32,
+            (Some("GOTPCREL" | "GOTTPOFF"), false) => bail!(
+                loc,
+                "`@{}` requires `%rip` relative addressing",
+                modifier.unwrap_or_default()
+            ),
+            (Some("TPOFF" | "DTPOFF"), true) => bail!(
+                loc,
+                "`@{}` cannot be `%rip` relative",
+                modifier.unwrap_or_default()
+            ),
+            (Some(_), _) => bail!(
+                loc,
+                "unsupported relocation modifier `@{}`",
+                modifier.unwrap_or_default()
+            ),
+        };
+
+        let code = &mut self.current_instr.code;
+        let is_disp32 = match (&mem.base, &mem.index) {
+            (Some(_), _) if mem.is_rip_relative() => {
+                if mem.index.is_some() {
+                    bail!(loc, "`%rip` cannot be used with index register");
+                }
+                code.push(compose_mod_rm(
+                    MOD_INDIRECTION_WITH_NO_DISP,
+                    reg_op,
+                    RM_DISP32,
+                ));
+                true
+            }
+            (Some(base), index) => {
+                let base_bits = base.base_offset & 7;
+                // `(%rbp)` and `(%r13)` with mod=00 mean `disp32` without base, so they need an explicit disp.
+                let r#mod = match (symbol.is_some(), disp) {
+                    (true, _) => MOD_INDIRECTION_WITH_DISP32,
+                    (false, 0) if base_bits != RM_DISP32 => MOD_INDIRECTION_WITH_NO_DISP,
+                    (false, -128..=127) => MOD_INDIRECTION_WITH_DISP8,
+                    (false, _) => MOD_INDIRECTION_WITH_DISP32,
+                };
+
+                match index {
+                    Some(index) => {
+                        code.push(compose_mod_rm(r#mod, reg_op, RM_SIB));
+                        code.push(sib(mem.scale, index.base_offset & 7, base_bits));
+                    }
+                    // `(%rsp)` and `(%r12)` collide with the SIB escape, so they need a SIB without index.
+                    None if base_bits == RM_SIB => {
+                        code.push(compose_mod_rm(r#mod, reg_op, RM_SIB));
+                        code.push(sib(1, RM_SIB, base_bits));
+                    }
+                    None => code.push(compose_mod_rm(r#mod, reg_op, base_bits)),
+                }
+
+                if r#mod == MOD_INDIRECTION_WITH_DISP8 {
+                    code.push(disp as u8);
+                    return Ok(());
+                }
+                r#mod == MOD_INDIRECTION_WITH_DISP32
+            }
+            // No base means absolute `disp32`. It is expressed by SIB with base=101.
+            (None, index) => {
+                code.push(compose_mod_rm(MOD_INDIRECTION_WITH_NO_DISP, reg_op, RM_SIB));
+                match index {
+                    Some(index) => code.push(sib(mem.scale, index.base_offset & 7, RM_DISP32)),
+                    None => code.push(sib(1, RM_SIB, RM_DISP32)),
+                }
+                true
+            }
+        };
+        if !is_disp32 {
+            return Ok(());
+        }
+
+        let offset = code.len();
+        match symbol {
+            Some(symbol) => {
+                code.extend_from_slice(&[0; 4]);
+                self.rela_text_users.push(Rela {
+                    uses: symbol,
+                    instr: self.current_instr.clone(),
+                    offset,
+                    rtype,
+                    adjust: disp,
+                    instr_idx: Some(self.instrs.len()),
+                    ..Default::default()
+                });
+            }
+            None => code.extend_from_slice(&disp.to_le_bytes()),
+        }
+        Ok(())
+    }
+
+    /// Relocation type of `@GOTPCREL`. The opcode must be already pushed.
+    ///
+    /// Without [`crate::AssembleOptions::relax_relocations`], it is always `R_X86_64_GOTPCREL` for old linkers.
+    /// Otherwise the instructions the linker can relax(mov, test, binop, call and jmp) take
+    /// `R_X86_64_REX_GOTPCRELX` with REX prefix or `R_X86_64_GOTPCRELX` without it, as GNU as does.
+    fn gotpcrel_type(&self, reg_op: u8) -> u64 {
+        let code = &self.current_instr.code;
+        let is_relaxable = match code.last() {
+            // mov: 8b, test: 85, binop(e.g. add r, r/m): 03+8*ext
+            Some(0x8b | 0x85) => true,
+            Some(&opcode) if opcode | 0x38 == 0x3b => true,
+            // call: ff /2, jmp: ff /4
+            Some(0xff) => matches!(reg_op, 2 | 4),
+            _ => false,
+        };
+        if !self.options.relax_relocations || !is_relaxable {
+            return R_X86_64_GOTPCREL;
+        }
+
+        let has_rex = code.len() >= 2 && code[code.len() - 2] & 0xf0 == 0x40;
+        match has_rex {
+            true => R_X86_64_REX_GOTPCRELX,
+            false => R_X86_64_GOTPCRELX,
+        }
+    }
+}
+
+/// Find the relocation modifier in the displacement. e.g. `GOTPCREL` of `foo@GOTPCREL`
+pub(crate) fn relocation_modifier(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Modified { modifier, .. } => Some(modifier),
+        Expr::Neg(expr) => relocation_modifier(expr),
+        Expr::Binop {
+            left_hs, right_hs, ..
+        } => relocation_modifier(left_hs).or_else(|| relocation_modifier(right_hs)),
+        _ => None,
+    }
+}
+
+/// |7 6  |5 4 3|2 1 0|
+/// |-----|-----|-----|
+/// |scale|index|base |
+fn sib(scale: u8, index: u8, base: u8) -> u8 {
+    let ss = match scale {
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        _ => 0,
+    };
+    (ss << 6) | (index << 3) | base
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::{code, parse};
+    use crate::error::Result;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_encode_sib_for_rsp_and_r12_base() -> Result<()> {
+        assert_eq!(vec![0x48, 0x8b, 0x04, 0x24], code("movq (%rsp), %rax")?);
+        assert_eq!(vec![0x49, 0x8b, 0x04, 0x24], code("movq (%r12), %rax")?);
+        assert_eq!(
+            vec![0x48, 0x8b, 0x44, 0x24, 0x08],
+            code("movq 8(%rsp), %rax")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_disp_for_rbp_and_r13_base() -> Result<()> {
+        assert_eq!(vec![0x48, 0x8b, 0x45, 0x00], code("movq (%rbp), %rax")?);
+        assert_eq!(vec![0x49, 0x8b, 0x45, 0x00], code("movq (%r13), %rax")?);
+        assert_eq!(
+            vec![0x48, 0x89, 0x85, 0x00, 0x01, 0x00, 0x00],
+            code("movq %rax, 256(%rbp)")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_index_and_scale() -> Result<()> {
+        assert_eq!(
+            vec![0x4a, 0x8b, 0x44, 0xab, 0xf8],
+            code("movq -8(%rbx, %r13, 4), %rax")?
+        );
+        assert_eq!(
+            vec![0x8b, 0x04, 0x25, 0x10, 0x00, 0x00, 0x00],
+            code("movl 16, %eax")?
+        );
+        assert!(code("movq (%rax, %rsp), %rax").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_absolute_disp32_with_sib() -> Result<()> {
+        // mod=00 rm=100, SIB index=100(none) base=101(none), disp32
+        assert_eq!(
+            vec![0x48, 0x8b, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00],
+            code("movq 0x1000,%rax")?
+        );
+        assert_eq!(
+            vec![0x48, 0x8d, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00],
+            code("leaq 0x1000, %rax")?
+        );
+        // `%rip` relative is mod=00 rm=101 without SIB.
+        assert_eq!(
+            vec![0x48, 0x8b, 0x05, 0x00, 0x10, 0x00, 0x00],
+            code("movq 0x1000(%rip), %rax")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_range_of_disp32() -> Result<()> {
+        assert_eq!(
+            vec![0x48, 0x8b, 0x80, 0xff, 0xff, 0xff, 0x7f],
+            code("movq 0x7fffffff(%rax), %rax")?
+        );
+        assert_eq!(
+            vec![0x48, 0x8b, 0x80, 0x00, 0x00, 0x00, 0x80],
+            code("movq -0x80000000(%rax), %rax")?
+        );
+        assert_eq!(vec![0x48, 0x8b, 0x40, 0x80], code("movq -128(%rax), %rax")?);
+
+        let err = parse(tokenize("movq 0x100000000(%rax), %rax")?).unwrap_err();
+        assert_eq!("displacement out of range. Got 4294967296", err.message);
+        assert!(code("movq -0x80000001(%rax), %rax").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_prefix_32bit_address() -> Result<()> {
+        assert_eq!(vec![0x67, 0x8b, 0x18], code("movl (%eax),%ebx")?);
+        assert_eq!(
+            vec![0x67, 0x41, 0x8b, 0x0c, 0x88],
+            code("movl (%r8d,%ecx,4),%ecx")?
+        );
+        assert_eq!(
+            vec![0x67, 0x8b, 0x05, 0, 0, 0, 0],
+            code("movl 0(%eip),%eax")?
+        );
+
+        let err = parse(tokenize("movl (%eax,%rbx),%ecx")?).unwrap_err();
+        assert_eq!(
+            "base `%eax` and index `%rbx` must be the same size",
+            err.message
+        );
+        assert!(code("movl (%rax,%ebx),%ecx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_memory_ordering() -> Result<()> {
+        assert_eq!(vec![0x0f, 0xae, 0xf0], code("mfence")?);
+        assert_eq!(vec![0x0f, 0xae, 0x38], code("clflush (%rax)")?);
+        assert_eq!(vec![0x0f, 0x18, 0x0f], code("prefetcht0 (%rdi)")?);
+        assert_eq!(vec![0x41, 0x0f, 0x18, 0x00], code("prefetchnta (%r8)")?);
+        assert!(code("clflush %rax").is_err());
+        Ok(())
+    }
+}
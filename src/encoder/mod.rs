@@ -1,19 +1,46 @@
-// mod addr;
-// mod stack_op;
 pub mod arch;
 
+use crate::elf::constants::{
+    R_X86_64_16, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_8, R_X86_64_DTPOFF32,
+    R_X86_64_GOTPCREL, R_X86_64_GOTPCRELX, R_X86_64_GOTTPOFF, R_X86_64_NONE, R_X86_64_PC16,
+    R_X86_64_PC32, R_X86_64_PC64, R_X86_64_PC8, R_X86_64_PLT32, R_X86_64_REX_GOTPCRELX,
+    R_X86_64_TPOFF32, STB_LOCAL, STT_OBJECT,
+};
+use crate::elf::is_nobits_section;
+use crate::encoder::addr::StringPool;
 use crate::encoder::arch::x86_64::{
-    bin_const::OPERAND_SIZE_PREFIX16,
+    bin_const::{MOD_REGI, OPERAND_SIZE_PREFIX16},
     instructions::InstrKind,
-    registers::{get_reg_info_by, get_xmm_by, DataSizeSuffix, Register},
+    registers::{
+        get_opmask_by, get_reg_info_by, get_segment_reg_by, get_xmm_by, get_ymm_by, get_zmm_by,
+        DataSizeSuffix, Register,
+    },
     Expr,
 };
-use crate::error::{self, bail, Result};
-use crate::lexer::{Location, Token, TokenKind};
-use std::collections::HashMap;
+use crate::encoder::dwarf::LineRow;
+use crate::encoder::macros::Macro;
+use crate::encoder::mem::{relocation_modifier, Memory};
+use crate::error::{self, ErrorKind, Result, Warning};
+use crate::lexer::{tokenize_from, Location, Token, TokenKind};
+use crate::statistics::PhaseTimer;
+use crate::utils::HashMap;
+use crate::{AssembleOptions, CpuFeature};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
 
 macro_rules! bail {
-
+    ($loc:expr, kind = $kind:expr, $($tt:tt)*) => {{
+        let err = $crate::error::format_err!($($tt)*)
+            .with_location($loc)
+            .with_kind($kind);
+        return Err(err);
+    }};
     ($loc:expr, $($tt:tt)*) => {{
         let err = $crate::error::format_err!($($tt)*)
             .with_location($loc);
@@ -21,23 +48,72 @@ macro_rules! bail {
     }};
 }
 
+mod addr;
+mod alu;
+mod dwarf;
+mod jump;
+mod macros;
+mod mem;
+mod port;
+mod sse;
+mod stack_op;
+mod vex;
+
 /// Instruction information
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Instr {
     pub(crate) kind: InstrKind,
     pub(crate) code: Vec<u8>,
+    /// Zero bytes reserved in a nobits section instead of `code`, since they are not in the file. e.g. `.skip 4` in `.bss`
+    pub(crate) reserved: usize,
     pub(crate) symbol_name: String,
+    /// Size of the symbol in `.symtab`. e.g. the bytes reserved by `.lcomm`
+    pub(crate) size: usize,
     pub(crate) flags: String,
+    /// Entity size of a section with the `M` flag. e.g. 1 of `.section .rodata.str1.1,"aMS",@progbits,1`
+    pub(crate) entsize: usize,
+    /// Section group of a section with the `G` flag. e.g. `foo, comdat` of `.section .text.foo,"axG",@progbits,foo,comdat`
+    pub(crate) group: Option<SectionGroup>,
+    /// Symbol whose section a section with the `o` flag is linked to. e.g. `.text` of `.section __patchable,"ao",@progbits,.text`
+    pub(crate) linked_to: Option<String>,
     pub(crate) addr: usize,
     pub(crate) binding: u8,
     /// STV_DEFAULT, STV_INTERNAL, STV_HIDDEN, STV_PROTECTED
     pub(crate) visibility: u8,
     pub(crate) symbol_type: u8,
+    /// Section which was current at this instruction.
+    /// Directives like `.align` are also laid out in this section, not in the one which is current at the end.
     pub(crate) section_name: String,
     pub(crate) is_jmp_or_call: bool,
+    /// Alignment in bytes of `.align` like directives. The padding is decided by address assignment.
+    pub(crate) align: usize,
+    /// Fill byte of the alignment padding. `None` means NOPs in executable sections, zeros otherwise.
+    pub(crate) align_fill: Option<u8>,
+    /// Padding is not inserted if it would need more bytes than this.
+    pub(crate) align_max_skip: Option<usize>,
     pub(crate) loc: Location,
 }
 
+impl Instr {
+    /// Byte length of this instruction.
+    ///
+    /// Unresolved jumps may be relaxed to the `rel32` form, so the worst-case length is returned for them.
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self.is_jmp_or_call {
+            true => {
+                let rel32_len = match self.kind {
+                    // e8/e9 rel32
+                    InstrKind::Call | InstrKind::Jmp => 5,
+                    // 0f 8x rel32
+                    _ => 6,
+                };
+                self.code.len().max(rel32_len)
+            }
+            false => self.code.len() + self.reserved,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Rela {
     pub uses: String,
@@ -45,14 +121,80 @@ pub struct Rela {
     pub offset: usize,
     pub rtype: u64,
     pub adjust: i32,
+    /// Addend given by `.reloc`. If `None`, it is derived from the relocation type.
+    pub addend: Option<i64>,
+    /// Index of `instr` in `Encoder::instrs`. The address of `instr` is synchronized with it after layout.
+    pub instr_idx: Option<usize>,
     pub is_already_resolved: bool,
 }
 
+impl Rela {
+    /// Addend of the relocation, relative to the symbol itself(not to its section).
+    ///
+    /// If `.reloc` does not give it, PC relative relocations subtract the distance from
+    /// the relocated field to the end of the instruction. e.g. `-4` for `lea msg(%rip), %rsi`
+    pub(crate) fn addend(&self) -> i64 {
+        let addend = match self.addend {
+            Some(addend) => addend,
+            None => match self.rtype {
+                R_X86_64_PC32
+                | R_X86_64_PC8
+                | R_X86_64_GOTPCREL
+                | R_X86_64_GOTPCRELX
+                | R_X86_64_REX_GOTPCRELX
+                | R_X86_64_GOTTPOFF => self.offset as i64 - self.instr.code.len() as i64,
+                R_X86_64_64 | R_X86_64_32 | R_X86_64_32S | R_X86_64_16 | R_X86_64_8
+                | R_X86_64_TPOFF32 | R_X86_64_DTPOFF32 => 0,
+                _ => -4,
+            },
+        };
+        addend + self.adjust as i64
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct UserDefinedSection {
     pub code: Vec<u8>,
     pub addr: usize,
     pub flags: u64,
+    pub entsize: usize,
+    /// The strongest alignment requested by `.align` like directives in this section
+    pub max_align: usize,
+    pub group: Option<SectionGroup>,
+    pub linked_to: Option<String>,
+}
+
+/// The sections in a group are kept or discarded together by the linker.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SectionGroup {
+    /// Name of the symbol which identifies the group.
+    pub signature: String,
+    /// Only one of the groups with the same signature is kept among the linked objects.
+    pub is_comdat: bool,
+}
+
+/// Prefixes given by the prefix mnemonics(e.g. `data16`, `rex.w`) for the current instruction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct ExplicitPrefix {
+    data16: bool,
+    rex: Option<u8>,
+    /// The instruction needs a REX prefix which differs from `rex`.
+    rex_conflict: bool,
+    /// The instruction needs a REX prefix, but it is not in 64bit mode.
+    rex_outside_64bit: bool,
+    /// The high byte register used with a REX prefix. e.g. `%ah` of `movb %ah, %r8b`
+    high_byte_with_rex: Option<Register>,
+}
+
+/// CPU mode selected by `.code16`, `.code32` and `.code64`.
+///
+/// It decides the default operand and address sizes, which are toggled by `66` and `67` prefixes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum CodeMode {
+    Code16,
+    Code32,
+    #[default]
+    Code64,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -62,9 +204,64 @@ pub struct Encoder {
     current_section_name: String,
     current_instr: Instr,
     /// All instructions, sections, symbols, directives
-    instrs: Vec<Instr>,
-    user_defined_symbols: HashMap<String, Instr>,
-    user_defined_sections: HashMap<String, UserDefinedSection>,
+    pub(crate) instrs: Vec<Instr>,
+    pub(crate) user_defined_symbols: HashMap<String, Instr>,
+    /// Symbols declared by `.globl`/`.weak` without definition. name -> binding
+    ///
+    /// They are referred from other objects, so they are in `.symtab` even if not used.
+    pub(crate) undefined_bindings: HashMap<String, u8>,
+    pub(crate) user_defined_sections: HashMap<String, UserDefinedSection>,
+    pub(crate) rela_text_users: Vec<Rela>,
+    /// Files of `.file 1 "a.c"` for the line information. number -> name
+    pub(crate) debug_files: HashMap<u64, String>,
+    /// Rows of the line information by `.loc`
+    pub(crate) line_rows: Vec<LineRow>,
+    /// Strings laid out in the mergeable string sections. section name -> pool
+    pub(crate) string_pools: HashMap<String, StringPool>,
+    /// 64-bit immediates of `mov` loaded from `.rodata` by [`AssembleOptions::imm64_in_rodata`]
+    ///
+    /// The index is the number of its label. e.g. `.Limm64.0`
+    pub(crate) imm64_constants: Vec<i64>,
+    /// The number of the labels defined for the location counter `.`. e.g. `.Ldot.0`
+    dot_labels: usize,
+    /// The label of `.` at the current statement, once it is referred.
+    current_dot_label: Option<String>,
+    /// The mode of `.code16`/`.code32`/`.code64`
+    pub(crate) code_mode: CodeMode,
+    /// `.set alias, target` to a label. (alias, target) in order of definition
+    ///
+    /// The alias is in `user_defined_symbols`, and gets the place of the target after layout.
+    pub(crate) symbol_aliases: Vec<(String, String)>,
+    /// `.equ`/`.set`/`.equiv` constants. name -> (value, location of the definition)
+    user_defined_constants: HashMap<String, (i64, Location)>,
+    /// Non-fatal diagnostics reported after the assembly.
+    pub(crate) warnings: Vec<Warning>,
+    /// Locations of the conditional directives(e.g. `.ifdef`) whose block is being assembled.
+    conditional_stack: Vec<Location>,
+    /// `.macro` definitions. name -> macro
+    macros: HashMap<String, Macro>,
+    /// Source of the macro expansions being encoded, innermost last. (offset of the macro body, expanded text)
+    expansion_texts: Vec<(usize, String)>,
+    /// Only expand the macros and conditionals, and record the statements without encoding them.
+    /// e.g. `--preprocess-only`
+    pub(crate) preprocess_only: bool,
+    /// Lines of the statements which are assembled, not skipped by the conditionals. Only by [`Self::preprocess_only`]
+    pub(crate) preprocessed: String,
+    /// Offset of the start of the line recorded last in [`Self::preprocessed`]. e.g. `foo: nop` is recorded once.
+    recorded_line: Option<usize>,
+    pub(crate) options: AssembleOptions,
+    explicit_prefix: ExplicitPrefix,
+    /// Mnemonic of the current instruction. e.g. `movq`
+    mnemonic: String,
+    /// Number of the operands of the current instruction, counted before they are parsed.
+    operand_count: usize,
+    /// The CPU feature the current instruction needs. It is checked against the disabled features.
+    required_feature: Option<CpuFeature>,
+    /// All source given by [`Self::feed`]. The token locations point into it.
+    pub(crate) source: String,
+    /// Number of tokens of all source
+    pub(crate) token_count: usize,
+    pub(crate) timer: PhaseTimer,
 }
 
 impl Default for Encoder {
@@ -76,22 +273,157 @@ impl Default for Encoder {
             current_section_name: ".text".to_owned(),
             instrs: Vec::with_capacity(1500000),
             user_defined_symbols: Default::default(),
+            undefined_bindings: Default::default(),
             user_defined_sections: Default::default(),
+            rela_text_users: Default::default(),
+            debug_files: Default::default(),
+            line_rows: Default::default(),
+            string_pools: Default::default(),
+            imm64_constants: Default::default(),
+            dot_labels: 0,
+            current_dot_label: None,
+            symbol_aliases: Default::default(),
+            code_mode: Default::default(),
+            user_defined_constants: Default::default(),
+            warnings: Default::default(),
+            conditional_stack: Default::default(),
+            macros: Default::default(),
+            expansion_texts: Default::default(),
+            preprocess_only: false,
+            preprocessed: Default::default(),
+            recorded_line: None,
+            options: Default::default(),
+            explicit_prefix: Default::default(),
+            mnemonic: Default::default(),
+            operand_count: Default::default(),
+            required_feature: None,
+            source: Default::default(),
+            token_count: Default::default(),
+            timer: PhaseTimer::new(),
+        }
+    }
+}
+
+impl Encoder {
+    /// Make an empty encoder to [`Self::feed`] the source incrementally.
+    pub fn with_options(options: AssembleOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Tokenize and encode more source into this encoder. e.g. for REPLs
+    ///
+    /// The symbols and constants of the previous calls are visible, and the locations of the diagnostics
+    /// continue from the previous source. Call [`Self::finish`] after all source is fed.
+    pub fn feed(&mut self, src: &str) -> Result<()> {
+        self.timer.restart();
+        if !self.source.is_empty() && !self.source.ends_with('\n') {
+            self.source.push('\n');
+        }
+        let tokens = tokenize_from(src, Location::end_of(&self.source))?;
+        self.source.push_str(src);
+        self.token_count += tokens.len();
+        self.timer.lap("tokenize");
+
+        self.tokens = tokens;
+        self.token_idx = 0;
+        while self.encode_next()? {}
+        self.timer.lap("parse and encode");
+        Ok(())
+    }
+
+    /// Error if a conditional block(e.g. `.ifdef`) is still open at the end of the source.
+    pub(crate) fn check_conditionals_closed(&self) -> Result<()> {
+        if let Some(&if_loc) = self.conditional_stack.last() {
+            bail!(if_loc, "unterminated conditional. expected `.endif`");
+        }
+        Ok(())
+    }
+
+    /// Encode the next statement of the tokens.
+    ///
+    /// Returns `false` if all tokens have already been encoded.
+    pub(crate) fn encode_next(&mut self) -> Result<bool> {
+        if self.token_idx >= self.tokens.len() {
+            return Ok(false);
+        }
+
+        let tokens = core::mem::take(&mut self.tokens);
+        let mut index = self.token_idx;
+        let result = self.encode_statement(&mut index, &tokens);
+        self.tokens = tokens;
+        self.token_idx = index;
+        result.map(|_| true)
+    }
+
+    /// Encode the statement at `index`. It is only recorded by [`Self::preprocess_only`].
+    fn encode_statement(&mut self, index: &mut usize, tokens: &[Token]) -> Result<()> {
+        if !self.preprocess_only {
+            return self.encode_instr(index, tokens);
+        }
+
+        let Token { kind, loc } = &tokens[*index];
+        let name = match kind {
+            TokenKind::Ident(name) => name.as_str(),
+            _ => "",
+        };
+        // The skipped conditional blocks and the macro definitions never come here.
+        let is_expanded = matches!(
+            name,
+            ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif" | ".macro" | ".endm"
+        ) || self.macros.contains_key(name);
+        let is_label = matches!(
+            tokens.get(*index + 1),
+            Some(Token {
+                kind: TokenKind::Colon,
+                ..
+            })
+        );
+        let (start, line) = self.line_of(*loc);
+        if !is_expanded && self.recorded_line != Some(start) {
+            let line = line.to_owned();
+            self.preprocessed.push_str(&line);
+            self.preprocessed.push('\n');
+            self.recorded_line = Some(start);
+        }
+
+        // The conditions may refer to the labels and constants, but the other statements are not encoded.
+        if is_expanded || is_label || matches!(name, ".set" | ".equ" | ".equiv") {
+            self.encode_instr(index, tokens)
+        } else {
+            skip_statement(index, tokens, loc.line);
+            Ok(())
         }
     }
+
+    /// Code of the last encoded instruction.
+    #[cfg(test)]
+    pub(crate) fn last_code(&self) -> &[u8] {
+        self.instrs.last().map_or(&[], |instr| &instr.code)
+    }
 }
 
-/// Next token is matched by 1st arg?
+/// Current token is matched by 1st arg?
+/// If matched, index is incremented(consume the token).
 ///
 /// # Parameters:
 /// - `token_kind`: expected tokenKind
 /// - `index`: current index for tokens. This index is incremented.
 /// - `tokens`: list of tokens
 fn expect(token_kind: TokenKind, index: &mut usize, tokens: &[Token]) -> Result<()> {
-    let Token { kind, loc } = peek_next(index, tokens)?;
+    let Token { kind, loc } = peek_n(*index, tokens)?;
     match token_kind == *kind {
-        true => Ok(()),
-        false => bail!(*loc, "Unexpected token {kind:?}. expected {token_kind:?}",),
+        true => {
+            *index += 1;
+            Ok(())
+        }
+        false => bail!(
+            *loc,
+            kind = ErrorKind::Parse,
+            "Unexpected token {kind:?}. expected {token_kind:?}",
+        ),
     }
 }
 
@@ -99,7 +431,8 @@ fn peek_n(n: usize, tokens: &[Token]) -> Result<&Token> {
     match tokens.get(n) {
         Some(token) => Ok(token),
         None => bail!(
-            tokens[n].loc,
+            tokens.last().map(|token| token.loc).unwrap_or_default(),
+            kind = ErrorKind::Parse,
             "The '{n}'th Token in the Token vector was not found."
         ),
     }
@@ -110,64 +443,299 @@ fn peek_next<'a>(index: &mut usize, tokens: &'a [Token]) -> Result<&'a Token> {
     peek_n(*index, tokens)
 }
 
-/// First, index+=1.
-/// 2nd, Parse register from global data. return XMM or general register.
+/// Is the statement that started on `line` finished?
+///
+/// The lexer has no newline token, so a token on another line starts a new statement.
+fn is_end_of_statement(index: usize, tokens: &[Token], line: usize) -> bool {
+    match tokens.get(index) {
+        Some(token) => token.loc.line != line,
+        None => true,
+    }
+}
+
+/// Number of the operands of the statement on `line` from `index`.
+///
+/// The commas in parentheses do not separate operands. e.g. `8(%rax, %rcx, 4)`
+fn count_operands(index: usize, tokens: &[Token], line: usize) -> usize {
+    let (mut count, mut depth) = (0, 0usize);
+    for token in tokens[index..].iter().take_while(|t| t.loc.line == line) {
+        count = count.max(1);
+        match token.kind {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => depth = depth.saturating_sub(1),
+            TokenKind::Comma if depth == 0 => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Get the REX prefix by the prefix mnemonic. e.g. `rex` => 0x40, `rex.w` => 0x48, `rex.wb` => 0x49
+fn explicit_rex_by(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    let bits = match name.strip_prefix("rex")? {
+        "" => return Some(rex(0, 0, 0, 0)),
+        bits => bits.strip_prefix('.')?,
+    };
+
+    // W, R, X and B in this order, without duplicates.
+    let mut rest = "wrxb";
+    let mut rex = rex(0, 0, 0, 0);
+    for bit in bits.chars() {
+        rest = &rest[rest.find(bit)? + 1..];
+        rex |= match bit {
+            'w' => 0b1000,
+            'r' => 0b0100,
+            'x' => 0b0010,
+            _ => 0b0001,
+        };
+    }
+    (!bits.is_empty()).then_some(rex)
+}
+
+/// Skip the remaining tokens of the statement that started on `line`.
+fn skip_statement(index: &mut usize, tokens: &[Token], line: usize) {
+    while !is_end_of_statement(*index, tokens, line) {
+        *index += 1;
+    }
+}
+
+/// Skip the tokens of the conditional block which is not assembled.
+///
+/// Returns `true` if stopped at `.else`(the following block is assembled), `false` if stopped at `.endif`.
+///
+/// - `if_loc`: location of the opening directive for error reporting.
+fn skip_conditional_block(index: &mut usize, tokens: &[Token], if_loc: Location) -> Result<bool> {
+    let mut depth = 0;
+
+    while let Some(Token { kind, loc }) = tokens.get(*index) {
+        if let TokenKind::Ident(name) = kind {
+            match name.as_str() {
+                ".if" | ".ifdef" | ".ifndef" => depth += 1,
+                ".else" if depth == 0 => {
+                    *index += 1;
+                    return Ok(true);
+                }
+                ".endif" if depth == 0 => {
+                    *index += 1;
+                    return Ok(false);
+                }
+                ".endif" => depth -= 1,
+                _ => {}
+            }
+        }
+        skip_statement(index, tokens, loc.line);
+    }
+    bail!(if_loc, "unterminated conditional. expected `.endif`")
+}
+
+/// The largest alignment of `.align`/`.p2align`, as GNU as limits it. Larger ones cannot be padded.
+const MAX_ALIGN: u64 = 1 << 31;
+
+/// The most bytes one directive can emit into a section in the file. e.g. `.skip` in `.data`
+const MAX_DATA_LEN: i64 = 1 << 31;
+
+/// Directives which are known, but have no effect on the object. e.g. debug information by GCC
+const IGNORED_DIRECTIVES: [&str; 6] = [
+    ".type",
+    ".size",
+    ".ident",
+    ".att_syntax",
+    ".addrsig",
+    ".addrsig_sym",
+];
+
+/// Is `directive` silently ignored? e.g. `.type main, @function`, `.cfi_startproc`
+fn is_ignored_directive(directive: &str) -> bool {
+    IGNORED_DIRECTIVES.contains(&directive) || directive.starts_with(".cfi_")
+}
+
+/// Relocation type by its name. e.g. `R_X86_64_64`
+fn reloc_type_by_name(name: &str) -> Option<u64> {
+    Some(match name {
+        "R_X86_64_NONE" => R_X86_64_NONE,
+        "R_X86_64_64" => R_X86_64_64,
+        "R_X86_64_PC32" => R_X86_64_PC32,
+        "R_X86_64_PLT32" => R_X86_64_PLT32,
+        "R_X86_64_GOTPCREL" => R_X86_64_GOTPCREL,
+        "R_X86_64_32" => R_X86_64_32,
+        "R_X86_64_32S" => R_X86_64_32S,
+        "R_X86_64_16" => R_X86_64_16,
+        "R_X86_64_PC16" => R_X86_64_PC16,
+        "R_X86_64_8" => R_X86_64_8,
+        "R_X86_64_PC8" => R_X86_64_PC8,
+        "R_X86_64_PC64" => R_X86_64_PC64,
+        "R_X86_64_DTPOFF32" => R_X86_64_DTPOFF32,
+        "R_X86_64_GOTTPOFF" => R_X86_64_GOTTPOFF,
+        "R_X86_64_TPOFF32" => R_X86_64_TPOFF32,
+        _ => return None,
+    })
+}
+
+/// Parse register from global data. return XMM or general register.
+///
+/// `index` must point to `%`. After parsing, index points to the next token of the register name.
 fn parse_register(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
     // Collect valid location information before the index changes with the peek_next function for error reporting.
     let current_loc = peek_n(*index, tokens)?.loc;
 
-    Ok(match &peek_next(index, tokens)?.kind {
-        TokenKind::Ident(reg_name) => match get_xmm_by(&reg_name.to_uppercase()) {
-            Ok(xmm) => Expr::Xmm(xmm),
-            Err(_err) => Expr::Register(get_reg_info_by(&reg_name.to_uppercase())?),
-        },
+    let Token { kind, loc } = peek_next(index, tokens)?;
+    let expr = match kind {
+        TokenKind::Ident(reg_name) => {
+            // Registers are case-insensitive, but keep the original spelling for error reporting.
+            let key = reg_name.to_uppercase();
+            if let Ok(xmm) = get_xmm_by(&key) {
+                Expr::Xmm(xmm)
+            } else if let Ok(reg) = get_reg_info_by(&key) {
+                Expr::Register(reg)
+            } else if let Ok(sreg) = get_segment_reg_by(&key) {
+                Expr::Segment(sreg)
+            } else if let Ok(ymm) = get_ymm_by(&key) {
+                Expr::Ymm(ymm)
+            } else if let Ok(zmm) = get_zmm_by(&key) {
+                Expr::Zmm(zmm)
+            } else if let Ok(k) = get_opmask_by(&key) {
+                Expr::Opmask(k)
+            } else {
+                bail!(
+                    *loc,
+                    kind = ErrorKind::Parse,
+                    "unknown register `%{reg_name}`"
+                )
+            }
+        }
         _ => bail!(
             current_loc,
+            kind = ErrorKind::Parse,
             "The next character after `%` must be register."
         ),
-    })
+    };
+    *index += 1;
+    Ok(expr)
 }
 
-/// Parse Number | Identifier | Unary minus
+/// Parse Number | Identifier | Unary minus | `(` expression `)`
 fn parse_factor(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
     let current_token = peek_n(*index, tokens)?;
+    *index += 1;
     Ok(match &current_token.kind {
         TokenKind::Number(num) => Expr::Number(num.to_string()),
-        TokenKind::Ident(ident) => Expr::Ident(ident.to_string()),
-        TokenKind::Minus => {
-            *index += 1;
-            Expr::Neg(Box::new(parse_factor(index, tokens)?))
+        TokenKind::Token(string) => Expr::Str(string.to_string()),
+        TokenKind::Ident(ident) => match tokens.get(*index).map(|token| &token.kind) {
+            // e.g. `foo@GOTPCREL`
+            Some(TokenKind::At) => {
+                *index += 1;
+                let Token { kind, loc } = peek_n(*index, tokens)?;
+                let TokenKind::Ident(modifier) = kind else {
+                    bail!(
+                        *loc,
+                        kind = ErrorKind::Parse,
+                        "expected relocation modifier after `@`. Got {kind:?}"
+                    );
+                };
+                *index += 1;
+                Expr::Modified {
+                    symbol: ident.to_string(),
+                    modifier: modifier.to_string(),
+                }
+            }
+            _ => Expr::Ident(ident.to_string()),
+        },
+        TokenKind::Minus => Expr::Neg(Box::new(parse_factor(index, tokens)?)),
+        // e.g. `$(1 + 2) * 3`
+        TokenKind::LParen => {
+            let expr = parse_expr(index, tokens)?;
+            expect(TokenKind::RParen, index, tokens)?;
+            expr
         }
         _ => bail!(
             current_token.loc,
+            kind = ErrorKind::Parse,
             "Unexpected token kind: {:?}. Expected: Number|Identifier|Unary minus",
             current_token.kind
         ),
     })
 }
 
+/// Parse `factor (('*' | '/') factor)*`
+fn parse_term(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
+    let mut expr = parse_factor(index, tokens)?;
+
+    while let Some(Token { kind, .. }) = tokens.get(*index) {
+        if !matches!(kind, TokenKind::Mul | TokenKind::Div) {
+            break;
+        }
+        *index += 1;
+        expr = Expr::Binop {
+            left_hs: Box::new(expr),
+            right_hs: Box::new(parse_factor(index, tokens)?),
+            op: kind.clone(),
+        };
+    }
+    Ok(expr)
+}
+
 /// Parse binary expression
+///
+/// `term (('+' | '-') term)*`
 fn parse_expr(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
-    let left_hs = Box::new(parse_factor(index, tokens)?);
+    let mut expr = parse_term(index, tokens)?;
 
-    let current_token = peek_n(*index, tokens)?;
-    Ok(match &current_token.kind {
-        TokenKind::Div | TokenKind::Minus | TokenKind::Mul | TokenKind::Plus => {
-            let op = current_token.kind.clone();
-            *index += 1;
-            let right_hs = Box::new(parse_expr(index, tokens)?);
-            Expr::Binop {
-                left_hs,
-                right_hs,
-                op,
-            }
+    while let Some(Token { kind, .. }) = tokens.get(*index) {
+        if !matches!(kind, TokenKind::Plus | TokenKind::Minus) {
+            break;
         }
-        _ => bail!(
-            current_token.loc,
-            "Unexpected token kind: {:?}. Expected: Binary expression",
-            current_token.kind
-        ),
-    })
+        *index += 1;
+        expr = Expr::Binop {
+            left_hs: Box::new(expr),
+            right_hs: Box::new(parse_term(index, tokens)?),
+            op: kind.clone(),
+        };
+    }
+    Ok(expr)
+}
+
+/// Parse comma separated expressions until the end of the statement.
+/// e.g. `.byte 1, 2, 3`
+///
+/// A trailing comma is allowed(`.byte 1, 2,`), but an empty operand(`.byte 1,,2`) is an error.
+fn parse_expr_list(index: &mut usize, tokens: &[Token], line: usize) -> Result<Vec<Expr>> {
+    let mut exprs = Vec::new();
+
+    while !is_end_of_statement(*index, tokens, line) {
+        let Token { kind, loc } = peek_n(*index, tokens)?;
+        if *kind == TokenKind::Comma {
+            bail!(*loc, kind = ErrorKind::Parse, "missing operand before `,`");
+        }
+
+        exprs.push(parse_expr(index, tokens)?);
+        if is_end_of_statement(*index, tokens, line) {
+            break;
+        }
+        expect(TokenKind::Comma, index, tokens)?;
+    }
+    Ok(exprs)
+}
+
+/// Parse comma separated operands which may be omitted. e.g. `4,,3`
+fn parse_optional_expr_list(
+    index: &mut usize,
+    tokens: &[Token],
+    line: usize,
+) -> Result<Vec<Option<Expr>>> {
+    let mut exprs = Vec::new();
+
+    while !is_end_of_statement(*index, tokens, line) {
+        match peek_n(*index, tokens)?.kind == TokenKind::Comma {
+            true => exprs.push(None),
+            false => exprs.push(Some(parse_expr(index, tokens)?)),
+        }
+        if is_end_of_statement(*index, tokens, line) {
+            break;
+        }
+        expect(TokenKind::Comma, index, tokens)?;
+    }
+    Ok(exprs)
 }
 
 /// Parse e.g. (movq `rsi, rdi` )
@@ -178,109 +746,218 @@ fn parse_two_operand(index: &mut usize, tokens: &[Token]) -> Result<(Expr, Expr)
     Ok((src, dst))
 }
 
+/// Parse a memory operand.
+///
+/// - indirect expression: `displacement(base, index, scale)`
+///
+/// e.g. `8(%rbx, %rdi, 8)`
 fn parse_indirect(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
-    let kind = &peek_n(*index, tokens)?.kind;
-
-    // - indirect expression
-    //   displacement(base + index, scale)
-    // e.g.         8(rbx + rdi, 8)
-    let expr = match *kind == TokenKind::LParen {
-        // Starting with '(' means displacement is omitted.
-        true => Expr::Number("0".to_owned()),
-        false => parse_expr(index, tokens)?,
+    // Starting with '(' means displacement is omitted.
+    let disp = match peek_n(*index, tokens)?.kind == TokenKind::LParen {
+        true => None,
+        false => Some(Box::new(parse_expr(index, tokens)?)),
     };
-    if *kind != TokenKind::LParen {
-        return Ok(expr);
+
+    let mut indirect = Expr::Indirection {
+        disp,
+        base: None,
+        index: None,
+        scale: None,
+        has_base: false,
+        has_index_scale: false,
+        segment: None,
     };
+    if tokens.get(*index).map(|token| &token.kind) != Some(&TokenKind::LParen) {
+        return Ok(indirect);
+    }
+    *index += 1;
 
-    Ok(match peek_next(index, tokens)?.kind == TokenKind::Comma {
-        true => {
-            let indirect = Expr::Indirection {
-                disp: Some(Box::new(expr)),
-                base: Some(Box::new(parse_register(index, tokens)?)),
-                index: Some(Box::new(parse_register(index, tokens)?)),
-                scale: Some(Box::new(
-                    match peek_next(index, tokens)?.kind == TokenKind::Comma {
-                        true => parse_expr(index, tokens)?,
-                        false => Expr::Number("1".to_owned()),
-                    },
-                )),
-                has_base: false,
-                has_index_scale: false,
-            };
-            expect(TokenKind::RParen, index, tokens)?;
-            indirect
+    if let Expr::Indirection {
+        base,
+        index: index_reg,
+        scale,
+        has_base,
+        has_index_scale,
+        ..
+    } = &mut indirect
+    {
+        if peek_n(*index, tokens)?.kind == TokenKind::Percent {
+            *base = Some(Box::new(parse_register(index, tokens)?));
+            *has_base = true;
         }
-        false => Expr::Indirection {
-            disp: Some(Box::new(expr)),
-            base: None,
-            index: None,
-            scale: None,
-            has_base: false,
-            has_index_scale: false,
-        },
-    })
+        if peek_n(*index, tokens)?.kind == TokenKind::Comma {
+            *index += 1;
+            *index_reg = Some(Box::new(parse_register(index, tokens)?));
+            *scale = Some(Box::new(
+                match peek_n(*index, tokens)?.kind == TokenKind::Comma {
+                    true => {
+                        *index += 1;
+                        parse_expr(index, tokens)?
+                    }
+                    false => Expr::Number("1".to_owned()),
+                },
+            ));
+            *has_index_scale = true;
+        }
+    }
+    expect(TokenKind::RParen, index, tokens)?;
+    Ok(indirect)
 }
 
 fn parse_operand(index: &mut usize, tokens: &[Token]) -> Result<Expr> {
     let Token { kind, loc } = peek_n(*index, tokens)?;
 
-    Ok(match &kind {
+    let operand = match &kind {
         // Dolor is immediate prefix. e.g. $1
         TokenKind::Dolor => {
             *index += 1;
             Expr::Immediate(Box::new(parse_expr(index, tokens)?))
         }
-        TokenKind::Percent => parse_register(index, tokens)?,
+        TokenKind::Percent => match parse_register(index, tokens)? {
+            // Segment override. e.g. `%fs:0x28`
+            Expr::Segment(sreg)
+                if tokens.get(*index).map(|t| &t.kind) == Some(&TokenKind::Colon) =>
+            {
+                *index += 1;
+                match parse_indirect(index, tokens)? {
+                    Expr::Indirection {
+                        disp,
+                        base,
+                        index,
+                        scale,
+                        has_base,
+                        has_index_scale,
+                        ..
+                    } => Expr::Indirection {
+                        disp,
+                        base,
+                        index,
+                        scale,
+                        has_base,
+                        has_index_scale,
+                        segment: Some(sreg),
+                    },
+                    expr => expr,
+                }
+            }
+            expr => expr,
+        },
         TokenKind::Mul => Expr::Star(Box::new(parse_register(index, tokens)?)),
-        TokenKind::LParen => parse_indirect(index, tokens)?,
+        TokenKind::LParen | TokenKind::Ident(_) | TokenKind::Number(_) | TokenKind::Minus => {
+            parse_indirect(index, tokens)?
+        }
         _ => bail!(
             *loc,
+            kind = ErrorKind::Parse,
             "Unexpected token kind: {kind:?}. Expected: Immediate|Register|Multiply|Indirect"
         ),
+    };
+    parse_mask_decoration(operand, index, tokens)
+}
+
+/// Parse the opmask decoration which follows an operand. e.g. `{%k1}{z}` of `%zmm0{%k1}{z}`
+///
+/// `%` of the opmask register may be omitted. e.g. `{k1}`
+fn parse_mask_decoration(operand: Expr, index: &mut usize, tokens: &[Token]) -> Result<Expr> {
+    if tokens.get(*index).map(|t| &t.kind) != Some(&TokenKind::LBrace) {
+        return Ok(operand);
+    }
+    let loc = tokens[*index].loc;
+    *index += 1;
+    if peek_n(*index, tokens)?.kind == TokenKind::Percent {
+        *index += 1;
+    }
+    let mask = match &peek_n(*index, tokens)?.kind {
+        TokenKind::Ident(name) => match get_opmask_by(&name.to_uppercase()) {
+            Ok(mask) if mask.base_offset != 0 => mask,
+            Ok(_) => bail!(loc, "`%k0` cannot be used as a write mask"),
+            Err(_) => bail!(
+                loc,
+                kind = ErrorKind::Parse,
+                "expected an opmask register. e.g. `{{%k1}}`"
+            ),
+        },
+        _ => bail!(
+            loc,
+            kind = ErrorKind::Parse,
+            "expected an opmask register. e.g. `{{%k1}}`"
+        ),
+    };
+    *index += 1;
+    expect(TokenKind::RBrace, index, tokens)?;
+
+    let zeroing = tokens.get(*index).map(|t| &t.kind) == Some(&TokenKind::LBrace);
+    if zeroing {
+        *index += 1;
+        match &peek_n(*index, tokens)?.kind {
+            TokenKind::Ident(z) if z == "z" => *index += 1,
+            _ => bail!(
+                loc,
+                kind = ErrorKind::Parse,
+                "expected the zeroing decoration `{{z}}`"
+            ),
+        }
+        expect(TokenKind::RBrace, index, tokens)?;
+    }
+    Ok(Expr::Masked {
+        operand: Box::new(operand),
+        mask,
+        zeroing,
     })
 }
 
 fn eval_expr_get_symbol_64(expr: Expr, arr: &mut Vec<String>) -> Result<i64> {
     Ok(match expr {
-        Expr::Number(string) => match string.parse::<i64>() {
-            Ok(int) => int,
-            Err(_) => error::bail!("Failed to parse number"),
-        },
+        Expr::Number(string) => {
+            let parsed = match string.strip_prefix("0x").or(string.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16).map(|int| int as i64),
+                None => string.parse::<i64>(),
+            };
+            match parsed {
+                Ok(int) => int,
+                Err(_) => {
+                    let err = error::format_err!("Failed to parse number. Got {string}");
+                    return Err(err.with_kind(ErrorKind::Parse));
+                }
+            }
+        }
         Expr::Binop {
             left_hs,
             right_hs,
             op,
-        } => match op {
-            TokenKind::Plus => {
-                eval_expr_get_symbol_64(*left_hs, arr)? + eval_expr_get_symbol_64(*right_hs, arr)?
-            }
-            TokenKind::Minus => {
-                eval_expr_get_symbol_64(*left_hs, arr)? - eval_expr_get_symbol_64(*right_hs, arr)?
-            }
-            TokenKind::Mul => {
-                eval_expr_get_symbol_64(*left_hs, arr)? * eval_expr_get_symbol_64(*right_hs, arr)?
-            }
-            TokenKind::Div => {
-                eval_expr_get_symbol_64(*left_hs, arr)? / eval_expr_get_symbol_64(*right_hs, arr)?
+        } => {
+            let left = eval_expr_get_symbol_64(*left_hs, arr)?;
+            let right = eval_expr_get_symbol_64(*right_hs, arr)?;
+            let (value, op) = match op {
+                TokenKind::Plus => (left.checked_add(right), '+'),
+                TokenKind::Minus => (left.checked_sub(right), '-'),
+                TokenKind::Mul => (left.checked_mul(right), '*'),
+                TokenKind::Div if right == 0 => error::bail!("division by zero. `{left} / 0`"),
+                TokenKind::Div => (left.checked_div(right), '/'),
+                unknown_op => error::bail!("Unimplemented {unknown_op:?} yet!"),
+            };
+            match value {
+                Some(value) => value,
+                None => error::bail!("overflow in the expression `{left} {op} {right}`"),
             }
-            unknown_op => error::bail!("Unimplemented {unknown_op:?} yet!"),
-        },
-        Expr::Ident(ident) => {
+        }
+        Expr::Ident(ident) | Expr::Modified { symbol: ident, .. } => {
             arr.push(ident);
             0
         }
-        Expr::Neg(num_stmt) => -eval_expr_get_symbol_64(*num_stmt, arr)?,
+        Expr::Str(string) => error::bail!("string \"{string}\" is not allowed in expressions"),
+        Expr::Neg(num_stmt) => {
+            let value = eval_expr_get_symbol_64(*num_stmt, arr)?;
+            match value.checked_neg() {
+                Some(value) => value,
+                None => error::bail!("overflow in the expression `-({value})`"),
+            }
+        }
         Expr::Immediate(stmt) => eval_expr_get_symbol_64(*stmt, arr)?,
         _ => unimplemented!(),
     })
 }
 
-fn eval_expr(expr: Expr) -> Result<i32> {
-    let mut arr = Vec::new();
-    Ok(eval_expr_get_symbol_64(expr, &mut arr)? as i32)
-}
-
 /// The 4-bit regions are called REX.w, REX.r, REX.x, and REX.b, in order from bit 3 to 0.
 ///
 /// |7|6|5|4|  3  |  2  |  1  |  0  |
@@ -315,8 +992,15 @@ impl Encoder {
         let x = if reg_i.base_offset >= 8 { 1 } else { 0 };
         let b = if reg_b.base_offset >= 8 { 1 } else { 0 };
 
-        if sizes.contains(&DataSizeSuffix::Word) {
-            self.current_instr.code.push(OPERAND_SIZE_PREFIX16);
+        // The operand size other than the default one of the mode needs `66`.
+        // `data16` has already given it.
+        let operand_size16 = match self.code_mode {
+            CodeMode::Code16 => DataSizeSuffix::Long,
+            _ => DataSizeSuffix::Word,
+        };
+        if sizes.contains(&operand_size16) && !self.explicit_prefix.data16 {
+            // Precede the `lock` prefix if any, in the same order as GNU as.
+            self.current_instr.code.insert(0, OPERAND_SIZE_PREFIX16);
         };
         if sizes.contains(&DataSizeSuffix::Single) {
             self.current_instr.code.push(0xf3);
@@ -325,8 +1009,23 @@ impl Encoder {
             self.current_instr.code.push(0xf2);
         }
 
-        if w != 0 || r != 0 || b != 0 || x != 0 || reg_r.rex_required || reg_b.rex_required {
-            self.current_instr.code.push(rex(w, r, x, b));
+        let needs_rex =
+            w != 0 || r != 0 || b != 0 || x != 0 || reg_r.rex_required || reg_b.rex_required;
+        if needs_rex {
+            // With REX, the encodings of `%ah`..`%bh` mean `%spl`..`%dil`.
+            // The explicit `rex` prefix is left to the user as GNU as does.
+            if let Some(high_byte) = [&reg_r, &reg_b].into_iter().find(|reg| reg.is_high_byte()) {
+                self.explicit_prefix.high_byte_with_rex = Some(high_byte.clone());
+            }
+            if self.code_mode != CodeMode::Code64 {
+                self.explicit_prefix.rex_outside_64bit = true;
+                return;
+            }
+            match self.explicit_prefix.rex {
+                // The explicit `rex` prefix has already been emitted.
+                Some(explicit) => self.explicit_prefix.rex_conflict |= explicit != rex(w, r, x, b),
+                None => self.current_instr.code.push(rex(w, r, x, b)),
+            }
         }
     }
 }
@@ -340,43 +1039,2186 @@ fn compose_mod_rm(r#mod: u8, reg_op: u8, rm: u8) -> u8 {
 }
 
 impl Encoder {
+    /// Replace the references to constants(e.g. `.equ`) with their values.
+    fn expand_constants(&self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Ident(name) => match self.user_defined_constants.get(&name) {
+                Some((value, _)) => Expr::Number(value.to_string()),
+                None => Expr::Ident(name),
+            },
+            Expr::Neg(expr) => Expr::Neg(Box::new(self.expand_constants(*expr))),
+            Expr::Binop {
+                left_hs,
+                right_hs,
+                op,
+            } => Expr::Binop {
+                left_hs: Box::new(self.expand_constants(*left_hs)),
+                right_hs: Box::new(self.expand_constants(*right_hs)),
+                op,
+            },
+            Expr::Immediate(expr) => Expr::Immediate(Box::new(self.expand_constants(*expr))),
+            expr => expr,
+        }
+    }
+
+    /// Evaluate an expression, and collect the referenced symbols other than constants into `symbols`.
+    ///
+    /// The location counter `.` is collected as the label defined at the current statement.
+    /// e.g. `jmp .`, `.quad .`
+    fn eval_expr_with_symbols(&mut self, expr: Expr, symbols: &mut Vec<String>) -> Result<i64> {
+        let first = symbols.len();
+        let value = self.eval_expr_at_statement(expr, symbols)?;
+        if symbols[first..].iter().any(|symbol| symbol == ".") {
+            let dot_label = self.define_dot_label()?;
+            for symbol in &mut symbols[first..] {
+                if symbol == "." {
+                    symbol.clone_from(&dot_label);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Evaluate an expression, and collect the referenced symbols other than constants into `symbols`.
+    ///
+    /// The errors are reported at the current statement, because the expression has no location.
+    fn eval_expr_at_statement(&self, expr: Expr, symbols: &mut Vec<String>) -> Result<i64> {
+        eval_expr_get_symbol_64(self.expand_constants(expr), symbols).map_err(|err| {
+            match err.location {
+                Some(_) => err,
+                None => err.with_location(self.current_instr.loc),
+            }
+        })
+    }
+
+    /// Evaluate an expression that must not refer to any symbol. e.g. size of `.skip`
+    fn eval_abs_expr(&self, expr: Expr, loc: Location) -> Result<i64> {
+        let mut used_symbols = Vec::new();
+        let value = self.eval_expr_at_statement(expr, &mut used_symbols)?;
+        if let Some(symbol) = used_symbols.first() {
+            bail!(
+                loc,
+                "expected an absolute expression. but got symbol `{symbol}`"
+            );
+        }
+        Ok(value)
+    }
+
+    /// Define a local label at the current statement for the location counter `.`, and return its name.
+    ///
+    /// The label is defined once per statement, just before the statement.
+    fn define_dot_label(&mut self) -> Result<String> {
+        if let Some(dot_label) = &self.current_dot_label {
+            return Ok(dot_label.clone());
+        }
+        let dot_label = format!(".Ldot.{}", self.dot_labels);
+        self.dot_labels += 1;
+        self.define_symbol(Instr {
+            kind: InstrKind::Label,
+            loc: self.current_instr.loc,
+            section_name: self.current_instr.section_name.clone(),
+            symbol_name: dot_label.clone(),
+            ..Default::default()
+        })?;
+        self.current_dot_label = Some(dot_label.clone());
+        Ok(dot_label)
+    }
+
     fn encode_instr(&mut self, index: &mut usize, tokens: &[Token]) -> Result<()> {
         let Token { kind, loc } = peek_n(*index, tokens)?;
         let instr_name = match kind {
             TokenKind::Ident(ident) => ident,
-            _ => bail!(*loc, "invalid"),
+            _ => bail!(
+                *loc,
+                "Unexpected token {kind:?}. expected instruction or directive"
+            ),
         };
 
-        if *kind == TokenKind::Colon {
-            let instr = Instr {
+        // A label ends at the colon, so a statement may follow it on the same line. e.g. `foo: nop`
+        if let Some(Token {
+            kind: TokenKind::Colon,
+            ..
+        }) = tokens.get(*index + 1)
+        {
+            *index += 1;
+            expect(TokenKind::Colon, index, tokens)?;
+            return self.define_symbol(Instr {
                 kind: InstrKind::Label,
                 loc: *loc,
                 section_name: self.current_section_name.to_string(),
                 symbol_name: instr_name.to_string(),
                 ..Default::default()
+            });
+        }
+
+        if self.macros.contains_key(instr_name) {
+            return self.expand_macro(index, tokens);
+        }
+
+        let line = loc.line;
+        self.current_instr = Instr {
+            loc: *loc,
+            section_name: self.current_section_name.to_string(),
+            ..Default::default()
+        };
+        self.current_dot_label = None;
+        *index += 1;
+
+        // Prefix mnemonics are encoded as a part of the following instruction on the same line.
+        // e.g. `lock xaddq %rax, (%rbx)`, `data16 nop`
+        self.explicit_prefix = ExplicitPrefix::default();
+        self.required_feature = None;
+        let mut instr_name = instr_name;
+        loop {
+            let prefix = match instr_name.as_str() {
+                "lock" => 0xf0,
+                "data16" => {
+                    self.explicit_prefix.data16 = true;
+                    OPERAND_SIZE_PREFIX16
+                }
+                name => match explicit_rex_by(name) {
+                    Some(rex) => {
+                        self.explicit_prefix.rex = Some(rex);
+                        rex
+                    }
+                    None => break,
+                },
             };
+            if self.explicit_prefix.rex.is_some_and(|rex| rex != prefix) {
+                bail!(*loc, "`rex` prefix must be the last prefix");
+            }
+            self.current_instr.code.push(prefix);
 
-            expect(TokenKind::Colon, index, tokens)?;
-            if self.user_defined_symbols.contains_key(instr_name) {
-                bail!(*loc, "symbol {instr_name} is already defined");
+            instr_name = match tokens.get(*index) {
+                Some(Token {
+                    kind: TokenKind::Ident(name),
+                    loc: next,
+                }) if next.line == line => {
+                    *index += 1;
+                    name
+                }
+                _ => bail!(
+                    *loc,
+                    kind = ErrorKind::Parse,
+                    "expected instruction after `{instr_name}`"
+                ),
+            };
+        }
+
+        self.mnemonic.clone_from(instr_name);
+        self.operand_count = count_operands(*index, tokens, line);
+
+        match instr_name.as_str() {
+            ".byte" => self.encode_data(InstrKind::Byte, 1, index, tokens, line)?,
+            ".word" | ".2byte" => self.encode_data(InstrKind::Word, 2, index, tokens, line)?,
+            ".long" | ".4byte" => self.encode_data(InstrKind::Long, 4, index, tokens, line)?,
+            ".quad" | ".8byte" => self.encode_data(InstrKind::Quad, 8, index, tokens, line)?,
+            ".ascii" => self.encode_string(false, index, tokens, line)?,
+            ".asciz" | ".string" => self.encode_string(true, index, tokens, line)?,
+            ".string16" => self.encode_wide_string(2, index, tokens, line)?,
+            ".string32" => self.encode_wide_string(4, index, tokens, line)?,
+            ".skip" | ".zero" => self.encode_skip(index, tokens, line)?,
+            ".fill" => self.encode_fill(index, tokens, line)?,
+            ".lcomm" => self.encode_lcomm(index, tokens, line)?,
+            ".section" => self.encode_section(index, tokens, line)?,
+            ".text" | ".data" | ".rodata" | ".bss" => self.switch_section(instr_name.clone()),
+            ".code16" => self.code_mode = CodeMode::Code16,
+            ".code32" => self.code_mode = CodeMode::Code32,
+            ".code64" => self.code_mode = CodeMode::Code64,
+            ".equ" | ".set" => return self.encode_equ(false, index, tokens, line),
+            ".equiv" => return self.encode_equ(true, index, tokens, line),
+            ".reloc" => return self.encode_reloc(index, tokens, line),
+            ".file" => return self.encode_file(index, tokens, line),
+            ".loc" => self.encode_loc(index, tokens, line)?,
+            ".globl" | ".global" | ".local" | ".weak" | ".hidden" | ".internal" | ".protected" => {
+                return self.encode_symbol_attribute(&instr_name[1..], index, tokens, line);
             }
+            ".align" | ".balign" => self.encode_align(false, index, tokens, line)?,
+            ".p2align" => self.encode_align(true, index, tokens, line)?,
+            ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif" => {
+                return self.encode_conditional(instr_name, index, tokens, line);
+            }
+            ".macro" => return self.define_macro(index, tokens, line),
+            ".endm" => bail!(*loc, "`.endm` without `.macro`"),
+            "nop" if is_end_of_statement(*index, tokens, line) => {
+                self.encode_no_operand(InstrKind::Nop, &[0x90])?
+            }
+            "push" | "pushq" => self.encode_push(index, tokens)?,
+            "pop" | "popq" => self.encode_pop(index, tokens)?,
+            // Sign extend the accumulator. Both of Intel and AT&T mnemonics are accepted.
+            "cbw" | "cbtw" => self.encode_no_operand(InstrKind::Cbtw, &[0x66, 0x98])?,
+            "cwde" | "cwtl" => self.encode_no_operand(InstrKind::Cwtl, &[0x98])?,
+            "cdqe" | "cltq" => self.encode_no_operand(InstrKind::Cltq, &[0x48, 0x98])?,
+            "cwd" | "cwtd" => self.encode_no_operand(InstrKind::Cwtd, &[0x66, 0x99])?,
+            "cdq" | "cltd" => self.encode_no_operand(InstrKind::Cltd, &[0x99])?,
+            "cqo" | "cqto" => self.encode_no_operand(InstrKind::Cqto, &[0x48, 0x99])?,
+            "pushf" | "pushfq" => self.encode_no_operand(InstrKind::Pushf, &[0x9c])?,
+            "popf" | "popfq" => self.encode_no_operand(InstrKind::Popf, &[0x9d])?,
+            "syscall" => self.encode_no_operand(InstrKind::Syscall, &[0x0f, 0x05])?,
+            "sysenter" => self.encode_no_operand(InstrKind::Sysenter, &[0x0f, 0x34])?,
+            "swapgs" => self.encode_no_operand(InstrKind::Swapgs, &[0x0f, 0x01, 0xf8])?,
+            // The operands are implicitly `%eax`, `%ecx` and `%edx`.
+            "rdtsc" => self.encode_no_operand(InstrKind::Rdtsc, &[0x0f, 0x31])?,
+            "rdtscp" => self.encode_no_operand(InstrKind::Rdtscp, &[0x0f, 0x01, 0xf9])?,
+            "rdpmc" => self.encode_no_operand(InstrKind::Rdpmc, &[0x0f, 0x33])?,
+            "cpuid" => self.encode_no_operand(InstrKind::Cpuid, &[0x0f, 0xa2])?,
+            // Return to 32bit mode by default, and to 64bit mode by REX.W. e.g. `sysretq`
+            "sysret" | "sysretl" | "sysexit" | "sysexitl" => {
+                if !instr_name.ends_with('l') {
+                    self.warn(
+                        *loc,
+                        format!("no instruction mnemonic suffix given. using `{instr_name}l`"),
+                    );
+                }
+                match instr_name.starts_with("sysret") {
+                    true => self.encode_no_operand(InstrKind::Sysret, &[0x0f, 0x07])?,
+                    false => self.encode_no_operand(InstrKind::Sysexit, &[0x0f, 0x35])?,
+                }
+            }
+            "sysretq" => self.encode_no_operand(InstrKind::Sysret, &[0x48, 0x0f, 0x07])?,
+            "sysexitq" => self.encode_no_operand(InstrKind::Sysexit, &[0x48, 0x0f, 0x35])?,
+            "lfence" => self.encode_no_operand(InstrKind::Lfence, &[0x0f, 0xae, 0xe8])?,
+            "mfence" => self.encode_no_operand(InstrKind::Mfence, &[0x0f, 0xae, 0xf0])?,
+            "sfence" => self.encode_no_operand(InstrKind::Sfence, &[0x0f, 0xae, 0xf8])?,
+            "endbr64" => self.encode_no_operand(InstrKind::Endbr, &[0xf3, 0x0f, 0x1e, 0xfa])?,
+            "endbr32" => self.encode_no_operand(InstrKind::Endbr, &[0xf3, 0x0f, 0x1e, 0xfb])?,
+            // CLFLUSH m8: 0f ae /7
+            "clflush" => {
+                self.encode_mem_operand(InstrKind::Clflush, &[0x0f, 0xae], 7, index, tokens)?
+            }
+            // PREFETCHNTA m8: 0f 18 /0, PREFETCHT0..2 m8: 0f 18 /1../3
+            "prefetchnta" | "prefetcht0" | "prefetcht1" | "prefetcht2" => {
+                let hint = match instr_name.as_str() {
+                    "prefetchnta" => 0,
+                    "prefetcht0" => 1,
+                    "prefetcht1" => 2,
+                    _ => 3,
+                };
+                self.encode_mem_operand(InstrKind::Prefetch, &[0x0f, 0x18], hint, index, tokens)?
+            }
+            "mov" => self.encode_mov(DataSizeSuffix::Unknown, index, tokens)?,
+            "movb" => self.encode_mov(DataSizeSuffix::Byte, index, tokens)?,
+            "movw" => self.encode_mov(DataSizeSuffix::Word, index, tokens)?,
+            "movl" => self.encode_mov(DataSizeSuffix::Long, index, tokens)?,
+            "movq" => self.encode_mov(DataSizeSuffix::Quad, index, tokens)?,
+            "movd" => self.encode_mov(DataSizeSuffix::Long, index, tokens)?,
+            "lea" => self.encode_lea(DataSizeSuffix::Unknown, index, tokens)?,
+            "leal" => self.encode_lea(DataSizeSuffix::Long, index, tokens)?,
+            "leaq" => self.encode_lea(DataSizeSuffix::Quad, index, tokens)?,
+            // Unknown directives are ignored with a warning, or rejected in the strict mode.
+            directive if directive.starts_with('.') => {
+                if !is_ignored_directive(directive) {
+                    if self.options.strict {
+                        bail!(*loc, "unknown directive `{directive}`");
+                    }
+                    self.warn(*loc, format!("unknown directive `{directive}` is ignored"));
+                }
+                skip_statement(index, tokens, line);
+                return Ok(());
+            }
+            name => {
+                if let Some((kind, ext, size)) = alu::alu_op_by(name) {
+                    self.encode_alu(kind, ext, size, index, tokens)?;
+                } else if let Some((kind, opcode, ext, size)) = alu::unary_op_by(name) {
+                    self.encode_unary(kind, opcode, ext, size, index, tokens)?;
+                } else if let Some(size) = alu::test_op_by(name) {
+                    self.encode_test(size, index, tokens)?;
+                } else if let Some(size) = alu::nop_op_by(name) {
+                    self.encode_nop(size, index, tokens)?;
+                } else if let Some(size) = vex::kmov_op_by(name) {
+                    self.encode_kmov(size, index, tokens)?;
+                } else if let Some((kind, pp, opcode)) = vex::vex_arith_op_by(name) {
+                    self.encode_vex_arith(kind, pp, opcode, index, tokens)?;
+                } else if let Some(size) = alu::movbe_op_by(name) {
+                    self.encode_movbe(size, index, tokens)?;
+                } else if let Some((kind, opcode)) = sse::packed_int_op_by(name) {
+                    self.encode_packed_int(kind, opcode, index, tokens)?;
+                } else if let Some((kind, opcode, size)) = alu::exchange_op_by(name) {
+                    self.encode_exchange(kind, opcode, size, index, tokens)?;
+                } else if let Some((kind, opcode, size)) = alu::double_shift_op_by(name) {
+                    self.encode_double_shift(kind, opcode, size, index, tokens)?;
+                } else if let Some((kind, f3, opcode, size)) = alu::bit_count_op_by(name) {
+                    self.encode_bit_count(kind, f3, opcode, size, index, tokens)?;
+                } else if let Some((kind, opcode)) = jump::jump_op_by(name) {
+                    self.encode_jump(kind, &opcode, index, tokens)?;
+                } else if let Some((kind, opcode)) = jump::loop_op_by(name) {
+                    self.encode_loop(kind, opcode, index, tokens)?;
+                } else if let Some((kind, size)) = port::port_op_by(name) {
+                    self.encode_port(kind, size, index, tokens)?;
+                } else {
+                    bail!(*loc, "unknown instruction `{instr_name}`");
+                }
+            }
+        };
 
-            self.user_defined_symbols
-                .insert(instr_name.to_string(), instr.clone());
-            self.instrs.push(instr);
-            return Ok(());
+        if let Some(feature) = self
+            .required_feature
+            .filter(|feature| self.options.disabled_features.contains(feature))
+        {
+            bail!(*loc, "`{instr_name}` needs `{feature}`, which is disabled");
+        }
+        if let Some(high_byte) = &self.explicit_prefix.high_byte_with_rex {
+            bail!(
+                *loc,
+                "`{high_byte}` cannot be used in an instruction requiring a REX prefix"
+            );
+        }
+        if self.explicit_prefix.rex_outside_64bit {
+            bail!(
+                *loc,
+                "`{instr_name}` needs a REX prefix, which is only available in `.code64`"
+            );
         }
+        if self.explicit_prefix.rex_conflict {
+            bail!(
+                *loc,
+                "`rex` prefix conflicts with the REX prefix `{instr_name}` needs"
+            );
+        }
+        // Only reserving zero-filled space is allowed in `.bss`. e.g. `.skip 4`
+        // The other statements without bytes are also fine. e.g. labels, `.code64`
+        let has_bytes = !self.current_instr.code.is_empty()
+            || self.current_instr.align_fill.is_some_and(|fill| fill != 0);
+        if is_nobits_section(&self.current_instr.section_name) && has_bytes {
+            bail!(
+                *loc,
+                "cannot emit data into a nobits section `{}`",
+                self.current_instr.section_name
+            );
+        }
+
+        let instr = core::mem::take(&mut self.current_instr);
+        log::debug!(
+            "instruction encoded {}: {}: {:?} {:02x?}",
+            instr.loc,
+            instr.section_name,
+            instr.kind,
+            instr.code
+        );
+        self.instrs.push(instr);
         Ok(())
     }
-}
 
-pub(crate) fn parse(tokens: Vec<Token>) -> Result<()> {
-    let mut index = 0;
-    dbg!(index);
-    while index <= tokens.len() {
-        dbg!(parse_operand(&mut index, &tokens)?);
-        index += 1;
+    fn warn(&mut self, loc: Location, message: String) {
+        self.warnings.push(Warning {
+            message,
+            location: Some(loc),
+        });
     }
 
-    Ok(())
+    /// Register a label-like symbol and push it to the instructions.
+    fn define_symbol(&mut self, instr: Instr) -> Result<()> {
+        if self.user_defined_symbols.contains_key(&instr.symbol_name) {
+            bail!(instr.loc, "symbol {} is already defined", instr.symbol_name);
+        }
+        if let Some((_, defined_loc)) = self.user_defined_constants.get(&instr.symbol_name) {
+            bail!(
+                instr.loc,
+                "symbol {} is already defined as a constant at {defined_loc}",
+                instr.symbol_name
+            );
+        }
+
+        self.user_defined_symbols
+            .insert(instr.symbol_name.clone(), instr.clone());
+        self.instrs.push(instr);
+        Ok(())
+    }
+
+    /// Set the kind of the current instruction, and check the number of its operands before they are parsed.
+    fn set_kind(&mut self, kind: InstrKind) -> Result<()> {
+        if let Some(counts) = kind.operand_counts() {
+            if !counts.contains(&self.operand_count) {
+                let expected: Vec<_> = counts.iter().map(ToString::to_string).collect();
+                bail!(
+                    self.current_instr.loc,
+                    kind = ErrorKind::Parse,
+                    "instruction `{}` expects {} operand{}, but got {}",
+                    self.mnemonic,
+                    expected.join(" or "),
+                    if counts == [1] { "" } else { "s" },
+                    self.operand_count
+                );
+            }
+        }
+        self.current_instr.kind = kind;
+        Ok(())
+    }
+
+    /// Encode an instruction that takes no operands. e.g. `nop`
+    fn encode_no_operand(&mut self, kind: InstrKind, code: &[u8]) -> Result<()> {
+        self.set_kind(kind)?;
+        self.current_instr.code.extend_from_slice(code);
+        Ok(())
+    }
+
+    /// e.g. `movq %rax, %rbx`
+    ///
+    /// - `size`: size of the mnemonic suffix. `Unknown` means it is inferred from the registers.
+    fn encode_mov(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Mov)?;
+        let (src, dst) = match parse_two_operand(index, tokens)? {
+            (Expr::Register(src), Expr::Register(dst)) => (src, dst),
+            // MOVD/MOVQ xmm, r/m: 66 (REX.W) 0f 6e /r
+            (Expr::Register(src), Expr::Xmm(dst)) => {
+                return self.encode_mov_xmm_gpr(size, 0x6e, dst, src);
+            }
+            // MOVD/MOVQ r/m, xmm: 66 (REX.W) 0f 7e /r
+            (Expr::Xmm(src), Expr::Register(dst)) => {
+                return self.encode_mov_xmm_gpr(size, 0x7e, src, dst);
+            }
+            (src @ Expr::Xmm(_), dst) | (src, dst @ Expr::Xmm(_)) => {
+                return self.encode_mov_xmm(size, src, dst);
+            }
+            (Expr::Immediate(imm), Expr::Register(dst)) => {
+                return self.encode_mov_imm(size, *imm, dst);
+            }
+            // MOV r/m8, imm8: c6 /0 ib, MOV r/m, imm: c7 /0 iw/id
+            (Expr::Immediate(imm), mem) => {
+                self.current_instr.kind = InstrKind::Mov;
+                let imm = self.eval_abs_expr(*imm, loc)?;
+                let opcode = match size {
+                    DataSizeSuffix::Byte => 0xc6,
+                    _ => 0xc7,
+                };
+                return self.encode_imm_mem(size, opcode, 0, imm, size, &mem);
+            }
+            // MOV r/m, r: 88 /r(8bit), 89 /r(16, 32, 64bit)
+            (Expr::Register(src), mem) => {
+                return self.encode_mov_mem(size, [0x88, 0x89], src, &mem);
+            }
+            // MOV r, r/m: 8a /r(8bit), 8b /r(16, 32, 64bit)
+            (mem, Expr::Register(dst)) => {
+                return self.encode_mov_mem(size, [0x8a, 0x8b], dst, &mem);
+            }
+            _ => bail!(loc, "unsupported operands for `mov`"),
+        };
+
+        let size = match size {
+            DataSizeSuffix::Unknown => src.size,
+            size => size,
+        };
+        if src.size != size || dst.size != size {
+            bail!(loc, "operand size mismatch for `mov`. `{src}`, `{dst}`");
+        }
+
+        self.current_instr.kind = InstrKind::Mov;
+        // MOV r/m, r: 88 /r(8bit), 89 /r(16, 32, 64bit)
+        let opcode = match size {
+            DataSizeSuffix::Byte => 0x88,
+            _ => 0x89,
+        };
+        self.add_prefix(src.clone(), Register::default(), dst.clone(), &[size]);
+        self.current_instr.code.push(opcode);
+        self.current_instr.code.push(compose_mod_rm(
+            MOD_REGI,
+            src.base_offset & 7,
+            dst.base_offset & 7,
+        ));
+        Ok(())
+    }
+
+    /// Move an immediate to general purpose register. e.g. `movl $msg, %esi`
+    ///
+    /// A symbol in the immediate is relocated by the absolute relocation of the immediate size,
+    /// or by the one of its modifier. e.g. `movq $var@tpoff, %rax`
+    fn encode_mov_imm(&mut self, size: DataSizeSuffix, imm: Expr, dst: Register) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let size = match size {
+            DataSizeSuffix::Unknown => dst.size,
+            size => size,
+        };
+        if dst.size != size {
+            bail!(loc, "operand size mismatch for `mov`. `{dst}`");
+        }
+
+        let (imm, symbol) = self.eval_imm_with_symbol(imm, size)?;
+        if size == DataSizeSuffix::Quad
+            && symbol.is_none()
+            && i32::try_from(imm).is_err()
+            && self.options.imm64_in_rodata
+        {
+            return self.encode_mov_imm_from_rodata(imm, dst);
+        }
+
+        self.current_instr.kind = InstrKind::Mov;
+        self.add_prefix(
+            Register::default(),
+            Register::default(),
+            dst.clone(),
+            &[size],
+        );
+        let reg_bits = dst.base_offset & 7;
+        let imm_size = match size {
+            // MOV r8, imm8: b0+r ib
+            DataSizeSuffix::Byte => {
+                self.current_instr.code.push(0xb0 + reg_bits);
+                1
+            }
+            // MOV r16, imm16: 66 b8+r iw
+            DataSizeSuffix::Word => {
+                self.current_instr.code.push(0xb8 + reg_bits);
+                2
+            }
+            // MOV r64, imm32: REX.W c7 /0 id (sign extended)
+            DataSizeSuffix::Quad if symbol.is_some() || i32::try_from(imm).is_ok() => {
+                self.current_instr
+                    .code
+                    .extend_from_slice(&[0xc7, compose_mod_rm(MOD_REGI, 0, reg_bits)]);
+                4
+            }
+            // MOV r64, imm64: REX.W b8+r io (movabs)
+            DataSizeSuffix::Quad => {
+                self.current_instr.code.push(0xb8 + reg_bits);
+                8
+            }
+            // MOV r32, imm32: b8+r id
+            _ => {
+                self.current_instr.code.push(0xb8 + reg_bits);
+                4
+            }
+        };
+
+        match symbol {
+            Some((symbol, rtype)) => self.push_imm_reloc(symbol, rtype, imm, imm_size),
+            None => self.push_truncated(imm, imm_size),
+        }
+        Ok(())
+    }
+
+    /// Evaluate an immediate operand that may refer to one symbol. e.g. `$msg + 8`, `$var@tpoff`
+    ///
+    /// Returns the value, which is the addend if the symbol exists, and the symbol with its relocation type.
+    /// The relocation type is the absolute one of the immediate `size`, or the one given by a modifier.
+    pub(crate) fn eval_imm_with_symbol(
+        &mut self,
+        imm: Expr,
+        size: DataSizeSuffix,
+    ) -> Result<(i64, Option<(String, u64)>)> {
+        let loc = self.current_instr.loc;
+        let modifier = relocation_modifier(&imm).map(str::to_owned);
+        let mut used_symbols = Vec::new();
+        let imm = self.eval_expr_with_symbols(imm, &mut used_symbols)?;
+        let symbol = match used_symbols.as_slice() {
+            [] => return Ok((imm, None)),
+            [symbol] => symbol.clone(),
+            _ => bail!(loc, "immediate must refer to at most one symbol"),
+        };
+
+        // `@tpoff` and `@dtpoff` are signed, so only the sign extended `imm32` of 64bit operations accepts them.
+        let upper = modifier.as_deref().map(str::to_ascii_uppercase);
+        let rtype = match (upper.as_deref(), size) {
+            (None, DataSizeSuffix::Byte) => R_X86_64_8,
+            (None, DataSizeSuffix::Word) => R_X86_64_16,
+            (None, DataSizeSuffix::Quad) => R_X86_64_32S,
+            (None, _) => R_X86_64_32,
+            (Some("TPOFF"), DataSizeSuffix::Quad) => R_X86_64_TPOFF32,
+            (Some("DTPOFF"), DataSizeSuffix::Quad) => R_X86_64_DTPOFF32,
+            (Some("TPOFF" | "DTPOFF"), _) => bail!(
+                loc,
+                "`@{}` requires a sign extended 32bit immediate of 64bit operand",
+                modifier.unwrap_or_default()
+            ),
+            (Some(_), _) => bail!(
+                loc,
+                "relocation modifier `@{}` is not supported for an immediate",
+                modifier.unwrap_or_default()
+            ),
+        };
+        Ok((imm, Some((symbol, rtype))))
+    }
+
+    /// Push the `size` bytes placeholder of an immediate relocated by `symbol`.
+    ///
+    /// - `adjust`: the addend of the symbol. e.g. `8` for `$msg + 8`
+    pub(crate) fn push_imm_reloc(&mut self, symbol: String, rtype: u64, adjust: i64, size: usize) {
+        let offset = self.current_instr.code.len();
+        self.current_instr.code.resize(offset + size, 0);
+        self.rela_text_users.push(Rela {
+            uses: symbol,
+            instr: self.current_instr.clone(),
+            offset,
+            rtype,
+            adjust: adjust as i32,
+            instr_idx: Some(self.instrs.len()),
+            ..Default::default()
+        });
+    }
+
+    /// Load a 64-bit immediate from a constant in `.rodata` instead of `movabs`.
+    ///
+    /// e.g. `movq $0x1122334455667788, %rax` => `movq .Limm64.0(%rip), %rax`
+    ///
+    /// - MOV r64, r/m64: REX.W 8b /r
+    ///
+    /// The same values share a constant, and they are placed by [`Self::place_imm64_constants`].
+    fn encode_mov_imm_from_rodata(&mut self, imm: i64, dst: Register) -> Result<()> {
+        let number = match self.imm64_constants.iter().position(|&value| value == imm) {
+            Some(number) => number,
+            None => {
+                self.imm64_constants.push(imm);
+                self.imm64_constants.len() - 1
+            }
+        };
+        let mem = Memory {
+            disp: Some(Expr::Ident(format!(".Limm64.{number}"))),
+            base: Some(get_reg_info_by("RIP")?),
+            index: None,
+            scale: 1,
+            segment: None,
+        };
+
+        self.current_instr.kind = InstrKind::Mov;
+        self.add_prefix(
+            dst.clone(),
+            Register::default(),
+            Register::default(),
+            &[DataSizeSuffix::Quad],
+        );
+        self.current_instr.code.push(0x8b);
+        self.add_modrm_sib_disp(&mem, dst.base_offset)
+    }
+
+    /// Lay out the constants of [`Self::encode_mov_imm_from_rodata`] at the end of `.rodata`.
+    pub(crate) fn place_imm64_constants(&mut self) -> Result<()> {
+        if self.imm64_constants.is_empty() {
+            return Ok(());
+        }
+        self.instrs.push(Instr {
+            kind: InstrKind::Align,
+            align: 8,
+            section_name: ".rodata".to_owned(),
+            ..Default::default()
+        });
+        for (number, value) in core::mem::take(&mut self.imm64_constants)
+            .into_iter()
+            .enumerate()
+        {
+            let label = Instr {
+                kind: InstrKind::Label,
+                section_name: ".rodata".to_owned(),
+                symbol_name: format!(".Limm64.{number}"),
+                ..Default::default()
+            };
+            if self.user_defined_symbols.contains_key(&label.symbol_name) {
+                error::bail!(
+                    "symbol {} conflicts with the constant of a 64-bit immediate",
+                    label.symbol_name
+                );
+            }
+            self.user_defined_symbols
+                .insert(label.symbol_name.clone(), label.clone());
+            self.instrs.push(label);
+            self.instrs.push(Instr {
+                kind: InstrKind::Quad,
+                section_name: ".rodata".to_owned(),
+                code: value.to_le_bytes().to_vec(),
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Move between general purpose register and memory. e.g. `movq (%rsp), %rax`
+    ///
+    /// - `opcodes`: opcode for 8bit and the others.
+    fn encode_mov_mem(
+        &mut self,
+        size: DataSizeSuffix,
+        opcodes: [u8; 2],
+        reg: Register,
+        mem: &Expr,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let mem = match Memory::from_expr(mem, loc)? {
+            Some(mem) => mem,
+            None => bail!(loc, "unsupported operands for `mov`"),
+        };
+
+        let size = match size {
+            DataSizeSuffix::Unknown => reg.size,
+            size => size,
+        };
+        if reg.size != size {
+            bail!(loc, "operand size mismatch for `mov`. `{reg}`");
+        }
+
+        self.current_instr.kind = InstrKind::Mov;
+        self.add_prefix(
+            reg.clone(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[size],
+        );
+        self.current_instr.code.push(match size {
+            DataSizeSuffix::Byte => opcodes[0],
+            _ => opcodes[1],
+        });
+        self.add_modrm_sib_disp(&mem, reg.base_offset)
+    }
+
+    /// Move between general purpose register and xmm register. e.g. `movq %rax, %xmm0`
+    ///
+    /// - `size`: `Long`(movd) or `Quad`(movq). `Unknown` means it is inferred from `gpr`.
+    fn encode_mov_xmm_gpr(
+        &mut self,
+        size: DataSizeSuffix,
+        opcode: u8,
+        xmm: Register,
+        gpr: Register,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let size = match size {
+            DataSizeSuffix::Unknown => gpr.size,
+            size => size,
+        };
+        if !matches!(size, DataSizeSuffix::Long | DataSizeSuffix::Quad) || gpr.size != size {
+            bail!(loc, "operand size mismatch for `movd`/`movq`. `{gpr}`");
+        }
+        self.required_feature = Some(CpuFeature::Sse2);
+
+        self.current_instr.kind = match size {
+            DataSizeSuffix::Quad => InstrKind::Mov,
+            _ => InstrKind::Movd,
+        };
+        // The mandatory prefix must precede REX prefix.
+        self.current_instr.code.push(OPERAND_SIZE_PREFIX16);
+        self.add_prefix(xmm.clone(), Register::default(), gpr.clone(), &[size]);
+        self.current_instr.code.extend_from_slice(&[0x0f, opcode]);
+        self.current_instr.code.push(compose_mod_rm(
+            MOD_REGI,
+            xmm.base_offset & 7,
+            gpr.base_offset & 7,
+        ));
+        Ok(())
+    }
+
+    /// e.g. `leaq msg(%rip), %rsi`, `leaq 8(%rbx, %rcx, 4), %rax`
+    ///
+    /// A symbol in `%rip` relative displacement is relocated by `R_X86_64_PC32`(not `R_X86_64_PLT32` like `call`),
+    /// because the address of the symbol itself is taken.
+    fn encode_lea(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Lea)?;
+        let (mem, dst) = match parse_two_operand(index, tokens)? {
+            (src, Expr::Register(dst)) => match Memory::from_expr(&src, loc)? {
+                Some(mem) => (mem, dst),
+                None => bail!(loc, "unsupported operands for `lea`. expected `mem, %reg`"),
+            },
+            _ => bail!(loc, "unsupported operands for `lea`. expected `mem, %reg`"),
+        };
+
+        let size = match size {
+            DataSizeSuffix::Unknown => dst.size,
+            size => size,
+        };
+        if !matches!(size, DataSizeSuffix::Long | DataSizeSuffix::Quad) || dst.size != size {
+            bail!(loc, "operand size mismatch for `lea`. `{dst}`");
+        }
+
+        self.add_prefix(
+            dst.clone(),
+            mem.index.clone().unwrap_or_default(),
+            mem.base.clone().unwrap_or_default(),
+            &[size],
+        );
+        // LEA r, m: 8d /r
+        self.current_instr.code.push(0x8d);
+        self.add_modrm_sib_disp(&mem, dst.base_offset)
+    }
+
+    /// Local labels which are never referenced by any relocation, including the resolved jumps, in source order.
+    pub(crate) fn unused_local_symbols(&self) -> Vec<&str> {
+        self.instrs
+            .iter()
+            .filter(|instr| instr.kind == InstrKind::Label)
+            .filter_map(|instr| self.user_defined_symbols.get(&instr.symbol_name))
+            .filter(|symbol| symbol.binding == STB_LOCAL)
+            .filter(|symbol| {
+                !self
+                    .rela_text_users
+                    .iter()
+                    .any(|rela| rela.uses == symbol.symbol_name)
+            })
+            .map(|symbol| symbol.symbol_name.as_str())
+            .collect()
+    }
+
+    /// Is the symbol or constant defined at this point?
+    fn is_defined(&self, symbol_name: &str) -> bool {
+        self.user_defined_symbols.contains_key(symbol_name)
+            || self.user_defined_constants.contains_key(symbol_name)
+    }
+
+    /// Define a constant. e.g. `.equ name, expr`, `.set name, expr`, `.equiv name, expr`
+    ///
+    /// - `is_equiv`: Error if the name is already defined as a constant or symbol.
+    fn encode_equ(
+        &mut self,
+        is_equiv: bool,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let (name, expr) = match parse_expr_list(index, tokens, line)?.as_slice() {
+            [Expr::Ident(name), expr] => (name.clone(), expr.clone()),
+            _ => bail!(loc, "expected `name, expression`"),
+        };
+
+        if is_equiv {
+            let defined_loc = match self.user_defined_symbols.get(&name) {
+                Some(symbol) => Some(symbol.loc),
+                None => self.user_defined_constants.get(&name).map(|(_, loc)| *loc),
+            };
+            if let Some(defined_loc) = defined_loc {
+                bail!(loc, "`{name}` is already defined at {defined_loc}");
+            }
+        } else if let Some(symbol) = self.user_defined_symbols.get(&name) {
+            bail!(
+                loc,
+                "`{name}` is already defined as a symbol at {}",
+                symbol.loc
+            );
+        }
+
+        // `.set here, .` is an alias of the current location.
+        let expr = match expr {
+            Expr::Ident(dot) if dot == "." => Expr::Ident(self.define_dot_label()?),
+            expr => expr,
+        };
+
+        // A name which is not a constant is a label, which may be defined later. e.g. `.set alias, target`
+        if let Expr::Ident(target) = &expr {
+            if !self.user_defined_constants.contains_key(target) {
+                if let Some((_, defined_loc)) = self.user_defined_constants.get(&name) {
+                    bail!(
+                        loc,
+                        "`{name}` is already defined as a constant at {defined_loc}"
+                    );
+                }
+                self.user_defined_symbols.insert(
+                    name.clone(),
+                    Instr {
+                        kind: InstrKind::Label,
+                        symbol_name: name.clone(),
+                        loc,
+                        ..Default::default()
+                    },
+                );
+                self.symbol_aliases.push((name, target.clone()));
+                return Ok(());
+            }
+        }
+
+        // Constants cannot be relocated, so every name must be resolved to a constant here.
+        let mut used_symbols = Vec::new();
+        let value = self.eval_expr_with_symbols(expr, &mut used_symbols)?;
+        if let Some(symbol) = used_symbols.first() {
+            match self.user_defined_symbols.get(symbol) {
+                Some(defined) => bail!(
+                    loc,
+                    "constant `{name}` cannot refer to the symbol `{symbol}` defined at {}",
+                    defined.loc
+                ),
+                None => bail!(
+                    loc,
+                    kind = ErrorKind::UndefinedSymbol(symbol.clone()),
+                    "constant `{name}` refers to undefined `{symbol}`"
+                ),
+            }
+        }
+        self.user_defined_constants.insert(name, (value, loc));
+        Ok(())
+    }
+
+    /// Conditional assembly. e.g. `.ifdef SYM`, `.ifndef SYM`, `.if expr`, `.else`, `.endif`
+    ///
+    /// The condition is evaluated during parsing, and the tokens of the inactive block are skipped.
+    fn encode_conditional(
+        &mut self,
+        directive: &str,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+
+        match directive {
+            // Reached the end of the assembled block, so the else block is skipped.
+            ".else" => {
+                let if_loc = match self.conditional_stack.pop() {
+                    Some(if_loc) => if_loc,
+                    None => bail!(loc, "`.else` without `.if`"),
+                };
+                if skip_conditional_block(index, tokens, if_loc)? {
+                    bail!(loc, "duplicate `.else`");
+                }
+            }
+            ".endif" => {
+                if self.conditional_stack.pop().is_none() {
+                    bail!(loc, "`.endif` without `.if`");
+                }
+            }
+            _ => {
+                let operands = parse_expr_list(index, tokens, line)?;
+                let is_true = match (directive, operands.as_slice()) {
+                    (".ifdef", [Expr::Ident(symbol)]) => self.is_defined(symbol),
+                    (".ifndef", [Expr::Ident(symbol)]) => !self.is_defined(symbol),
+                    (".if", [expr]) => self.eval_abs_expr(expr.clone(), loc)? != 0,
+                    _ => bail!(loc, "invalid operands for `{directive}`"),
+                };
+
+                if is_true || skip_conditional_block(index, tokens, loc)? {
+                    self.conditional_stack.push(loc);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserve zero filled bytes in the current section.
+    ///
+    /// e.g. `.skip size[, fill]`
+    fn encode_skip(&mut self, index: &mut usize, tokens: &[Token], line: usize) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let mut operands = parse_expr_list(index, tokens, line)?.into_iter();
+
+        let size = match operands.next() {
+            Some(size) => self.eval_abs_expr(size, loc)?,
+            None => bail!(loc, "expected size of `.skip`"),
+        };
+        let fill = match operands.next() {
+            Some(fill) => self.eval_abs_expr(fill, loc)?,
+            None => 0,
+        };
+        if size < 0 {
+            bail!(loc, "`.skip` size must not be negative. Got {size}");
+        }
+
+        self.current_instr.kind = InstrKind::Skip;
+        if !self.reserve_zeros(".skip", size, fill as u8 == 0)? {
+            self.current_instr.code = vec![fill as u8; size as usize];
+        }
+        Ok(())
+    }
+
+    /// Reserve `len` zero bytes without allocating them in a nobits section. e.g. `.skip 4` in `.bss`
+    ///
+    /// Returns `false` if the bytes must be emitted, after checking that they are not too many.
+    fn reserve_zeros(&mut self, directive: &str, len: i64, is_zero: bool) -> Result<bool> {
+        if is_zero && is_nobits_section(&self.current_instr.section_name) {
+            self.current_instr.reserved = len as usize;
+            return Ok(true);
+        }
+        if len > MAX_DATA_LEN {
+            bail!(
+                self.current_instr.loc,
+                "`{directive}` of {len} bytes is too large. It must be at most {MAX_DATA_LEN:#x} bytes"
+            );
+        }
+        Ok(false)
+    }
+
+    /// Repeat a `size` bytes value. `size` is 1 and `value` is 0 by default.
+    ///
+    /// e.g. `.fill repeat[, size[, value]]`
+    ///
+    /// As GNU as does, only the low 4 bytes are taken from `value`, and the higher bytes are zero.
+    fn encode_fill(&mut self, index: &mut usize, tokens: &[Token], line: usize) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let operands = parse_expr_list(index, tokens, line)?;
+        if operands.is_empty() || operands.len() > 3 {
+            bail!(loc, "expected `repeat[, size[, value]]`");
+        }
+        let mut operands = operands.into_iter();
+        let mut next_or = |default| match operands.next() {
+            Some(expr) => self.eval_abs_expr(expr, loc),
+            None => Ok(default),
+        };
+        let repeat = next_or(0)?;
+        let size = next_or(1)?;
+        let value = next_or(0)?;
+
+        if repeat < 0 {
+            bail!(loc, "`.fill` repeat must not be negative. Got {repeat}");
+        }
+        if !(1..=8).contains(&size) {
+            bail!(loc, "`.fill` size must be 1 to 8. Got {size}");
+        }
+
+        self.current_instr.kind = InstrKind::Fill;
+        if self.reserve_zeros(".fill", repeat.saturating_mul(size), value as u32 == 0)? {
+            return Ok(());
+        }
+        let bytes = u64::from(value as u32).to_le_bytes();
+        for _ in 0..repeat {
+            self.current_instr
+                .code
+                .extend_from_slice(&bytes[..size as usize]);
+        }
+        Ok(())
+    }
+
+    /// e.g. `.align 16`, `.p2align 4`, `.p2align 4, 0x90, 3`
+    ///
+    /// - `is_power_of_2`: operand is the exponent of 2(`.p2align`)
+    ///
+    /// The optional 2nd operand is the fill byte and the 3rd one is the max-skip.
+    fn encode_align(
+        &mut self,
+        is_power_of_2: bool,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let (align, fill, max_skip) =
+            match parse_optional_expr_list(index, tokens, line)?.as_slice() {
+                [Some(align), rest @ ..] if rest.len() <= 2 => {
+                    let mut rest = rest.iter().map(|expr| match expr {
+                        Some(expr) => self.eval_abs_expr(expr.clone(), loc).map(Some),
+                        None => Ok(None),
+                    });
+                    let fill = rest.next().transpose()?.flatten();
+                    let max_skip = rest.next().transpose()?.flatten();
+                    (self.eval_abs_expr(align.clone(), loc)?, fill, max_skip)
+                }
+                _ => bail!(loc, "expected `alignment[, fill[, max-skip]]`"),
+            };
+
+        let align = match is_power_of_2 {
+            true if (0..64).contains(&align) => 1 << align,
+            false if align > 0 && (align as u64).is_power_of_two() => align as u64,
+            _ => bail!(loc, "invalid alignment. Got {align}"),
+        };
+        if align > MAX_ALIGN {
+            bail!(
+                loc,
+                "alignment {align:#x} is too large. It must be at most {MAX_ALIGN:#x}"
+            );
+        }
+        let align = align as usize;
+
+        if let Some(max_skip) = max_skip {
+            if max_skip < 0 {
+                bail!(loc, "max-skip must not be negative. Got {max_skip}");
+            }
+        }
+
+        self.current_instr.kind = InstrKind::Align;
+        self.current_instr.align = align;
+        self.current_instr.align_fill = fill.map(|fill| fill as u8);
+        self.current_instr.align_max_skip = max_skip.map(|max_skip| max_skip as usize);
+        Ok(())
+    }
+
+    /// Switch the current section.
+    ///
+    /// e.g. `.section .data, "aw"`
+    fn encode_section(&mut self, index: &mut usize, tokens: &[Token], line: usize) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let section_name = match &peek_n(*index, tokens)?.kind {
+            TokenKind::Ident(name) if !is_end_of_statement(*index, tokens, line) => name.clone(),
+            _ => bail!(loc, "expected section name"),
+        };
+        *index += 1;
+
+        if !is_end_of_statement(*index, tokens, line) {
+            expect(TokenKind::Comma, index, tokens)?;
+            let Token {
+                kind,
+                loc: flags_loc,
+            } = peek_n(*index, tokens)?;
+            match kind {
+                TokenKind::Token(flags) => self.current_instr.flags = flags.clone(),
+                _ => bail!(*flags_loc, "expected section flags. e.g. \"ax\""),
+            }
+            *index += 1;
+            // Section type(e.g. `@progbits`) is not supported yet. The arguments of the flags follow it.
+            if !is_end_of_statement(*index, tokens, line) {
+                expect(TokenKind::Comma, index, tokens)?;
+            }
+            while !is_end_of_statement(*index, tokens, line)
+                && peek_n(*index, tokens)?.kind != TokenKind::Comma
+            {
+                *index += 1;
+            }
+            let mut args = Vec::new().into_iter();
+            if !is_end_of_statement(*index, tokens, line) {
+                expect(TokenKind::Comma, index, tokens)?;
+                args = parse_expr_list(index, tokens, line)?.into_iter();
+            }
+            // The arguments follow the type in the order of `M`, `o` and `G`.
+            let flags = self.current_instr.flags.clone();
+            if flags.contains('M') {
+                let Some(entsize) = args.next() else {
+                    bail!(loc, "entity size for SHF_MERGE not specified");
+                };
+                self.current_instr.entsize = match self.eval_abs_expr(entsize, loc)? {
+                    entsize @ 1.. => entsize as usize,
+                    entsize => bail!(loc, "entity size must be positive. Got {entsize}"),
+                };
+            }
+            if flags.contains('o') {
+                let Some(Expr::Ident(symbol)) = args.next() else {
+                    bail!(
+                        loc,
+                        "expected the symbol which SHF_LINK_ORDER section links to"
+                    );
+                };
+                self.current_instr.linked_to = Some(symbol);
+            }
+            if flags.contains('G') {
+                let Some(Expr::Ident(signature)) = args.next() else {
+                    bail!(loc, "group name for SHF_GROUP not specified");
+                };
+                let is_comdat = match args.next() {
+                    None => false,
+                    Some(Expr::Ident(linkage)) if linkage == "comdat" => true,
+                    Some(_) => bail!(loc, "expected `comdat` after the group name"),
+                };
+                self.current_instr.group = Some(SectionGroup {
+                    signature,
+                    is_comdat,
+                });
+            }
+        }
+
+        self.switch_section(section_name);
+        Ok(())
+    }
+
+    /// Make the current instruction a section switch.
+    ///
+    /// Without flags, the conventional flags of the section name are used. e.g. `.data` => "aw"
+    fn switch_section(&mut self, section_name: String) {
+        self.current_section_name = section_name.clone();
+        self.current_instr.kind = InstrKind::Section;
+        self.current_instr.section_name = section_name;
+    }
+
+    /// Place a relocation entry by hand.
+    ///
+    /// e.g. `.reloc offset, R_X86_64_64, symbol + addend`
+    fn encode_reloc(&mut self, index: &mut usize, tokens: &[Token], line: usize) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let (offset, rtype, target) = match parse_expr_list(index, tokens, line)?.as_slice() {
+            [offset, Expr::Ident(rtype), target @ ..] if target.len() <= 1 => {
+                (offset.clone(), rtype.clone(), target.first().cloned())
+            }
+            _ => bail!(loc, "`.reloc` expects `offset, type[, symbol + addend]`"),
+        };
+
+        let offset = self.eval_abs_expr(offset, loc)?;
+        if offset < 0 {
+            bail!(loc, "`.reloc` offset must not be negative. Got {offset}");
+        }
+        let rtype = match reloc_type_by_name(&rtype) {
+            Some(rtype) => rtype,
+            None => bail!(loc, "unknown relocation type `{rtype}`"),
+        };
+
+        let (uses, addend) = match target {
+            Some(target) => {
+                let mut used_symbols = Vec::new();
+                let addend = self.eval_expr_with_symbols(target, &mut used_symbols)?;
+                match used_symbols.as_slice() {
+                    [] => (String::new(), addend),
+                    [symbol] => (symbol.clone(), addend),
+                    _ => bail!(loc, "`.reloc` target must refer to at most one symbol"),
+                }
+            }
+            None => (String::new(), 0),
+        };
+
+        self.rela_text_users.push(Rela {
+            uses,
+            instr: core::mem::take(&mut self.current_instr),
+            offset: offset as usize,
+            rtype,
+            addend: Some(addend),
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// Record the binding or visibility of symbols. e.g. `.globl main`, `.weak a, b`
+    ///
+    /// They are applied after all labels are defined, so they may precede the definitions.
+    fn encode_symbol_attribute(
+        &mut self,
+        directive: &str,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let kind = match directive {
+            "globl" | "global" => InstrKind::Global,
+            "local" => InstrKind::Local,
+            "weak" => InstrKind::Weak,
+            "hidden" => InstrKind::Hidden,
+            "internal" => InstrKind::Internal,
+            _ => InstrKind::Protected,
+        };
+
+        let symbols = parse_expr_list(index, tokens, line)?;
+        if symbols.is_empty() {
+            bail!(loc, "`.{directive}` expects symbol names");
+        }
+        for symbol in symbols {
+            let Expr::Ident(symbol_name) = symbol else {
+                bail!(loc, "`.{directive}` expects symbol names. Got {symbol:?}");
+            };
+            self.instrs.push(Instr {
+                kind: kind.clone(),
+                loc,
+                section_name: self.current_section_name.to_string(),
+                symbol_name,
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Reserve a local symbol in `.bss`.
+    ///
+    /// e.g. `.lcomm symbol, size`
+    fn encode_lcomm(&mut self, index: &mut usize, tokens: &[Token], line: usize) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let (symbol_name, size) = match parse_expr_list(index, tokens, line)?.as_slice() {
+            [Expr::Ident(symbol_name), size] => {
+                (symbol_name.clone(), self.eval_abs_expr(size.clone(), loc)?)
+            }
+            _ => bail!(loc, "`.lcomm` expects `symbol, size`"),
+        };
+        if size < 0 {
+            bail!(loc, "`.lcomm` size must not be negative. Got {size}");
+        }
+
+        // Aligned by the size as GNU as does. e.g. 8 bytes or more to 8
+        let align = match size {
+            8.. => 8,
+            4.. => 4,
+            2.. => 2,
+            _ => 1,
+        };
+        self.instrs.push(Instr {
+            kind: InstrKind::Align,
+            align,
+            section_name: ".bss".to_owned(),
+            loc,
+            ..Default::default()
+        });
+        self.define_symbol(Instr {
+            kind: InstrKind::Label,
+            loc,
+            section_name: ".bss".to_owned(),
+            symbol_name,
+            symbol_type: STT_OBJECT,
+            size: size as usize,
+            ..Default::default()
+        })?;
+
+        self.current_instr.kind = InstrKind::Skip;
+        self.current_instr.section_name = ".bss".to_owned();
+        self.current_instr.reserved = size as usize;
+        Ok(())
+    }
+
+    /// Encode data directives. e.g. `.byte 1, 2`, `.quad 8`
+    ///
+    /// - `size`: the number of bytes per operand.
+    fn encode_data(
+        &mut self,
+        kind: InstrKind,
+        size: usize,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        self.current_instr.kind = kind;
+        if self.options.natural_align && size > 1 {
+            self.insert_natural_align(size);
+        }
+
+        let loc = self.current_instr.loc;
+        for expr in parse_expr_list(index, tokens, line)? {
+            // A string is expanded to its bytes. e.g. `.byte "AB"`
+            if let Expr::Str(string) = &expr {
+                match (string.as_bytes(), size) {
+                    (bytes, 1) => self.current_instr.code.extend_from_slice(bytes),
+                    (&[byte], _) => self.push_data(byte.into(), size)?,
+                    _ => bail!(
+                        loc,
+                        "string \"{string}\" is ambiguous in a {size} bytes data directive"
+                    ),
+                }
+                continue;
+            }
+
+            // `sym - .` is the offset from the data slot to `sym`. e.g. `.long sym - . + 4`
+            let (expr, is_pc_relative) = take_minus_dot(expr);
+            let modifier = relocation_modifier(&expr).map(str::to_owned);
+
+            let mut used_symbols = Vec::new();
+            let value = self.eval_expr_with_symbols(expr, &mut used_symbols)?;
+            match (used_symbols.as_slice(), is_pc_relative) {
+                ([], false) => self.push_data(value, size)?,
+                // The constant part is the addend. e.g. `16` of `.quad foo + 16`
+                ([symbol], _) => {
+                    // `.` is the place of this data slot. e.g. the second `.` of `.quad ., .`
+                    let offset = self.current_instr.code.len();
+                    let value = match &self.current_dot_label {
+                        Some(dot_label) if dot_label == symbol => value + offset as i64,
+                        _ => value,
+                    };
+                    let rtype = match (modifier.as_deref(), size, is_pc_relative) {
+                        (None, 1, false) => R_X86_64_8,
+                        (None, 2, false) => R_X86_64_16,
+                        (None, 4, false) => R_X86_64_32,
+                        (None, _, false) => R_X86_64_64,
+                        (None, 1, true) => R_X86_64_PC8,
+                        (None, 2, true) => R_X86_64_PC16,
+                        (None, 4, true) => R_X86_64_PC32,
+                        (None, _, true) => R_X86_64_PC64,
+                        (Some(modifier), _, false) => match data_reloc_type(modifier, size) {
+                            Some(rtype) => rtype,
+                            None => bail!(
+                                loc,
+                                "relocation modifier `@{modifier}` is not supported in {size} bytes data"
+                            ),
+                        },
+                        (Some(modifier), _, true) => {
+                            bail!(loc, "`@{modifier}` cannot be used with `- .`")
+                        }
+                    };
+                    self.current_instr.code.resize(offset + size, 0);
+                    self.rela_text_users.push(Rela {
+                        uses: symbol.clone(),
+                        instr: self.current_instr.clone(),
+                        offset,
+                        rtype,
+                        addend: Some(value),
+                        instr_idx: Some(self.instrs.len()),
+                        ..Default::default()
+                    });
+                }
+                ([], true) => bail!(loc, "`- .` expects a symbol. e.g. `.long sym - .`"),
+                ([first, second, ..], _) => bail!(
+                    loc,
+                    "data directive must refer to at most one symbol. Got `{first}` and `{second}`"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pad the section to `size` bytes before a data directive, as if `.balign size` was written.
+    ///
+    /// The padding goes before the labels just defined, so that they point to the aligned data.
+    /// e.g. `foo: .quad 1`
+    fn insert_natural_align(&mut self, size: usize) {
+        let at = self
+            .instrs
+            .iter()
+            .rposition(|instr| instr.kind != InstrKind::Label)
+            .map_or(0, |idx| idx + 1);
+        let align = Instr {
+            kind: InstrKind::Align,
+            align: size,
+            section_name: self.current_section_name.to_string(),
+            loc: self.current_instr.loc,
+            ..Default::default()
+        };
+        self.instrs.insert(at, align);
+    }
+
+    /// e.g. `.ascii "abc"`, `.asciz "hello\n", "world"`
+    ///
+    /// - `nul_terminated`: append NUL to each string. (`.asciz` and `.string`)
+    fn encode_string(
+        &mut self,
+        nul_terminated: bool,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.current_instr.kind = InstrKind::String;
+
+        for expr in parse_expr_list(index, tokens, line)? {
+            let Expr::Str(string) = expr else {
+                bail!(loc, "expected a string literal. e.g. \"hello\"");
+            };
+            self.current_instr.code.extend_from_slice(string.as_bytes());
+            if nul_terminated {
+                self.current_instr.code.push(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// e.g. `.string16 "abc"` in UTF-16LE, `.string32 "abc"` in UTF-32LE
+    ///
+    /// Each string is terminated by a NUL of the `width` bytes.
+    fn encode_wide_string(
+        &mut self,
+        width: usize,
+        index: &mut usize,
+        tokens: &[Token],
+        line: usize,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.current_instr.kind = InstrKind::String;
+
+        for expr in parse_expr_list(index, tokens, line)? {
+            let Expr::Str(string) = expr else {
+                bail!(loc, "expected a string literal. e.g. \"hello\"");
+            };
+            let code = &mut self.current_instr.code;
+            match width {
+                // The characters out of BMP are encoded in the surrogate pairs.
+                2 => string
+                    .encode_utf16()
+                    .chain([0])
+                    .for_each(|unit| code.extend_from_slice(&unit.to_le_bytes())),
+                _ => string
+                    .chars()
+                    .map(u32::from)
+                    .chain([0])
+                    .for_each(|unit| code.extend_from_slice(&unit.to_le_bytes())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the little endian `size` bytes of `value`, and warn if it does not fit.
+    ///
+    /// Both of signed and unsigned values are accepted. e.g. `.byte -1`, `.byte 255`
+    fn push_truncated(&mut self, value: i64, size: usize) {
+        let bytes = &value.to_le_bytes()[..size];
+        if !fits_in(value, size) {
+            let mut truncated = [0; 8];
+            truncated[..size].copy_from_slice(bytes);
+            let truncated = u64::from_le_bytes(truncated);
+            self.warn(
+                self.current_instr.loc,
+                format!("value {value:#x} truncated to {truncated:#x}"),
+            );
+        }
+        self.current_instr.code.extend_from_slice(bytes);
+    }
+
+    /// Append the two's complement `value` of a data directive as the little endian `size` bytes.
+    ///
+    /// Unlike immediates, a value which does not fit is an error. e.g. `.byte 256`
+    fn push_data(&mut self, value: i64, size: usize) -> Result<()> {
+        if !fits_in(value, size) {
+            let bits = size * 8;
+            bail!(
+                self.current_instr.loc,
+                "value {value} is out of range for {size} bytes data. It must be in {}..={}",
+                -(1i64 << (bits - 1)),
+                (1i64 << bits) - 1
+            );
+        }
+        self.current_instr
+            .code
+            .extend_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+}
+
+/// Remove a `- .` term from the additive chain of `expr`, and tell whether it was found.
+///
+/// e.g. `foo - . + 4` => (`foo + 4`, true)
+fn take_minus_dot(expr: Expr) -> (Expr, bool) {
+    match expr {
+        Expr::Binop {
+            left_hs,
+            right_hs,
+            op: TokenKind::Minus,
+        } if *right_hs == Expr::Ident(".".to_owned()) => (*left_hs, true),
+        Expr::Binop {
+            left_hs,
+            right_hs,
+            op: op @ (TokenKind::Plus | TokenKind::Minus),
+        } => {
+            let (left_hs, found) = take_minus_dot(*left_hs);
+            // The right term of `-` is negated, so `- .` in it is not the offset from the slot.
+            let (right_hs, found) = match (found, &op) {
+                (false, TokenKind::Plus) => take_minus_dot(*right_hs),
+                _ => (*right_hs, found),
+            };
+            let expr = Expr::Binop {
+                left_hs: Box::new(left_hs),
+                right_hs: Box::new(right_hs),
+                op,
+            };
+            (expr, found)
+        }
+        expr => (expr, false),
+    }
+}
+
+/// Relocation type of the data with a modifier. e.g. `.long foo@PC32`, `.long foo@PC`, `.long x@dtpoff`
+///
+/// `None` if the modifier has no relocation of the data size.
+fn data_reloc_type(modifier: &str, size: usize) -> Option<u64> {
+    let modifier = modifier.to_ascii_uppercase();
+    let bits = (size * 8).to_string();
+    let name = match modifier.as_str() {
+        // The width is taken from the data. e.g. `R_X86_64_PLT32` for `.long foo@PLT`
+        "PC" | "PLT" | "DTPOFF" | "TPOFF" => format!("R_X86_64_{modifier}{bits}"),
+        "GOTPCREL" if size == 4 => "R_X86_64_GOTPCREL".to_owned(),
+        // The width must match the data. e.g. `PC32` for `.long`
+        _ if modifier.ends_with(&bits) => format!("R_X86_64_{modifier}"),
+        _ => return None,
+    };
+    reloc_type_by_name(&name)
+}
+
+/// Does `value` fit in `size` bytes as either signed or unsigned? e.g. -128..=255 for a byte
+fn fits_in(value: i64, size: usize) -> bool {
+    size >= 8 || (-(1 << (size * 8 - 1))..1 << (size * 8)).contains(&value)
+}
+
+/// Parse with the default encoder.
+#[cfg(test)]
+pub(crate) fn parse(tokens: Vec<Token>) -> Result<Encoder> {
+    parse_with(Encoder::default(), tokens)
+}
+
+/// Bytes of the last instruction encoded from `src`. e.g. `code("syscall")`
+#[cfg(test)]
+pub(crate) fn code(src: &str) -> Result<Vec<u8>> {
+    let encoder = parse(crate::lexer::tokenize(src)?)?;
+    Ok(encoder
+        .instrs
+        .last()
+        .map(|instr| instr.code.clone())
+        .unwrap_or_default())
+}
+
+/// Parse with a configured encoder. e.g. [`AssembleOptions::relax_relocations`]
+#[cfg(test)]
+pub(crate) fn parse_with(mut encoder: Encoder, tokens: Vec<Token>) -> Result<Encoder> {
+    encoder.tokens = tokens;
+    encoder.token_idx = 0;
+    while encoder.encode_next()? {}
+    encoder.check_conditionals_closed()?;
+    Ok(encoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_accept_trailing_comma_in_data_directive() -> Result<()> {
+        let encoder = parse(tokenize(".byte 1,2,\n.byte 3\n")?)?;
+        assert_eq!(vec![1, 2], encoder.instrs[0].code);
+        assert_eq!(vec![3], encoder.instrs[1].code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_one_statement_at_a_time() -> Result<()> {
+        let mut encoder = Encoder {
+            tokens: tokenize("movq %rax, %rbx\nnop\n")?,
+            ..Default::default()
+        };
+        assert!(encoder.encode_next()?);
+        assert_eq!(&[0x48, 0x89, 0xc3], encoder.last_code());
+        assert_eq!(1, encoder.instrs.len());
+
+        assert!(encoder.encode_next()?);
+        assert_eq!(&[0x90], encoder.last_code());
+        assert!(!encoder.encode_next()?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_define_label_on_same_line_as_instruction() -> Result<()> {
+        let mut encoder = parse(tokenize("syscall\nfoo: nop\nbar: baz: syscall\n")?)?;
+        encoder.assign_addresses()?;
+        assert_eq!(
+            vec![0x0f, 0x05, 0x90, 0x0f, 0x05],
+            encoder.user_defined_sections[".text"].code
+        );
+        let addr = |name: &str| encoder.user_defined_symbols[name].addr;
+        assert_eq!((2, 3, 3), (addr("foo"), addr("bar"), addr("baz")));
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_mask_decoration() -> Result<()> {
+        let Expr::Masked {
+            operand,
+            mask,
+            zeroing,
+        } = parse_operand(&mut 0, &tokenize("%zmm1{%k1}{z}")?)?
+        else {
+            panic!("expected a masked operand");
+        };
+        assert_eq!(Expr::Zmm(get_zmm_by("ZMM1")?), *operand);
+        assert_eq!(get_opmask_by("K1")?, mask);
+        assert!(zeroing);
+
+        assert!(matches!(
+            parse_operand(&mut 0, &tokenize("(%rax){k2}")?)?,
+            Expr::Masked { mask, zeroing: false, .. } if mask.base_offset == 2
+        ));
+        assert!(parse_operand(&mut 0, &tokenize("%zmm1{%k0}")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_operand_count_before_parsing() -> Result<()> {
+        let message = |src: &str| -> Result<String> {
+            let err = parse(tokenize(src)?).unwrap_err();
+            assert_eq!(ErrorKind::Parse, err.kind);
+            assert_eq!(Some(1), err.location.map(|loc| loc.line));
+            Ok(err.message)
+        };
+        assert_eq!(
+            "instruction `movq` expects 2 operands, but got 1",
+            message("nop\nmovq %rax\nnop\n")?
+        );
+        assert_eq!(
+            "instruction `movq` expects 2 operands, but got 3",
+            message("nop\nmovq %rax, %rbx, %rcx\n")?
+        );
+        assert_eq!(
+            "instruction `pushq` expects 1 operand, but got 0",
+            message("nop\npushq\n")?
+        );
+        assert_eq!(
+            "instruction `syscall` expects 0 operands, but got 1",
+            message("nop\nsyscall %rax\n")?
+        );
+        assert_eq!(
+            "instruction `xaddq` expects 2 operands, but got 1",
+            message("nop\nlock xaddq %rax\n")?
+        );
+
+        // The commas in a memory operand do not separate operands.
+        assert!(parse(tokenize("nopl 8(%rax, %rcx, 4)\nmovq (,%rax,8), %rbx\n")?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_explicit_prefix() -> Result<()> {
+        let code =
+            |src: &str| -> Result<Vec<u8>> { Ok(parse(tokenize(src)?)?.instrs[0].code.clone()) };
+        assert_eq!(vec![0x66, 0x90], code("data16 nop")?);
+        assert_eq!(vec![0x48, 0x90], code("rex.w nop")?);
+        assert_eq!(vec![0x49, 0x90], code("rex.WB nop")?);
+        assert_eq!(vec![0x48, 0x89, 0xc3], code("rex.w movq %rax, %rbx")?);
+        assert_eq!(vec![0x66, 0x89, 0xc3], code("data16 movw %ax, %bx")?);
+
+        let err = parse(tokenize("rex.b movq %rax, %rbx")?).unwrap_err();
+        assert_eq!(
+            "`rex` prefix conflicts with the REX prefix `movq` needs",
+            err.message
+        );
+        assert!(parse(tokenize("rex.bw nop")?).is_err());
+        assert!(parse(tokenize("rex.w data16 nop")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_expand_string_in_data_directive() -> Result<()> {
+        let encoder = parse(tokenize(".byte \"AB\", 0, 'C'\n.long \"\\n\"\n")?)?;
+        assert_eq!(vec![0x41, 0x42, 0x00, 0x43], encoder.instrs[0].code);
+        assert_eq!(vec![0x0a, 0x00, 0x00, 0x00], encoder.instrs[1].code);
+
+        let err = parse(tokenize(".word \"AB\"\n")?).unwrap_err();
+        assert_eq!(
+            "string \"AB\" is ambiguous in a 2 bytes data directive",
+            err.message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_wide_string() -> Result<()> {
+        let encoder = parse(tokenize(".string16 \"Hi\"\n.string32 \"Hi\"\n")?)?;
+        assert_eq!(vec![b'H', 0, b'i', 0, 0, 0], encoder.instrs[0].code);
+        assert_eq!(
+            vec![b'H', 0, 0, 0, b'i', 0, 0, 0, 0, 0, 0, 0],
+            encoder.instrs[1].code
+        );
+
+        // U+1F600 is out of BMP, so it is a surrogate pair in UTF-16.
+        let encoder = parse(tokenize(
+            ".string16 \"\u{1F600}\"\n.string32 \"\u{1F600}\"\n",
+        )?)?;
+        assert_eq!(
+            vec![0x3d, 0xd8, 0x00, 0xde, 0x00, 0x00],
+            encoder.instrs[0].code
+        );
+        assert_eq!(
+            vec![0x00, 0xf6, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            encoder.instrs[1].code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_repeat_value_by_fill() -> Result<()> {
+        assert_eq!(
+            vec![0xcd, 0xab, 0xcd, 0xab, 0xcd, 0xab, 0xcd, 0xab],
+            code(".fill 4, 2, 0xabcd")?
+        );
+        assert_eq!(vec![0, 0, 0], code(".fill 3")?);
+        // Only the low 4 bytes of the value are taken.
+        assert_eq!(
+            vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0],
+            code(".fill 1, 8, -1")?
+        );
+
+        assert!(parse(tokenize(".fill 1, 9, 0\n")?).is_err());
+        assert!(parse(tokenize(".fill 1, 0\n")?).is_err());
+        assert!(parse(tokenize(".bss\n.fill 4, 4\n")?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_unknown_directive_in_strict_mode() -> Result<()> {
+        let encoder = parse(tokenize(".gloabl foo\nfoo:\n")?)?;
+        assert_eq!(
+            "unknown directive `.gloabl` is ignored",
+            encoder.warnings[0].message
+        );
+        assert!(parse(tokenize(".file \"a.c\"\n.cfi_startproc\n")?)?
+            .warnings
+            .is_empty());
+
+        let mut encoder = Encoder::default();
+        encoder.options.strict = true;
+        let err = parse_with(encoder, tokenize("nop\n.gloabl foo\nfoo:\n")?).unwrap_err();
+        assert_eq!("unknown directive `.gloabl`", err.message);
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_data_in_nobits_section() -> Result<()> {
+        let err = parse(tokenize(".bss\n.byte 1\n")?).unwrap_err();
+        assert_eq!("cannot emit data into a nobits section `.bss`", err.message);
+        assert!(parse(tokenize(".section .bss.foo\nnop\n")?).is_err());
+        assert!(parse(tokenize(".bss\n.skip 4, 1\n")?).is_err());
+
+        assert!(parse(tokenize(".bss\n.p2align 3, 0x90\n")?).is_err());
+
+        assert!(parse(tokenize(".bss\nbuf:\n.skip 4\n.zero 4\n.p2align 3\n")?).is_ok());
+        assert!(parse(tokenize(".bss\nfoo: .skip 4\n.code64\n")?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_encoded_len() -> Result<()> {
+        let encoder = parse(tokenize("movq %rax, %rbx")?)?;
+        assert_eq!(vec![0x48, 0x89, 0xc3], encoder.instrs[0].code);
+        assert_eq!(3, encoder.instrs[0].encoded_len());
+
+        let jmp = Instr {
+            kind: InstrKind::Jmp,
+            code: vec![0xeb, 0x00],
+            is_jmp_or_call: true,
+            ..Default::default()
+        };
+        assert_eq!(5, jmp.encoded_len());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_mov_between_gpr_and_xmm() -> Result<()> {
+        assert_eq!(
+            vec![0x66, 0x48, 0x0f, 0x6e, 0xc0],
+            code("movq %rax, %xmm0")?
+        );
+        assert_eq!(vec![0x66, 0x0f, 0x7e, 0xc9], code("movd %xmm1, %ecx")?);
+        assert_eq!(
+            vec![0x66, 0x4c, 0x0f, 0x7e, 0xc8],
+            code("movq %xmm9, %rax")?
+        );
+        assert!(parse(tokenize("movd %xmm1, %rcx")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_high_byte_register_with_rex() -> Result<()> {
+        let err = parse(tokenize("nop\nmovb %ah,%r8b\n")?).unwrap_err();
+        assert_eq!(
+            "`%ah` cannot be used in an instruction requiring a REX prefix",
+            err.message
+        );
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+        assert!(parse(tokenize("movb %bh,%sil\n")?).is_err());
+        assert!(parse(tokenize("movb %ch,(%r8)\n")?).is_err());
+
+        let encoder = parse(tokenize("movb %ah,%al\n")?)?;
+        assert_eq!(vec![0x88, 0xe0], encoder.instrs[0].code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_toggle_default_sizes_by_code_mode() -> Result<()> {
+        // The statement after the mode directive
+        assert_eq!(vec![0x89, 0xc3], code(".code64\nmov %eax, %ebx")?);
+        assert_eq!(vec![0x66, 0x89, 0xc3], code(".code16\nmov %eax, %ebx")?);
+        assert_eq!(vec![0x66, 0x89, 0xc3], code(".code64\nmov %ax, %bx")?);
+        assert_eq!(vec![0x89, 0xc3], code(".code16\nmov %ax, %bx")?);
+        assert_eq!(vec![0x67, 0x89, 0x03], code(".code64\nmov %eax, (%ebx)")?);
+        assert_eq!(
+            vec![0x67, 0x66, 0x89, 0x03],
+            code(".code16\nmov %eax, (%ebx)")?
+        );
+        assert_eq!(vec![0x89, 0x03], code(".code32\nmov %eax, (%ebx)")?);
+
+        assert!(parse(tokenize(".code16\nmov %rax, %rbx")?).is_err());
+        assert!(parse(tokenize(".code32\nmov %r8d, %eax")?).is_err());
+        assert!(parse(tokenize(".code16\nmov (%rax), %eax")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_symbol_in_mov_immediate() -> Result<()> {
+        let encoder = parse(tokenize("movl $msg, %esi")?)?;
+        assert_eq!(vec![0xbe, 0, 0, 0, 0], encoder.instrs[0].code);
+
+        let rela = &encoder.rela_text_users[0];
+        assert_eq!("msg", rela.uses);
+        assert_eq!(1, rela.offset);
+        assert_eq!(R_X86_64_32, rela.rtype);
+
+        let encoder = parse(tokenize("movq $msg+8, %r9")?)?;
+        assert_eq!(vec![0x49, 0xc7, 0xc1, 0, 0, 0, 0], encoder.instrs[0].code);
+        assert_eq!(R_X86_64_32S, encoder.rela_text_users[0].rtype);
+        assert_eq!(8, encoder.rela_text_users[0].adjust);
+        Ok(())
+    }
+
+    #[test]
+    fn should_relocate_immediate_by_modifier() -> Result<()> {
+        let encoder = parse(tokenize("movq $var@tpoff, %rax")?)?;
+        assert_eq!(vec![0x48, 0xc7, 0xc0, 0, 0, 0, 0], encoder.instrs[0].code);
+        assert_eq!("var", encoder.rela_text_users[0].uses);
+        assert_eq!(3, encoder.rela_text_users[0].offset);
+        assert_eq!(R_X86_64_TPOFF32, encoder.rela_text_users[0].rtype);
+
+        // A symbol takes the full size immediate, even in the short form of the accumulator.
+        let encoder = parse(tokenize("addq $var@tpoff, %rax\naddq $var, 8(%rsp)")?)?;
+        assert_eq!(vec![0x48, 0x05, 0, 0, 0, 0], encoder.instrs[0].code);
+        assert_eq!(R_X86_64_TPOFF32, encoder.rela_text_users[0].rtype);
+        assert_eq!(
+            vec![0x48, 0x81, 0x44, 0x24, 0x08, 0, 0, 0, 0],
+            encoder.instrs[1].code
+        );
+        assert_eq!(5, encoder.rela_text_users[1].offset);
+        assert_eq!(R_X86_64_32S, encoder.rela_text_users[1].rtype);
+
+        let encoder = parse(tokenize("push $var@dtpoff")?)?;
+        assert_eq!(vec![0x68, 0, 0, 0, 0], encoder.instrs[0].code);
+        assert_eq!(R_X86_64_DTPOFF32, encoder.rela_text_users[0].rtype);
+
+        // The modifiers are never dropped silently.
+        assert!(parse(tokenize("movq $var@GOTPCREL, %rax")?).is_err());
+        assert!(parse(tokenize("movl $var@tpoff, %ecx")?).is_err());
+        assert!(parse(tokenize("addb $var@tpoff, %al")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_sign_extend_accumulator() -> Result<()> {
+        assert_eq!(vec![0x66, 0x98], code("cbw")?);
+        assert_eq!(vec![0x98], code("cwde")?);
+        assert_eq!(vec![0x48, 0x98], code("cdqe")?);
+        assert_eq!(vec![0x66, 0x99], code("cwd")?);
+        assert_eq!(vec![0x99], code("cdq")?);
+        assert_eq!(vec![0x48, 0x99], code("cqo")?);
+        assert_eq!(code("cqo")?, code("cqto")?);
+        assert_eq!(code("cdqe")?, code("cltq")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_system_call_and_return() -> Result<()> {
+        assert_eq!(vec![0x0f, 0x05], code("syscall")?);
+        assert_eq!(vec![0x0f, 0x07], code("sysretl")?);
+        assert_eq!(vec![0x48, 0x0f, 0x07], code("sysretq")?);
+        assert_eq!(vec![0x0f, 0x34], code("sysenter")?);
+        assert_eq!(vec![0x0f, 0x35], code("sysexitl")?);
+        assert_eq!(vec![0x48, 0x0f, 0x35], code("sysexitq")?);
+        assert_eq!(vec![0x0f, 0x01, 0xf8], code("swapgs")?);
+
+        // Without the suffix, it returns to 32bit mode as GNU as does.
+        let encoder = parse(tokenize("sysret")?)?;
+        assert_eq!(vec![0x0f, 0x07], encoder.instrs[0].code);
+        assert_eq!(1, encoder.warnings.len());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_counter_and_cpuid() -> Result<()> {
+        assert_eq!(vec![0x0f, 0x31], code("rdtsc")?);
+        assert_eq!(vec![0x0f, 0x01, 0xf9], code("rdtscp")?);
+        assert_eq!(vec![0x0f, 0x33], code("rdpmc")?);
+        assert_eq!(vec![0x0f, 0xa2], code("cpuid")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_endbr() -> Result<()> {
+        assert_eq!(vec![0xf3, 0x0f, 0x1e, 0xfa], code("endbr64")?);
+        assert_eq!(vec![0xf3, 0x0f, 0x1e, 0xfb], code("endbr32")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_rip_relative_lea() -> Result<()> {
+        let encoder = parse(tokenize("nop\nlea msg(%rip), %rsi")?)?;
+        assert_eq!(vec![0x48, 0x8d, 0x35, 0, 0, 0, 0], encoder.instrs[1].code);
+
+        let rela = &encoder.rela_text_users[0];
+        assert_eq!("msg", rela.uses);
+        assert_eq!(3, rela.offset);
+        assert_eq!(R_X86_64_PC32, rela.rtype);
+        assert_eq!(Some(1), rela.instr_idx);
+        Ok(())
+    }
+
+    #[test]
+    fn should_name_unresolved_name_of_constant() -> Result<()> {
+        let err = parse(tokenize("nop\n.equ A, B + 1\n")?).unwrap_err();
+        assert_eq!("constant `A` refers to undefined `B`", err.message);
+        assert_eq!(1, err.location.unwrap().line);
+
+        let err = parse(tokenize("B: nop\n.set A, B + 1\n")?).unwrap_err();
+        assert_eq!(
+            "constant `A` cannot refer to the symbol `B` defined at 1:1",
+            err.message
+        );
+
+        assert!(parse(tokenize(".equ B, 2\n.equ A, B * 2\n")?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_arithmetic_errors_at_their_line() -> Result<()> {
+        let err = parse(tokenize("nop\nmovq $(1/0), %rax\n")?).unwrap_err();
+        assert_eq!("division by zero. `1 / 0`", err.message);
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+
+        let err = parse(tokenize("movq $(9223372036854775807 * 2), %rax\n")?).unwrap_err();
+        assert_eq!(
+            "overflow in the expression `9223372036854775807 * 2`",
+            err.message
+        );
+        assert!(parse(tokenize(
+            ".equ MIN, -9223372036854775807 - 1\n.quad MIN / -1\n"
+        )?)
+        .is_err());
+
+        let encoder = parse(tokenize(".byte (1 + 2) * 3\n")?)?;
+        assert_eq!(vec![9], encoder.instrs[0].code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_equiv_redefinition() -> Result<()> {
+        let err = parse(tokenize(".equiv A, 1\n.equiv A, 2\n")?).unwrap_err();
+        assert_eq!(
+            Some(Location {
+                line: 1,
+                column: 0,
+                offset: 12
+            }),
+            err.location
+        );
+        assert_eq!("`A` is already defined at 1:1", err.message);
+
+        let err = parse(tokenize("A: nop\n.equiv A, 2\n")?).unwrap_err();
+        assert_eq!("`A` is already defined at 1:1", err.message);
+
+        let encoder = parse(tokenize(".equiv A, 1\n.byte A + 1, A * 3\n")?)?;
+        assert_eq!(vec![2, 3], encoder.instrs[0].code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_warn_truncated_imm() -> Result<()> {
+        let encoder = parse(tokenize("movb $255, %al\nmovb $0x100, %al\n")?)?;
+        assert_eq!(
+            vec![Warning {
+                message: "value 0x100 truncated to 0x0".to_owned(),
+                location: Some(Location {
+                    line: 1,
+                    column: 0,
+                    offset: 15
+                }),
+            }],
+            encoder.warnings
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_data_in_twos_complement() -> Result<()> {
+        let encoder = parse(tokenize(
+            ".byte -1, 255, -128\n.word -1, 0xffff\n.long -2\n",
+        )?)?;
+        assert_eq!(vec![0xff, 0xff, 0x80], encoder.instrs[0].code);
+        assert_eq!(vec![0xff, 0xff, 0xff, 0xff], encoder.instrs[1].code);
+        assert_eq!(vec![0xfe, 0xff, 0xff, 0xff], encoder.instrs[2].code);
+        assert!(encoder.warnings.is_empty());
+
+        let err = parse(tokenize(".byte 256\n")?).unwrap_err();
+        assert_eq!(
+            "value 256 is out of range for 1 bytes data. It must be in -128..=255",
+            err.message
+        );
+        let err = parse(tokenize(".byte -129\n")?).unwrap_err();
+        assert_eq!(
+            "value -129 is out of range for 1 bytes data. It must be in -128..=255",
+            err.message
+        );
+        let err = parse(tokenize(".word 0x10000\n")?).unwrap_err();
+        assert_eq!(
+            "value 65536 is out of range for 2 bytes data. It must be in -32768..=65535",
+            err.message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_select_conditional_block() -> Result<()> {
+        let src = r#"
+foo:
+.ifdef foo
+    .byte 1
+.else
+    .byte 2
+.endif
+.ifdef bar
+    .byte 3
+.else
+    .byte 4
+.endif
+.ifndef bar
+    .byte 5
+.endif
+"#;
+        let encoder = parse(tokenize(src)?)?;
+        let code: Vec<u8> = encoder.instrs.iter().flat_map(|i| i.code.clone()).collect();
+        assert_eq!(vec![1, 4, 5], code);
+        Ok(())
+    }
+
+    #[test]
+    fn should_select_nested_conditional_block() -> Result<()> {
+        let src = r#"
+.ifdef undefined
+    .ifdef foo
+        .byte 1
+    .else
+        .byte 2
+    .endif
+.else
+    .if 1
+        .ifndef foo
+            .byte 3
+        .else
+            .byte 4
+        .endif
+    .endif
+    .byte 5
+.endif
+"#;
+        let encoder = parse(tokenize(src)?)?;
+        let code: Vec<u8> = encoder.instrs.iter().flat_map(|i| i.code.clone()).collect();
+        assert_eq!(vec![3, 5], code);
+
+        let err = parse(tokenize("nop\n  .if 0\nnop\n")?).unwrap_err();
+        assert_eq!(
+            Some(Location {
+                line: 1,
+                column: 2,
+                offset: 6
+            }),
+            err.location
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_register_with_original_spelling() -> Result<()> {
+        let tokens = tokenize("  %raxx")?;
+        let err = parse_register(&mut 0, &tokens).unwrap_err();
+        assert_eq!("1:4: unknown register `%raxx`", err.to_string());
+
+        assert_eq!(
+            Expr::Register(get_reg_info_by("RAX")?),
+            parse_register(&mut 0, &tokenize("%Rax")?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_empty_operand_in_data_directive() -> Result<()> {
+        let err = parse(tokenize(".byte 1,,2")?).unwrap_err();
+        assert_eq!(
+            Some(Location {
+                line: 0,
+                column: 8,
+                offset: 8
+            }),
+            err.location
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_log_encoded_instruction() -> Result<()> {
+        use std::sync::Mutex;
+
+        struct CapturingLogger(Mutex<Vec<String>>);
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        parse(tokenize("cqto\n")?)?;
+
+        let messages = LOGGER.0.lock().unwrap();
+        assert!(messages
+            .iter()
+            .any(|message| message == "instruction encoded 1:1: .text: Cqto [48, 99]"));
+        Ok(())
+    }
 }
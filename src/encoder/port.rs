@@ -0,0 +1,123 @@
+use crate::encoder::alu::split_suffix;
+use crate::encoder::arch::x86_64::{
+    registers::{DataSizeSuffix, Register},
+    Expr,
+};
+use crate::encoder::{parse_two_operand, Encoder, InstrKind};
+use crate::error::Result;
+use crate::lexer::Token;
+
+/// Get `in` or `out` by mnemonic. e.g. `inb`
+pub(crate) fn port_op_by(mnemonic: &str) -> Option<(InstrKind, DataSizeSuffix)> {
+    let (base, size) = split_suffix(mnemonic, &["in", "out"])?;
+    match base {
+        "in" => Some((InstrKind::In, size)),
+        _ => Some((InstrKind::Out, size)),
+    }
+}
+
+impl Encoder {
+    /// e.g. `inb $0x60, %al`, `inw %dx, %ax`, `outb %al, $0x20`, `outl %eax, %dx`
+    ///
+    /// - IN AL, imm8: e4 ib / IN AX/EAX, imm8: e5 ib / IN AL, DX: ec / IN AX/EAX, DX: ed
+    /// - OUT imm8, AL: e6 ib / OUT imm8, AX/EAX: e7 ib / OUT DX, AL: ee / OUT DX, AX/EAX: ef
+    pub(crate) fn encode_port(
+        &mut self,
+        kind: InstrKind,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        let is_in = kind == InstrKind::In;
+        self.set_kind(kind)?;
+
+        let (port, acc) = match (is_in, parse_two_operand(index, tokens)?) {
+            (true, (port, Expr::Register(acc))) => (port, acc),
+            (false, (Expr::Register(acc), port)) => (port, acc),
+            _ => bail!(
+                loc,
+                "port I/O needs the accumulator(`%al`, `%ax` or `%eax`)"
+            ),
+        };
+        let size = self.accumulator_size(size, &acc)?;
+        // `in` and `out` differ only in bit 1 of the opcode.
+        let direction = if is_in { 0 } else { 0b10 };
+        let width = match size {
+            DataSizeSuffix::Byte => 0,
+            _ => 1,
+        };
+
+        if size == DataSizeSuffix::Word {
+            self.current_instr.code.push(0x66);
+        }
+        match port {
+            Expr::Immediate(port) => {
+                let port = self.eval_abs_expr(*port, loc)?;
+                let port = match u8::try_from(port) {
+                    Ok(port) => port,
+                    Err(_) => bail!(loc, "port number must be 0..=255. Got {port}"),
+                };
+                self.current_instr
+                    .code
+                    .extend_from_slice(&[0xe4 | direction | width, port]);
+            }
+            Expr::Register(reg) if reg.lit == "DX" => {
+                self.current_instr.code.push(0xec | direction | width)
+            }
+            _ => bail!(loc, "port must be an 8bit immediate or `%dx`"),
+        }
+        Ok(())
+    }
+
+    /// Validate that `reg` is the accumulator of the size. 64bit is not allowed.
+    fn accumulator_size(&self, size: DataSizeSuffix, reg: &Register) -> Result<DataSizeSuffix> {
+        let loc = self.current_instr.loc;
+        let size = match size {
+            DataSizeSuffix::Unknown => reg.size,
+            size => size,
+        };
+        let is_accumulator = reg.base_offset == 0 && !reg.rex_required;
+        if !is_accumulator
+            || reg.size != size
+            || !matches!(
+                size,
+                DataSizeSuffix::Byte | DataSizeSuffix::Word | DataSizeSuffix::Long
+            )
+        {
+            bail!(
+                loc,
+                "expected `%al`, `%ax` or `%eax` of the operand size. Got `{reg}`"
+            );
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::code;
+    use crate::error::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_encode_immediate_port() -> Result<()> {
+        assert_eq!(vec![0xe4, 0x60], code("inb $0x60, %al")?);
+        assert_eq!(vec![0x66, 0xe5, 0x60], code("inw $0x60, %ax")?);
+        assert_eq!(vec![0xe6, 0x20], code("outb %al, $0x20")?);
+        assert_eq!(vec![0xe7, 0x20], code("outl %eax, $0x20")?);
+        assert!(code("inb $0x100, %al").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_dx_port() -> Result<()> {
+        assert_eq!(vec![0x66, 0xed], code("inw %dx, %ax")?);
+        assert_eq!(vec![0xec], code("in %dx, %al")?);
+        assert_eq!(vec![0xee], code("outb %al, %dx")?);
+        assert_eq!(vec![0xef], code("outl %eax, %dx")?);
+        assert!(code("inb %dx, %bl").is_err());
+        assert!(code("inw %dx, %al").is_err());
+        Ok(())
+    }
+}
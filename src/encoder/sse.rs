@@ -0,0 +1,163 @@
+//! SSE instructions on xmm registers. e.g. `pxor %xmm0, %xmm0`, `movq (%rax), %xmm1`
+use crate::encoder::arch::x86_64::{
+    bin_const::{MOD_REGI, OPERAND_SIZE_PREFIX16},
+    registers::{DataSizeSuffix, Register},
+    Expr,
+};
+use crate::encoder::mem::Memory;
+use crate::encoder::{compose_mod_rm, parse_two_operand, Encoder, InstrKind};
+use crate::error::Result;
+use crate::lexer::Token;
+use crate::CpuFeature;
+
+/// Get the opcode of a packed integer instruction by mnemonic. e.g. `pxor` => (Pxor, 0xef)
+///
+/// The opcode follows `66 0f`.
+pub(crate) fn packed_int_op_by(mnemonic: &str) -> Option<(InstrKind, u8)> {
+    Some(match mnemonic {
+        "pand" => (InstrKind::Pand, 0xdb),
+        "por" => (InstrKind::Por, 0xeb),
+        "pxor" => (InstrKind::Pxor, 0xef),
+        _ => return None,
+    })
+}
+
+impl Encoder {
+    /// e.g. `pxor %xmm0, %xmm0`, `pand (%rax), %xmm1`
+    ///
+    /// - PAND xmm1, xmm2/m128: 66 0f db /r
+    /// - POR xmm1, xmm2/m128: 66 0f eb /r
+    /// - PXOR xmm1, xmm2/m128: 66 0f ef /r
+    pub(crate) fn encode_packed_int(
+        &mut self,
+        kind: InstrKind,
+        opcode: u8,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.set_kind(kind)?;
+        self.required_feature = Some(CpuFeature::Sse2);
+        let (src, dst) = parse_two_operand(index, tokens)?;
+        let Expr::Xmm(dst) = dst else {
+            bail!(
+                self.current_instr.loc,
+                "destination must be an xmm register"
+            );
+        };
+        self.encode_xmm_rm(&[OPERAND_SIZE_PREFIX16], opcode, dst, &src)
+    }
+
+    /// Move between xmm registers and memory. e.g. `movq %xmm1, %xmm2`, `movd (%rax), %xmm1`
+    ///
+    /// - `size`: `Long`(movd) or `Quad`(movq)
+    ///
+    /// - MOVD xmm, m32: 66 0f 6e /r
+    /// - MOVD m32, xmm: 66 0f 7e /r
+    /// - MOVQ xmm1, xmm2/m64: f3 0f 7e /r
+    /// - MOVQ m64, xmm: 66 0f d6 /r
+    pub(crate) fn encode_mov_xmm(
+        &mut self,
+        size: DataSizeSuffix,
+        src: Expr,
+        dst: Expr,
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.current_instr.kind = match size {
+            DataSizeSuffix::Quad => InstrKind::Mov,
+            _ => InstrKind::Movd,
+        };
+        self.required_feature = Some(CpuFeature::Sse2);
+        match (size, src, dst) {
+            (DataSizeSuffix::Quad, src, Expr::Xmm(dst)) => {
+                self.encode_xmm_rm(&[0xf3], 0x7e, dst, &src)
+            }
+            (DataSizeSuffix::Quad, Expr::Xmm(src), mem @ Expr::Indirection { .. }) => {
+                self.encode_xmm_rm(&[OPERAND_SIZE_PREFIX16], 0xd6, src, &mem)
+            }
+            (DataSizeSuffix::Long, mem @ Expr::Indirection { .. }, Expr::Xmm(dst)) => {
+                self.encode_xmm_rm(&[OPERAND_SIZE_PREFIX16], 0x6e, dst, &mem)
+            }
+            (DataSizeSuffix::Long, Expr::Xmm(src), mem @ Expr::Indirection { .. }) => {
+                self.encode_xmm_rm(&[OPERAND_SIZE_PREFIX16], 0x7e, src, &mem)
+            }
+            _ => bail!(loc, "unsupported operands for `movd`/`movq`"),
+        }
+    }
+
+    /// Encode `prefix 0f opcode /r` whose reg field is `xmm`, and r/m is an xmm register or memory.
+    ///
+    /// The mandatory prefix must precede REX prefix.
+    fn encode_xmm_rm(&mut self, prefix: &[u8], opcode: u8, xmm: Register, rm: &Expr) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.current_instr.code.extend_from_slice(prefix);
+        match rm {
+            Expr::Xmm(rm) => {
+                self.add_prefix(xmm.clone(), Register::default(), rm.clone(), &[]);
+                self.current_instr.code.extend_from_slice(&[
+                    0x0f,
+                    opcode,
+                    compose_mod_rm(MOD_REGI, xmm.base_offset & 7, rm.base_offset & 7),
+                ]);
+                Ok(())
+            }
+            rm => {
+                let Some(mem) = Memory::from_expr(rm, loc)? else {
+                    bail!(loc, "expected an xmm register or memory operand");
+                };
+                self.add_prefix(
+                    xmm.clone(),
+                    mem.index.clone().unwrap_or_default(),
+                    mem.base.clone().unwrap_or_default(),
+                    &[],
+                );
+                self.current_instr.code.extend_from_slice(&[0x0f, opcode]);
+                self.add_modrm_sib_disp(&mem, xmm.base_offset)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::code;
+    use crate::error::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_encode_packed_int() -> Result<()> {
+        assert_eq!(vec![0x66, 0x0f, 0xef, 0xc0], code("pxor %xmm0,%xmm0")?);
+        assert_eq!(vec![0x66, 0x0f, 0xdb, 0x08], code("pand (%rax),%xmm1")?);
+        assert_eq!(
+            vec![0x66, 0x45, 0x0f, 0xeb, 0xd1],
+            code("por %xmm9,%xmm10")?
+        );
+        assert_eq!(
+            vec![0x66, 0x45, 0x0f, 0xef, 0x7c, 0x4c, 0x08],
+            code("pxor 8(%r12,%rcx,2),%xmm15")?
+        );
+        assert!(code("pxor %xmm0,(%rax)").is_err());
+        assert!(code("pxor %eax,%xmm0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_mov_xmm() -> Result<()> {
+        assert_eq!(vec![0xf3, 0x0f, 0x7e, 0xd1], code("movq %xmm1,%xmm2")?);
+        assert_eq!(
+            vec![0xf3, 0x41, 0x0f, 0x7e, 0xd1],
+            code("movq %xmm9,%xmm2")?
+        );
+        assert_eq!(vec![0xf3, 0x0f, 0x7e, 0x08], code("movq (%rax),%xmm1")?);
+        assert_eq!(
+            vec![0x66, 0x45, 0x0f, 0xd6, 0x01],
+            code("movq %xmm8,(%r9)")?
+        );
+        assert_eq!(
+            vec![0x66, 0x45, 0x0f, 0x6e, 0x18],
+            code("movd (%r8),%xmm11")?
+        );
+        assert_eq!(vec![0x66, 0x0f, 0x7e, 0x08], code("movd %xmm1,(%rax)")?);
+        assert!(code("movd %xmm1,%xmm2").is_err());
+        Ok(())
+    }
+}
@@ -1,114 +1,101 @@
-// use std::process::exit;
+use crate::encoder::arch::x86_64::{registers::DataSizeSuffix, Expr};
+use crate::encoder::{parse_operand, rex, Encoder, InstrKind};
+use crate::error::Result;
+use crate::lexer::Token;
+use alloc::vec;
 
-// use crate::{elf::R_X86_64_32S};
-// use crate::globals::RELA_TEXT_USERS;
-// use crate::encoder::{Rela, SLASH_6,Instr, InstrKind, Encoder};
+impl Encoder {
+    /// e.g. `popq %rbx`, `popq %fs`
+    pub(crate) fn encode_pop(&mut self, index: &mut usize, tokens: &[Token]) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Pop)?;
 
-// impl Encoder<'_> {
-//     fn pop(&mut self) {
-//         let mut instr = Instr {
-//             kind: InstrKind::Pop,
-//             section: self.current_section,
-//             pos: self.tok.pos,
-//             ..Default::default()
-//         };
-//         self.instrs
-//             .get_mut(&self.current_section)
-//             .unwrap()
-//             .push(instr.clone());
+        match parse_operand(index, tokens)? {
+            Expr::Register(reg) if reg.size == DataSizeSuffix::Quad => {
+                if reg.base_offset >= 8 {
+                    self.current_instr.code.push(rex(0, 0, 0, 1));
+                }
+                self.current_instr.code.push(0x58 + (reg.base_offset & 7));
+            }
+            // POP FS: 0f a1, POP GS: 0f a9
+            Expr::Segment(sreg) if matches!(sreg.lit, "FS" | "GS") => {
+                self.current_instr
+                    .code
+                    .extend_from_slice(&[0x0f, 0xa1 + ((sreg.base_offset - 4) << 3)]);
+            }
+            _ => bail!(loc, "invalid operand for `pop`"),
+        }
+        Ok(())
+    }
 
-//         let source = self.parse_operand();
+    /// e.g. `pushq %rbx`, `pushq $1`, `pushq %fs`
+    pub(crate) fn encode_push(&mut self, index: &mut usize, tokens: &[Token]) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Push)?;
 
-//         if let Operand::Register(reg) = source {
-//             reg.check_regi_size(Suffix::Quad);
-//             instr.add_rex_prefix("", "", reg.lit, &[]);
-//             instr.code.push(0x58 + reg.regi_bits());
-//             return;
-//         }
+        match parse_operand(index, tokens)? {
+            Expr::Register(reg) if reg.size == DataSizeSuffix::Quad => {
+                if reg.base_offset >= 8 {
+                    self.current_instr.code.push(rex(0, 0, 0, 1));
+                }
+                self.current_instr.code.push(0x50 + (reg.base_offset & 7));
+            }
+            // PUSH FS: 0f a0, PUSH GS: 0f a8
+            Expr::Segment(sreg) if matches!(sreg.lit, "FS" | "GS") => {
+                self.current_instr
+                    .code
+                    .extend_from_slice(&[0x0f, 0xa0 + ((sreg.base_offset - 4) << 3)]);
+            }
+            Expr::Immediate(imm) => {
+                // The immediate is sign extended to 64bit.
+                match self.eval_imm_with_symbol(*imm, DataSizeSuffix::Quad)? {
+                    (imm, None) => match i8::try_from(imm) {
+                        Ok(imm) => self.current_instr.code = vec![0x6a, imm as u8],
+                        Err(_) => match i32::try_from(imm) {
+                            Ok(imm) => {
+                                self.current_instr.code.push(0x68);
+                                self.current_instr
+                                    .code
+                                    .extend_from_slice(&imm.to_le_bytes());
+                            }
+                            Err(_) => bail!(loc, "immediate out of range for `push`. Got {imm}"),
+                        },
+                    },
+                    (imm, Some((symbol, rtype))) => {
+                        self.current_instr.code = vec![0x68];
+                        self.push_imm_reloc(symbol, rtype, imm, 4);
+                    }
+                }
+            }
+            _ => bail!(loc, "invalid operand for `push`"),
+        }
+        Ok(())
+    }
+}
 
-//         if let Operand::Indirection(ind) = source {
-//             instr.add_segment_override_prefix(&ind);
-//             instr.add_rex_prefix("", ind.index.lit, ind.base.lit, &[]);
-//             instr.code.(0x8f); // op_code
-//             instr.add_modrm_sib_disp(&ind, SLASH_0);
-//             return;
-//         }
+#[cfg(test)]
+mod tests {
+    use crate::encoder::code;
+    use crate::error::Result;
+    use pretty_assertions::assert_eq;
 
-//         eprintln!("invalid operand for instruction. {}", source.pos);
-//         exit(1);
-//     }
+    #[test]
+    fn should_encode_segment_and_flags_stack_op() -> Result<()> {
+        assert_eq!(vec![0x0f, 0xa0], code("pushq %fs")?);
+        assert_eq!(vec![0x0f, 0xa8], code("pushq %gs")?);
+        assert_eq!(vec![0x0f, 0xa9], code("popq %gs")?);
+        assert_eq!(vec![0x9c], code("pushfq")?);
+        assert_eq!(vec![0x9d], code("popfq")?);
+        assert!(code("pushq %ds").is_err());
+        Ok(())
+    }
 
-//     fn push(&mut self) {
-//         let mut instr = Instr {
-//             kind: InstrKind::Push,
-//             section: self.current_section,
-//             pos: self.tok.pos,
-//             ..Default::default()
-//         };
-//         self.instrs
-//             .get_mut(&self.current_section)
-//             .unwrap()
-//             .push(instr.clone());
-
-//         let source = self.parse_operand();
-
-//         if let Operand::Register(reg) = source {
-//             reg.check_regi_size(Suffix::Quad);
-//             if r8_r15.contains(&source.lit) {
-//                 instr.code.push(rex(0, 0, 0, 1));
-//             }
-//             source.check_regi_size(Suffix::Quad);
-//             instr.code.push(0x50 + source.regi_bits());
-//             return;
-//         }
-
-//         if let Operand::Indirection(ind) = source {
-//             instr.add_segment_override_prefix(&ind);
-//             instr.add_rex_prefix("", ind.index.lit, ind.base.lit, &[]);
-//             instr.code.push(0xff); // op_code
-//             instr.add_modrm_sib_disp(&ind, SLASH_6);
-//             return;
-//         }
-
-//         if let Operand::Immediate(imm) = source {
-//             let mut used_symbols = Vec::<String>::new();
-//             let imm_val = eval_expr_get_symbol(&imm, &mut used_symbols);
-//             if used_symbols.len() >= 2 {
-//                 eprintln!("invalid immediate operand. {}", imm.pos);
-//                 exit(1);
-//             }
-//             let imm_need_rela = used_symbols.len() == 1;
-//             if imm_need_rela {
-//                 instr.code.push(0x68);
-//                 instr.code.push(0);
-//                 instr.code.push(0);
-//                 instr.code.push(0);
-//                 instr.code.push(0);
-//                 RELA_TEXT_USERS.lock().unwrap().push(Rela {
-//                     uses: used_symbols[0].as_str(),
-//                     instr,
-//                     offset: 0x1,
-//                     rtype: R_X86_64_32S,
-//                     adjust: imm_val,
-//                     ..Default::default()
-//                 });
-//             } else   {
-//               match imm_val{
-//                 i8::MIN..=i8::MAX => {
-//                     instr.code = vec![0x6a, imm_val as u8];
-//                 },
-//                 i32::MIN..=i32::MAX =>{
-//                     let hex = u32::to_le_bytes(imm_val as u32);
-//                     instr.code = vec![0x68, hex[0], hex[1], hex[2], hex[3]];
-//                 }
-//                 _=>{}
-
-//               };
-//             };
-//             return;
-//         }
-
-//         eprintln!("invalid operand for instruction. {}", source.pos);
-//         exit(1);
-//     }
-// }
+    #[test]
+    fn should_encode_gpr_and_immediate_stack_op() -> Result<()> {
+        assert_eq!(vec![0x55], code("pushq %rbp")?);
+        assert_eq!(vec![0x41, 0x5c], code("popq %r12")?);
+        assert_eq!(vec![0x6a, 0xff], code("pushq $-1")?);
+        assert_eq!(vec![0x68, 0x00, 0x01, 0x00, 0x00], code("pushq $256")?);
+        Ok(())
+    }
+}
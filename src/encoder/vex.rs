@@ -0,0 +1,311 @@
+//! VEX encoded instructions. e.g. `kmovw %k1, %eax`, `vaddps %ymm2, %ymm1, %ymm0`
+use crate::encoder::arch::x86_64::{bin_const::MOD_REGI, registers::DataSizeSuffix, Expr};
+use crate::encoder::mem::Memory;
+use crate::encoder::{
+    compose_mod_rm, expect, parse_operand, parse_two_operand, Encoder, InstrKind,
+};
+use crate::error::Result;
+use crate::lexer::{Token, TokenKind};
+use crate::CpuFeature;
+use alloc::vec::Vec;
+
+/// Fields of the VEX prefix. The inverted fields are given as they are, and inverted by [`Vex::push_to`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Vex {
+    /// Extension of ModR/M reg
+    pub(crate) r: bool,
+    /// Extension of SIB index
+    pub(crate) x: bool,
+    /// Extension of ModR/M r/m or SIB base
+    pub(crate) b: bool,
+    /// Opcode map. 1: `0f`, 2: `0f 38`, 3: `0f 3a`
+    pub(crate) map: u8,
+    pub(crate) w: bool,
+    /// Additional source register. 0 if unused.
+    pub(crate) vvvv: u8,
+    /// 256bit vector length
+    pub(crate) l: bool,
+    /// Implied prefix. 0: none, 1: `66`, 2: `f3`, 3: `f2`
+    pub(crate) pp: u8,
+}
+
+impl Vex {
+    /// Push the 2 bytes form(`c5`) if possible, otherwise the 3 bytes form(`c4`), as GNU as does.
+    pub(crate) fn push_to(self, code: &mut Vec<u8>) {
+        let lpp = (u8::from(self.l) << 2) | self.pp;
+        let vvvv = (!self.vvvv & 0xf) << 3;
+        if !self.x && !self.b && !self.w && self.map == 1 {
+            code.extend_from_slice(&[0xc5, u8::from(!self.r) << 7 | vvvv | lpp]);
+        } else {
+            let rxb = u8::from(!self.r) << 7 | u8::from(!self.x) << 6 | u8::from(!self.b) << 5;
+            code.extend_from_slice(&[0xc4, rxb | self.map, u8::from(self.w) << 7 | vvvv | lpp]);
+        }
+    }
+}
+
+/// Is `mnemonic` a `kmov` instruction? Returns its opmask size.
+pub(crate) fn kmov_op_by(mnemonic: &str) -> Option<DataSizeSuffix> {
+    Some(match mnemonic {
+        "kmovb" => DataSizeSuffix::Byte,
+        "kmovw" => DataSizeSuffix::Word,
+        "kmovd" => DataSizeSuffix::Long,
+        "kmovq" => DataSizeSuffix::Quad,
+        _ => return None,
+    })
+}
+
+/// Get the packed floating point arithmetic by mnemonic. e.g. `vaddpd` => (Vadd, 1, 0x58)
+///
+/// Returns (kind, VEX.pp, opcode). The opcode follows `VEX.0F`.
+pub(crate) fn vex_arith_op_by(mnemonic: &str) -> Option<(InstrKind, u8, u8)> {
+    let (kind, opcode) = match mnemonic.get(..mnemonic.len().saturating_sub(2))? {
+        "vadd" => (InstrKind::Vadd, 0x58),
+        "vmul" => (InstrKind::Vmul, 0x59),
+        "vsub" => (InstrKind::Vsub, 0x5c),
+        "vdiv" => (InstrKind::Vdiv, 0x5e),
+        _ => return None,
+    };
+    match &mnemonic[mnemonic.len() - 2..] {
+        "ps" => Some((kind, 0, opcode)),
+        "pd" => Some((kind, 1, opcode)),
+        _ => None,
+    }
+}
+
+impl Encoder {
+    /// e.g. `vaddps %ymm2, %ymm1, %ymm0`, `vmulpd (%rax), %xmm1, %xmm0`
+    ///
+    /// - VADDPS/VADDPD xmm1, xmm2, xmm3/m128: VEX.128.0F(.66) 58 /r
+    /// - VADDPS/VADDPD ymm1, ymm2, ymm3/m256: VEX.256.0F(.66) 58 /r
+    ///
+    /// `vmul`(59), `vsub`(5c) and `vdiv`(5e) are the same except the opcode.
+    /// The second operand is in VEX.vvvv.
+    pub(crate) fn encode_vex_arith(
+        &mut self,
+        kind: InstrKind,
+        pp: u8,
+        opcode: u8,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(kind)?;
+        self.required_feature = Some(CpuFeature::Avx);
+        if self.explicit_prefix.data16 || self.explicit_prefix.rex.is_some() {
+            bail!(loc, "`data16` and `rex` prefixes cannot be used with VEX");
+        }
+
+        let (rm, src) = parse_two_operand(index, tokens)?;
+        expect(TokenKind::Comma, index, tokens)?;
+        let (l, src, dst) = match (src, parse_operand(index, tokens)?) {
+            (Expr::Xmm(src), Expr::Xmm(dst)) => (false, src, dst),
+            (Expr::Ymm(src), Expr::Ymm(dst)) => (true, src, dst),
+            _ => bail!(loc, "operands must be all xmm or all ymm registers"),
+        };
+        let mut vex = Vex {
+            r: dst.base_offset >= 8,
+            map: 1,
+            vvvv: src.base_offset,
+            l,
+            pp,
+            ..Default::default()
+        };
+
+        match (rm, l) {
+            (Expr::Xmm(rm), false) | (Expr::Ymm(rm), true) => {
+                vex.b = rm.base_offset >= 8;
+                vex.push_to(&mut self.current_instr.code);
+                self.current_instr.code.extend_from_slice(&[
+                    opcode,
+                    compose_mod_rm(MOD_REGI, dst.base_offset & 7, rm.base_offset & 7),
+                ]);
+                Ok(())
+            }
+            (rm, _) => {
+                let Some(mem) = Memory::from_expr(&rm, loc)? else {
+                    bail!(loc, "operands must be all xmm or all ymm registers");
+                };
+                vex.x = mem
+                    .index
+                    .as_ref()
+                    .is_some_and(|index| index.base_offset >= 8);
+                vex.b = mem.base.as_ref().is_some_and(|base| base.base_offset >= 8);
+                vex.push_to(&mut self.current_instr.code);
+                self.current_instr.code.push(opcode);
+                self.add_modrm_sib_disp(&mem, dst.base_offset & 7)
+            }
+        }
+    }
+
+    /// e.g. `kmovw %k1, %eax`, `kmovq (%rax), %k2`
+    ///
+    /// - KMOV k1, k2/m: VEX.L0.0F 90 /r
+    /// - KMOV m, k1: VEX.L0.0F 91 /r
+    /// - KMOV k1, r32/r64: VEX.L0.0F 92 /r
+    /// - KMOV r32/r64, k1: VEX.L0.0F 93 /r
+    ///
+    /// VEX.pp and VEX.W depend on the size and whether the other operand is a general purpose register.
+    pub(crate) fn encode_kmov(
+        &mut self,
+        size: DataSizeSuffix,
+        index: &mut usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let loc = self.current_instr.loc;
+        self.set_kind(InstrKind::Kmov)?;
+        self.required_feature = Some(match size {
+            DataSizeSuffix::Byte => CpuFeature::Avx512dq,
+            DataSizeSuffix::Word => CpuFeature::Avx512f,
+            _ => CpuFeature::Avx512bw,
+        });
+        if self.explicit_prefix.data16 || self.explicit_prefix.rex.is_some() {
+            bail!(loc, "`data16` and `rex` prefixes cannot be used with VEX");
+        }
+
+        // (pp, W) of the opmask or memory form, and those of the general purpose register form
+        let ((km_pp, km_w), (gpr_pp, gpr_w)) = match size {
+            DataSizeSuffix::Byte => ((1, false), (1, false)),
+            DataSizeSuffix::Word => ((0, false), (0, false)),
+            DataSizeSuffix::Long => ((1, true), (3, false)),
+            _ => ((0, true), (3, true)),
+        };
+        let gpr_size = match size {
+            DataSizeSuffix::Quad => DataSizeSuffix::Quad,
+            _ => DataSizeSuffix::Long,
+        };
+        let mut vex = Vex {
+            map: 1,
+            pp: km_pp,
+            w: km_w,
+            ..Default::default()
+        };
+
+        let (opcode, k, rm) = match parse_two_operand(index, tokens)? {
+            (Expr::Opmask(src), Expr::Opmask(dst)) => {
+                vex.push_to(&mut self.current_instr.code);
+                self.current_instr.code.extend_from_slice(&[
+                    0x90,
+                    compose_mod_rm(MOD_REGI, dst.base_offset, src.base_offset),
+                ]);
+                return Ok(());
+            }
+            (Expr::Register(src), Expr::Opmask(dst)) => {
+                if src.size != gpr_size {
+                    bail!(loc, "operand size mismatch. `{src}`");
+                }
+                vex.pp = gpr_pp;
+                vex.w = gpr_w;
+                vex.b = src.base_offset >= 8;
+                vex.push_to(&mut self.current_instr.code);
+                self.current_instr.code.extend_from_slice(&[
+                    0x92,
+                    compose_mod_rm(MOD_REGI, dst.base_offset, src.base_offset & 7),
+                ]);
+                return Ok(());
+            }
+            (Expr::Opmask(src), Expr::Register(dst)) => {
+                if dst.size != gpr_size {
+                    bail!(loc, "operand size mismatch. `{dst}`");
+                }
+                vex.pp = gpr_pp;
+                vex.w = gpr_w;
+                vex.r = dst.base_offset >= 8;
+                vex.push_to(&mut self.current_instr.code);
+                self.current_instr.code.extend_from_slice(&[
+                    0x93,
+                    compose_mod_rm(MOD_REGI, dst.base_offset & 7, src.base_offset),
+                ]);
+                return Ok(());
+            }
+            (mem, Expr::Opmask(dst)) => (0x90, dst, mem),
+            (Expr::Opmask(src), mem) => (0x91, src, mem),
+            _ => bail!(loc, "unsupported operands for `kmov`"),
+        };
+
+        let Some(mem) = Memory::from_expr(&rm, loc)? else {
+            bail!(loc, "unsupported operands for `kmov`");
+        };
+        vex.x = mem
+            .index
+            .as_ref()
+            .is_some_and(|index| index.base_offset >= 8);
+        vex.b = mem.base.as_ref().is_some_and(|base| base.base_offset >= 8);
+        vex.push_to(&mut self.current_instr.code);
+        self.current_instr.code.push(opcode);
+        self.add_modrm_sib_disp(&mem, k.base_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::{code, parse_with, Encoder};
+    use crate::error::Result;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_encode_kmov() -> Result<()> {
+        assert_eq!(vec![0xc5, 0xf8, 0x93, 0xc1], code("kmovw %k1,%eax")?);
+        assert_eq!(vec![0xc5, 0xf8, 0x92, 0xc8], code("kmovw %eax,%k1")?);
+        assert_eq!(vec![0xc5, 0xf8, 0x91, 0x08], code("kmovw %k1,(%rax)")?);
+        assert_eq!(vec![0xc4, 0xe1, 0xfb, 0x93, 0xc1], code("kmovq %k1,%rax")?);
+        assert_eq!(vec![0xc4, 0xe1, 0xf8, 0x90, 0xca], code("kmovq %k2,%k1")?);
+        assert_eq!(
+            vec![0xc4, 0x81, 0xf8, 0x91, 0x5c, 0x51, 0x08],
+            code("kmovq %k3,8(%r9,%r10,2)")?
+        );
+        assert_eq!(vec![0xc4, 0xc1, 0x79, 0x92, 0xf9], code("kmovb %r9d,%k7")?);
+        assert_eq!(vec![0xc5, 0xfb, 0x93, 0xc1], code("kmovd %k1,%eax")?);
+        assert!(code("kmovw %k1,%ax").is_err());
+        assert!(code("kmovq %k1,%eax").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_encode_vex_arith() -> Result<()> {
+        assert_eq!(
+            vec![0xc5, 0xf4, 0x58, 0xc2],
+            code("vaddps %ymm2,%ymm1,%ymm0")?
+        );
+        assert_eq!(
+            vec![0xc4, 0x41, 0x70, 0x58, 0xca],
+            code("vaddps %xmm10,%xmm1,%xmm9")?
+        );
+        assert_eq!(
+            vec![0xc5, 0x8d, 0x58, 0x18],
+            code("vaddpd (%rax),%ymm14,%ymm3")?
+        );
+        assert_eq!(
+            vec![0xc4, 0x81, 0x70, 0x5c, 0x44, 0x91, 0x08],
+            code("vsubps 8(%r9,%r10,4),%xmm1,%xmm0")?
+        );
+        assert_eq!(
+            vec![0xc4, 0x41, 0x05, 0x59, 0xff],
+            code("vmulpd %ymm15,%ymm15,%ymm15")?
+        );
+        assert!(code("vaddps %xmm2,%ymm1,%ymm0").is_err());
+        assert!(code("vdivps %ymm2,%ymm1,(%rax)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_gate_instruction_by_cpu_feature() -> Result<()> {
+        let assemble = |mattr: &str| {
+            let mut encoder = Encoder::default();
+            encoder.options.mattr(mattr)?;
+            parse_with(encoder, tokenize("nop\nvaddps %ymm2,%ymm1,%ymm0\n")?)
+        };
+
+        let err = assemble("+sse2,-avx").unwrap_err();
+        assert_eq!("`vaddps` needs `avx`, which is disabled", err.message);
+        assert_eq!(Some(1), err.location.map(|loc| loc.line));
+
+        assert!(assemble("-avx,+avx").is_ok());
+        assert!(assemble("-avx512f").is_ok());
+        assert!(assemble("avx").is_err());
+        assert!(assemble("+avx3").is_err());
+        // A multibyte character is not a sign.
+        assert!(assemble("éavx").is_err());
+        Ok(())
+    }
+}
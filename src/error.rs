@@ -5,19 +5,40 @@
 //! https://github.com/rust-analyzer/ungrammar/blob/20bc271547bb130f282c704f736e4989743ce332/Cargo.toml#L5
 //!
 //! Boilerplate error definitions.
-use std::fmt;
 use crate::lexer::Location;
+use alloc::string::String;
+use core::fmt;
 
 /// A type alias for std's Result with the Error as our error type.
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = Error> = core::result::Result<T, E>;
 
 trait Context {
     fn context(self, msg: &str) -> Self;
 }
 
+/// Category of an [`Error`], to tell failures apart without parsing the message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Invalid character or literal. e.g. `\r`, `"abc`
+    Lex,
+    /// Unexpected token or malformed operand. e.g. `movq %rax,`
+    Parse,
+    /// Instruction or directive which cannot be assembled. e.g. operand size mismatch
+    #[default]
+    Encode,
+    /// Inconsistency found in the ELF object being written.
+    Elf,
+    /// Reference to the symbol which must be defined in the source.
+    UndefinedSymbol(String),
+    /// Reading or writing a file failed.
+    Io,
+}
+
 /// An error encountered when parsing a Grammar.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Error {
+    pub(crate) kind: ErrorKind,
     pub(crate) message: String,
     pub(crate) location: Option<Location>,
 }
@@ -25,16 +46,57 @@ pub struct Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(loc) = self.location {
-            // Report 1-based indices, to match text editors
-            write!(f, "{}:{}: ", loc.line + 1, loc.column + 1)?
+            write!(f, "{loc}: ")?
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// A non-fatal diagnostic. The assembly continues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub(crate) message: String,
+    pub(crate) location: Option<Location>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(loc) = self.location {
+            write!(f, "{loc}: ")?
         }
         write!(f, "{}", self.message)
     }
 }
 
-impl std::error::Error for Error {}
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        format_err!("{err}").with_kind(ErrorKind::Io)
+    }
+}
 
 impl Error {
+    /// Category of this error.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Place in the source where the error occurred, if known.
+    pub fn location(&self) -> Option<Location> {
+        self.location
+    }
+
+    /// Description of the error, without the location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub(crate) fn with_kind(self, kind: ErrorKind) -> Error {
+        Error { kind, ..self }
+    }
+
     pub(crate) fn with_location(self, location: Location) -> Error {
         Error {
             location: Some(location),
@@ -46,7 +108,8 @@ impl Error {
 macro_rules! _format_err {
     ($($tt:tt)*) => {
         $crate::error::Error {
-            message: format!($($tt)*),
+            kind: $crate::error::ErrorKind::Encode,
+            message: alloc::format!($($tt)*),
             location: None,
         }
     };
@@ -0,0 +1,74 @@
+//! Flat binary output. e.g. for bootloaders and shellcode
+use crate::elf::constants::{
+    R_X86_64_16, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_8, R_X86_64_PC16, R_X86_64_PC32,
+    R_X86_64_PC64, R_X86_64_PC8, R_X86_64_PLT32,
+};
+use crate::encoder::Encoder;
+use crate::error::{format_err, ErrorKind, Result};
+use crate::utils::HashMap;
+use alloc::{string::String, vec::Vec};
+
+/// Concatenate the bytes of the laid out sections in order of first appearance, and resolve all relocations.
+///
+/// Every relocated symbol must be defined in the source, since there is no linker after this.
+/// Returns the binary and the offset of each section in it.
+pub(crate) fn write_flat_binary(encoder: &Encoder) -> Result<(Vec<u8>, HashMap<String, usize>)> {
+    let mut out = Vec::new();
+    // section name -> address of the section in the binary
+    let mut section_bases = HashMap::new();
+    for name in encoder.section_names() {
+        let Some(section) = encoder.user_defined_sections.get(&name) else {
+            continue;
+        };
+        let base = out.len();
+        section_bases.insert(name, base);
+        out.extend_from_slice(&section.code);
+        // `.bss` has only its size, so it is filled with zero.
+        out.resize(base + section.addr.max(section.code.len()), 0);
+    }
+
+    for r in &encoder.rela_text_users {
+        if r.is_already_resolved {
+            continue;
+        }
+        let loc = r.instr.loc;
+        let symbol = match encoder.user_defined_symbols.get(&r.uses) {
+            Some(symbol) => symbol,
+            None => {
+                let err = format_err!(
+                    "undefined symbol `{}` cannot be resolved in binary output",
+                    r.uses
+                );
+                let kind = ErrorKind::UndefinedSymbol(r.uses.clone());
+                return Err(err.with_location(loc).with_kind(kind));
+            }
+        };
+
+        let s = (section_bases[&symbol.section_name] + symbol.addr) as i64;
+        let p = section_bases[&r.instr.section_name] + r.instr.addr + r.offset;
+        let (value, size) = match r.rtype {
+            R_X86_64_64 => (s + r.addend(), 8),
+            R_X86_64_32 | R_X86_64_32S => (s + r.addend(), 4),
+            R_X86_64_16 => (s + r.addend(), 2),
+            R_X86_64_8 => (s + r.addend(), 1),
+            R_X86_64_PC64 => (s + r.addend() - p as i64, 8),
+            R_X86_64_PC32 | R_X86_64_PLT32 => (s + r.addend() - p as i64, 4),
+            R_X86_64_PC16 => (s + r.addend() - p as i64, 2),
+            R_X86_64_PC8 => (s + r.addend() - p as i64, 1),
+            rtype => {
+                let err =
+                    format_err!("relocation type {rtype} cannot be resolved in binary output");
+                let kind = ErrorKind::UndefinedSymbol(r.uses.clone());
+                return Err(err.with_location(loc).with_kind(kind));
+            }
+        };
+
+        let bits = size * 8;
+        if size < 8 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+            let err = format_err!("relocation of `{}` overflows. Got {value:#x}", r.uses);
+            return Err(err.with_location(loc));
+        }
+        out[p..p + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+    Ok((out, section_bases))
+}
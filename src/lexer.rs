@@ -5,8 +5,9 @@
 //! https://github.com/rust-analyzer/ungrammar/blob/20bc271547bb130f282c704f736e4989743ce332/Cargo.toml#L5
 //!
 //! Simple hand-written assembler lexer
-use std::str::Chars;
-use crate::error::{bail, Result};
+use crate::error::{bail, ErrorKind, Result};
+use alloc::{string::String, vec::Vec};
+use core::str::Chars;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) enum TokenKind {
@@ -22,9 +23,16 @@ pub(crate) enum TokenKind {
     Dolor,
     Percent,
     Colon,
+    /// Relocation modifier prefix. e.g. `foo@GOTPCREL`
+    At,
     Comma,
     LParen,
     RParen,
+    /// AVX-512 operand decoration. e.g. `{%k1}{z}`
+    LBrace,
+    RBrace,
+    /// Parameter in a `.macro` body. e.g. `\reg`, and `\()` as an empty separator
+    MacroArg(String),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -33,14 +41,47 @@ pub(crate) struct Token {
     pub(crate) loc: Location,
 }
 
+/// Place in the source. The line and column are 0-based, and are shown 1-based by `Display`.
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
-pub(crate) struct Location {
+pub struct Location {
     pub(crate) line: usize,
     pub(crate) column: usize,
+    /// Byte offset from the beginning of the source. e.g. for editor integrations
+    pub(crate) offset: usize,
+}
+
+impl core::fmt::Display for Location {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Report 1-based indices, to match text editors
+        write!(f, "{}:{}", self.line + 1, self.column + 1)
+    }
 }
 
 impl Location {
+    /// 0-based line number.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// 0-based column, in characters.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Byte offset from the beginning of the source.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The location just after `text`.
+    pub(crate) fn end_of(text: &str) -> Self {
+        let mut loc = Self::default();
+        loc.advance(text);
+        loc
+    }
+
     fn advance(&mut self, text: &str) {
+        self.offset += text.len();
         match text.rfind('\n') {
             Some(idx) => {
                 self.line += text.chars().filter(|&it| it == '\n').count();
@@ -51,9 +92,14 @@ impl Location {
     }
 }
 
-pub(crate) fn tokenize(mut input: &str) -> Result<Vec<Token>> {
+#[cfg(test)]
+pub(crate) fn tokenize(input: &str) -> Result<Vec<Token>> {
+    tokenize_from(input, Location::default())
+}
+
+/// Tokenize `input` which follows the source up to `loc`. e.g. the second snippet fed to an encoder
+pub(crate) fn tokenize_from(mut input: &str, mut loc: Location) -> Result<Vec<Token>> {
     let mut res = Vec::new();
-    let mut loc = Location::default();
     while !input.is_empty() {
         let old_input = input;
         skip_ws(&mut input);
@@ -61,9 +107,10 @@ pub(crate) fn tokenize(mut input: &str) -> Result<Vec<Token>> {
         if old_input.len() == input.len() {
             match advance(&mut input) {
                 Ok(kind) => {
+                    log::trace!("token {loc}: {kind:?}");
                     res.push(Token { kind, loc });
                 }
-                Err(err) => return Err(err.with_location(loc)),
+                Err(err) => return Err(err.with_location(loc).with_kind(ErrorKind::Lex)),
             }
         }
         let consumed = old_input.len() - input.len();
@@ -95,13 +142,21 @@ fn advance(input: &mut &str) -> Result<TokenKind> {
         '%' => TokenKind::Percent,
         '$' => TokenKind::Dolor,
         ':' => TokenKind::Colon,
+        '@' => TokenKind::At,
         '(' => TokenKind::LParen,
         ')' => TokenKind::RParen,
+        '{' => TokenKind::LBrace,
+        '}' => TokenKind::RBrace,
         '\'' => take_until('\'', &mut chars)?,
         '\"' => take_until('\"', &mut chars)?,
         c if c.is_ascii_digit() => {
             let mut buf = String::new();
             buf.push(c);
+            // hex prefix. e.g. 0x10
+            if let Some(x @ ('x' | 'X')) = chars.clone().next().filter(|_| c == '0') {
+                chars.next();
+                buf.push(x);
+            }
             loop {
                 match chars.clone().next() {
                     Some(c) if is_number_char(c) => {
@@ -118,7 +173,7 @@ fn advance(input: &mut &str) -> Result<TokenKind> {
             buf.push(c);
             loop {
                 match chars.clone().next() {
-                    Some(c) if is_ident_char(c) => {
+                    Some(c) if is_ident_char(c) || c.is_ascii_digit() => {
                         chars.next();
                         buf.push(c);
                     }
@@ -127,6 +182,22 @@ fn advance(input: &mut &str) -> Result<TokenKind> {
             }
             TokenKind::Ident(buf)
         }
+        '\\' => {
+            let rest = chars.as_str();
+            let len = match rest.strip_prefix("()") {
+                Some(_) => 2,
+                None => rest
+                    .find(|c: char| !is_ident_char(c) && !c.is_ascii_digit())
+                    .unwrap_or(rest.len()),
+            };
+            let name = match &rest[..len] {
+                "()" => String::new(),
+                "" => bail!("expected a macro parameter after `\\`"),
+                name => String::from(name),
+            };
+            chars = rest[len..].chars();
+            TokenKind::MacroArg(name)
+        }
         '\r' => bail!("unexpected `\\r`, only Unix-style line endings allowed"),
         c => bail!("unexpected character: `{}`", c),
     };
@@ -198,37 +269,66 @@ _start:
             vec![
                 Token {
                     kind: TokenKind::Ident(".text".to_owned()),
-                    loc: Location { line: 1, column: 0 },
+                    loc: Location {
+                        line: 1,
+                        column: 0,
+                        offset: 43
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident(".global".to_owned()),
-                    loc: Location { line: 2, column: 0 },
+                    loc: Location {
+                        line: 2,
+                        column: 0,
+                        offset: 49
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident("_start".to_owned()),
-                    loc: Location { line: 2, column: 8 },
+                    loc: Location {
+                        line: 2,
+                        column: 8,
+                        offset: 57
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident("_start".to_owned()),
-                    loc: Location { line: 3, column: 0 },
+                    loc: Location {
+                        line: 3,
+                        column: 0,
+                        offset: 64
+                    },
                 },
                 Token {
                     kind: TokenKind::Colon,
-                    loc: Location { line: 3, column: 6 },
+                    loc: Location {
+                        line: 3,
+                        column: 6,
+                        offset: 70
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident("mov".to_owned()),
-                    loc: Location { line: 4, column: 4 },
+                    loc: Location {
+                        line: 4,
+                        column: 4,
+                        offset: 76
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident("eax".to_owned()),
-                    loc: Location { line: 4, column: 8 },
+                    loc: Location {
+                        line: 4,
+                        column: 8,
+                        offset: 80
+                    },
                 },
                 Token {
                     kind: TokenKind::Comma,
                     loc: Location {
                         line: 4,
                         column: 11,
+                        offset: 83
                     },
                 },
                 Token {
@@ -236,39 +336,39 @@ _start:
                     loc: Location {
                         line: 4,
                         column: 13,
+                        offset: 85
                     },
                 },
                 Token {
                     kind: TokenKind::Ident("lea".to_owned()),
-                    loc: Location { line: 5, column: 4 },
+                    loc: Location {
+                        line: 5,
+                        column: 4,
+                        offset: 93
+                    },
                 },
                 Token {
                     kind: TokenKind::Ident("e".to_owned()),
-                    loc: Location { line: 5, column: 8 },
-                },
-                Token {
-                    kind: TokenKind::Comma,
-                    loc: Location { line: 5, column: 9 },
-                },
-                Token {
-                    kind: TokenKind::Number("0".to_owned()),
                     loc: Location {
                         line: 5,
-                        column: 11,
+                        column: 8,
+                        offset: 97
                     },
                 },
                 Token {
-                    kind: TokenKind::Ident("x".to_owned()),
+                    kind: TokenKind::Comma,
                     loc: Location {
                         line: 5,
-                        column: 12,
+                        column: 9,
+                        offset: 98
                     },
                 },
                 Token {
-                    kind: TokenKind::Number("10".to_owned()),
+                    kind: TokenKind::Number("0x10".to_owned()),
                     loc: Location {
                         line: 5,
-                        column: 13,
+                        column: 11,
+                        offset: 100
                     },
                 },
             ],
@@ -276,4 +376,47 @@ _start:
         );
         Ok(())
     }
+
+    #[test]
+    fn should_track_byte_offset() -> Result<()> {
+        // `é` is 2 bytes, but 1 column.
+        let tokens = tokenize("nop\n# é\n  movq $1, %rax\n")?;
+        let dolor = tokens
+            .iter()
+            .find(|token| token.kind == TokenKind::Dolor)
+            .unwrap();
+        assert_eq!(
+            Location {
+                line: 2,
+                column: 7,
+                offset: 16,
+            },
+            dolor.loc
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_drop_comment_after_tokens() -> Result<()> {
+        let kinds = |src| -> Result<Vec<TokenKind>> {
+            Ok(tokenize(src)?.into_iter().map(|token| token.kind).collect())
+        };
+        let ident = |name: &str| TokenKind::Ident(name.to_owned());
+
+        assert_eq!(vec![ident("nop")], kinds("nop # comment")?);
+        assert_eq!(
+            vec![
+                ident("movq"),
+                TokenKind::Percent,
+                ident("rax"),
+                TokenKind::Comma,
+                TokenKind::Percent,
+                ident("rbx"),
+            ],
+            kinds("movq %rax, %rbx # note: $1, (%rcx)")?
+        );
+        // No whitespace is needed before `#`, and the next line is tokenized.
+        assert_eq!(vec![ident("nop"), ident("ret")], kinds("nop#% $ (\nret\n")?);
+        Ok(())
+    }
 }
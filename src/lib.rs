@@ -0,0 +1,631 @@
+//! A small assembler.
+//!
+//! Without the `std` feature, this is `no_std` and needs only `alloc`.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
+mod elf;
+mod encoder;
+mod error;
+mod flat;
+mod lexer;
+mod listing;
+mod statistics;
+mod utils;
+
+pub use crate::elf::constants;
+pub use crate::error::{Error, ErrorKind, Result, Warning};
+pub use crate::lexer::Location;
+pub use crate::statistics::Statistics;
+
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+
+use crate::elf::Elf;
+pub use crate::encoder::Encoder;
+use crate::error::{bail, format_err};
+use crate::utils::HashMap;
+
+/// Symbol information resolved by [`assemble`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbol {
+    /// Offset from the beginning of `section`.
+    pub address: usize,
+    pub section: String,
+    /// [`constants::STB_LOCAL`], [`constants::STB_GLOBAL`] or [`constants::STB_WEAK`]
+    pub binding: u8,
+    /// e.g. [`constants::STT_NOTYPE`]
+    pub symbol_type: u8,
+    pub size: u64,
+}
+
+/// symbol name -> symbol information
+pub type SymbolTable = HashMap<String, Symbol>;
+
+/// Output file format of [`assemble`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// ELF64 relocatable object file
+    #[default]
+    Elf,
+    /// Only the concatenated section bytes, with all relocations resolved. e.g. for bootloaders
+    Binary,
+}
+
+/// Instruction set extensions which can be disabled by [`AssembleOptions::mattr`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuFeature {
+    /// e.g. `pxor`, `movq %xmm0, (%rax)`
+    Sse2,
+    /// e.g. `vaddps`
+    Avx,
+    /// e.g. `kmovw`
+    Avx512f,
+    /// e.g. `kmovd`, `kmovq`
+    Avx512bw,
+    /// e.g. `kmovb`
+    Avx512dq,
+    Movbe,
+}
+
+impl CpuFeature {
+    const ALL: [Self; 6] = [
+        Self::Sse2,
+        Self::Avx,
+        Self::Avx512f,
+        Self::Avx512bw,
+        Self::Avx512dq,
+        Self::Movbe,
+    ];
+
+    /// Name in `--mattr`. e.g. `avx512f`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sse2 => "sse2",
+            Self::Avx => "avx",
+            Self::Avx512f => "avx512f",
+            Self::Avx512bw => "avx512bw",
+            Self::Avx512dq => "avx512dq",
+            Self::Movbe => "movbe",
+        }
+    }
+}
+
+impl core::fmt::Display for CpuFeature {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Options of [`assemble`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleOptions {
+    /// Keeps local symbols (e.g., those starting with `.L`)
+    pub keep_locals: bool,
+    /// Keeps the local symbols which start with any of these prefixes. e.g. `.Lfunc`
+    pub keep_locals_matching: Vec<String>,
+    /// Error if no executable section has any bytes.
+    pub require_text: bool,
+    /// Make a listing which shows each instruction with its source line.
+    pub listing_with_source: bool,
+    /// Use the relaxable `R_X86_64_(REX_)GOTPCRELX` for `@GOTPCREL`. (default: true)
+    ///
+    /// Disable it to emit plain `R_X86_64_GOTPCREL` for old linkers.
+    pub relax_relocations: bool,
+    pub output_format: OutputFormat,
+    /// Report the local symbols which are never referenced.
+    pub list_unused: bool,
+    /// Collect counts, section sizes and phase timings.
+    pub statistics: bool,
+    /// Stamp `.comment` with `ras <version>`. (default: true)
+    pub comment: bool,
+    /// Error on unknown directives instead of ignoring them with a warning. e.g. `.gloabl`
+    pub strict: bool,
+    /// Keep the relocations resolved by the assembler in the object. e.g. `jmp` to a local label
+    pub emit_relocs: bool,
+    /// Align `.word`, `.long` and `.quad` to their size by padding before them.
+    pub natural_align: bool,
+    /// Load the 64-bit immediates of `mov` from constants in `.rodata` instead of `movabs`.
+    pub imm64_in_rodata: bool,
+    /// Assemble twice and error if the objects differ. e.g. by the order of a hash map leaking into them
+    ///
+    /// No timestamp is embedded, so the object does not depend on `SOURCE_DATE_EPOCH`.
+    pub reproducible: bool,
+    /// Instructions which need any of these features are rejected.
+    ///
+    /// All features, including AVX and AVX-512, are enabled by default as GNU as does, not only baseline
+    /// x86-64(SSE2). Disable the others to catch them on an older target. e.g. `--mattr=-avx`
+    pub disabled_features: Vec<CpuFeature>,
+    /// Error if a section is larger than the bytes after layout. e.g. `(".text", 4096)`
+    pub size_limits: Vec<(String, usize)>,
+}
+
+impl Default for AssembleOptions {
+    fn default() -> Self {
+        Self {
+            keep_locals: false,
+            keep_locals_matching: Vec::new(),
+            require_text: false,
+            listing_with_source: false,
+            relax_relocations: true,
+            output_format: OutputFormat::Elf,
+            list_unused: false,
+            statistics: false,
+            comment: true,
+            strict: false,
+            emit_relocs: false,
+            natural_align: false,
+            imm64_in_rodata: false,
+            reproducible: false,
+            disabled_features: Vec::new(),
+            size_limits: Vec::new(),
+        }
+    }
+}
+
+impl AssembleOptions {
+    /// Enable or disable features by the comma separated list. e.g. `+sse2,-avx`
+    ///
+    /// They are applied from left to right, so the last one wins.
+    pub fn mattr(&mut self, mattr: &str) -> Result<()> {
+        for attr in mattr.split(',').map(str::trim) {
+            let (enable, name) = match (attr.strip_prefix('+'), attr.strip_prefix('-')) {
+                (Some(name), _) => (true, name),
+                (_, Some(name)) => (false, name),
+                _ => bail!("`{attr}` must start with `+` or `-`. e.g. `-avx`"),
+            };
+            let Some(feature) = CpuFeature::ALL.into_iter().find(|f| f.name() == name) else {
+                bail!("unknown CPU feature `{name}`");
+            };
+            self.disabled_features
+                .retain(|&disabled| disabled != feature);
+            if !enable {
+                self.disabled_features.push(feature);
+            }
+        }
+        Ok(())
+    }
+
+    /// Limit the size of a section by `SECTION=N`. e.g. `.text=4096`, `.data=0x100`
+    pub fn assert_size(&mut self, arg: &str) -> Result<()> {
+        let Some((name, size)) = arg.split_once('=') else {
+            bail!("`{arg}` must be `SECTION=N`. e.g. `.text=4096`");
+        };
+        let size = match size.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => size.parse(),
+        };
+        let Ok(size) = size else {
+            bail!("the size of `{arg}` must be a decimal or hexadecimal number");
+        };
+        self.size_limits.push((name.to_owned(), size));
+        Ok(())
+    }
+}
+
+/// The result of [`assemble`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembled {
+    /// ELF64 relocatable object file, or flat binary by [`OutputFormat::Binary`]
+    pub object: Vec<u8>,
+    symbol_table: SymbolTable,
+    /// section name -> offset of the section in `object`
+    section_offsets: HashMap<String, usize>,
+    warnings: Vec<Warning>,
+    listing: Option<String>,
+    unused_symbols: Option<Vec<String>>,
+    statistics: Option<Statistics>,
+}
+
+impl Assembled {
+    /// User defined symbols with their resolved addresses.
+    pub fn symbol_table(&self) -> &SymbolTable {
+        &self.symbol_table
+    }
+
+    /// Offset of the label in [`Self::object`], to patch the bytes after assembly. e.g. for JIT
+    ///
+    /// `None` if the label is undefined, or is in a section without bytes in the output like `.bss`.
+    pub fn label_offset(&self, name: &str) -> Option<usize> {
+        let symbol = self.symbol_table.get(name)?;
+        Some(self.section_offsets.get(&symbol.section)? + symbol.address)
+    }
+
+    /// Non-fatal diagnostics in source order.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Listing made by [`AssembleOptions::listing_with_source`].
+    pub fn listing(&self) -> Option<&str> {
+        self.listing.as_deref()
+    }
+
+    /// Local symbols never referenced by any relocation or jump, made by [`AssembleOptions::list_unused`].
+    pub fn unused_symbols(&self) -> Option<&[String]> {
+        self.unused_symbols.as_deref()
+    }
+
+    /// Statistics made by [`AssembleOptions::statistics`].
+    pub fn statistics(&self) -> Option<&Statistics> {
+        self.statistics.as_ref()
+    }
+}
+
+/// Decode a source file read as bytes. e.g. by `std::fs::read`
+///
+/// The error points to the first byte which is not UTF-8. e.g. a Latin-1 comment
+pub fn decode_source(bytes: &[u8]) -> Result<&str> {
+    core::str::from_utf8(bytes).map_err(|err| {
+        let valid = err.valid_up_to();
+        let loc = Location::end_of(core::str::from_utf8(&bytes[..valid]).unwrap_or_default());
+        format_err!(
+            "invalid UTF-8 byte {:#04x}. The source must be UTF-8",
+            bytes[valid]
+        )
+        .with_location(loc)
+        .with_kind(ErrorKind::Lex)
+    })
+}
+
+/// Assemble x86_64 AT&T syntax source into an ELF64 relocatable object.
+pub fn assemble(src: &str, options: &AssembleOptions) -> Result<Assembled> {
+    let mut encoder = Encoder::with_options(options.clone());
+    encoder.feed(src)?;
+    let assembled = encoder.finish()?;
+
+    if options.reproducible {
+        // Another encoder iterates its hash maps in another order, so such nondeterminism shows up as a difference.
+        let mut encoder = Encoder::with_options(options.clone());
+        encoder.feed(src)?;
+        let again = encoder.finish()?.object;
+        if assembled.object != again {
+            let offset = assembled
+                .object
+                .iter()
+                .zip(&again)
+                .position(|(a, b)| a != b)
+                .unwrap_or(assembled.object.len().min(again.len()));
+            bail!("the object is not reproducible. Two assemblies differ at byte {offset:#x}");
+        }
+    }
+    Ok(assembled)
+}
+
+/// Expand the conditional blocks(e.g. `.ifdef`) and the macros of `src`, and return the source lines which are assembled.
+///
+/// The conditions may refer to the labels and constants defined so far, but the other statements are not encoded.
+pub fn preprocess(src: &str, options: &AssembleOptions) -> Result<String> {
+    let mut encoder = Encoder::with_options(options.clone());
+    encoder.preprocess_only = true;
+    encoder.feed(src)?;
+    encoder.check_conditionals_closed()?;
+    Ok(encoder.preprocessed)
+}
+
+impl Encoder {
+    /// Assign the addresses of all source given by [`Self::feed`], and make the object.
+    pub fn finish(mut self) -> Result<Assembled> {
+        self.check_conditionals_closed()?;
+        self.timer.restart();
+        self.assign_addresses()?;
+        if self.options.output_format == OutputFormat::Elf {
+            self.emit_debug_line()?;
+        }
+        self.timer.lap("layout");
+        for instr in &self.instrs {
+            log::debug!(
+                "instruction laid out {}+{:#x}: {:?} {:02x?}",
+                instr.section_name,
+                instr.addr,
+                instr.kind,
+                instr.code
+            );
+        }
+        let options = &self.options;
+        if options.require_text && !self.has_executable_code() {
+            bail!("no executable section has code. Is the code placed in a data section?");
+        }
+        for (name, limit) in &options.size_limits {
+            // The laid-out size, which also counts the bytes of nobits sections. e.g. `.bss`
+            let Some(section) = self.user_defined_sections.get(name) else {
+                bail!("cannot check the size of section `{name}` which does not exist");
+            };
+            if section.addr > *limit {
+                bail!(
+                    "section `{name}` is {} bytes, which exceeds the limit of {limit} bytes",
+                    section.addr
+                );
+            }
+        }
+
+        let (object, section_offsets) = match options.output_format {
+            OutputFormat::Elf => {
+                let mut e = Elf::new(&self, options.keep_locals);
+                e.keep_locals_matching(&options.keep_locals_matching);
+                if options.emit_relocs {
+                    e.emit_relocs();
+                }
+                if options.comment {
+                    e.comment(concat!("ras ", env!("CARGO_PKG_VERSION")));
+                }
+                e.collect_rela_symbols();
+                e.build_symtab_strtab();
+                e.rela_text_users();
+                e.build_shstrtab();
+                e.build_headers()
+                    .and_then(|()| e.validate())
+                    .map_err(|err| err.with_kind(ErrorKind::Elf))?;
+                (e.write_elf(), e.section_offsets())
+            }
+            OutputFormat::Binary => flat::write_flat_binary(&self)?,
+        };
+        self.timer.lap("write object");
+
+        let symbol_table = self
+            .user_defined_symbols
+            .iter()
+            .map(|(name, symbol)| {
+                let symbol = Symbol {
+                    address: symbol.addr,
+                    section: symbol.section_name.clone(),
+                    binding: symbol.binding,
+                    symbol_type: symbol.symbol_type,
+                    size: symbol.size as u64,
+                };
+                (name.clone(), symbol)
+            })
+            .collect();
+
+        let options = &self.options;
+        Ok(Assembled {
+            object,
+            symbol_table,
+            section_offsets,
+            warnings: self.warnings.clone(),
+            listing: options
+                .listing_with_source
+                .then(|| listing::listing_with_source(&self, &self.source)),
+            unused_symbols: options.list_unused.then(|| {
+                self.unused_local_symbols()
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect()
+            }),
+            statistics: options.statistics.then(|| Statistics::collect(&self)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{STB_LOCAL, STT_NOTYPE};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_preprocess_without_encoding() -> Result<()> {
+        let src = "foo: nop\n.set X, 0\n.ifdef foo\n  movq %rax, %rbx\n.else\n  ud2\n.endif\n.if X\n  int3\n.endif\nsyscall\n";
+        assert_eq!(
+            "foo: nop\n.set X, 0\n  movq %rax, %rbx\nsyscall\n",
+            preprocess(src, &AssembleOptions::default())?
+        );
+
+        let src = ".macro load reg, value\n  movq $\\value, %\\reg\n  unknown \\reg\n.endm\nload rax, 1\n";
+        assert_eq!(
+            "  movq $1, %rax\n  unknown rax\n",
+            preprocess(src, &AssembleOptions::default())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_symbol_table() -> Result<()> {
+        let assembled = assemble("foo: nop\n", &AssembleOptions::default())?;
+        assert_eq!(
+            Some(&Symbol {
+                address: 0,
+                section: ".text".to_owned(),
+                binding: STB_LOCAL,
+                symbol_type: STT_NOTYPE,
+                size: 0,
+            }),
+            assembled.symbol_table().get("foo")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_require_text() -> Result<()> {
+        let src = ".section .data, \"aw\"\nnop\n";
+        let options = AssembleOptions {
+            require_text: true,
+            ..Default::default()
+        };
+        assert!(assemble(src, &options).is_err());
+        assert!(assemble(src, &AssembleOptions::default()).is_ok());
+        assert!(assemble("nop\n", &options).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_limit_section_size() -> Result<()> {
+        let src = "nop\nsyscall\n.bss\n.skip 16\n";
+        let assemble_with = |arg: &str| {
+            let mut options = AssembleOptions::default();
+            options.assert_size(arg)?;
+            assemble(src, &options)
+        };
+        assert!(assemble_with(".text=3").is_ok());
+        assert!(assemble_with(".bss=0x10").is_ok());
+        assert_eq!(
+            "section `.text` is 3 bytes, which exceeds the limit of 2 bytes",
+            assemble_with(".text=2").unwrap_err().message
+        );
+        assert!(assemble_with(".bss=15").is_err());
+        assert!(assemble_with(".data=4096").is_err());
+
+        let mut options = AssembleOptions::default();
+        assert!(options.assert_size(".text").is_err());
+        assert!(options.assert_size(".text=4k").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_assemble_reproducibly() -> Result<()> {
+        // Each relocation section has a name in `.shstrtab`, which must not follow the order of a hash map.
+        let src = ".section .s1,\"a\"\n.quad x1\n.section .s2,\"a\"\n.quad x2\n.data\n.quad x3\n.text\ncall x4\n";
+        let options = AssembleOptions {
+            reproducible: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            assemble(src, &AssembleOptions::default())?.object,
+            assemble(src, &options)?.object
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_unused_local_symbols() -> Result<()> {
+        let src = "start:\njmp used\nunused:\nnop\nused:\nlea data(%rip), %rax\ndata:\n";
+        let options = AssembleOptions {
+            list_unused: true,
+            ..Default::default()
+        };
+        let assembled = assemble(src, &options)?;
+        assert_eq!(
+            Some(["start".to_owned(), "unused".to_owned()].as_slice()),
+            assembled.unused_symbols()
+        );
+        assert_eq!(
+            None,
+            assemble(src, &AssembleOptions::default())?.unused_symbols()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_locate_invalid_utf8() {
+        assert_eq!(Ok("nop\n"), decode_source(b"nop\n"));
+
+        // `\xe9` is `é` in Latin-1. `ü` before it is valid, and counts as 1 column.
+        let err = decode_source(b"nop\n# \xc3\xbc \xe9t\xe9\nnop\n").unwrap_err();
+        assert_eq!(ErrorKind::Lex, err.kind);
+        assert_eq!(
+            "2:5: invalid UTF-8 byte 0xe9. The source must be UTF-8",
+            err.to_string()
+        );
+        assert_eq!(Some(9), err.location.map(|loc| loc.offset));
+    }
+
+    #[test]
+    fn should_expose_error_location() {
+        let err = assemble("nop\n  frob %rax\n", &AssembleOptions::default()).unwrap_err();
+        assert_eq!("unknown instruction `frob`", err.message());
+        let loc = err.location().unwrap();
+        assert_eq!((1, 2, 6), (loc.line(), loc.column(), loc.offset()));
+    }
+
+    #[test]
+    fn should_categorize_errors() {
+        let kind = |src: &str, output_format| {
+            let options = AssembleOptions {
+                output_format,
+                ..Default::default()
+            };
+            assemble(src, &options).unwrap_err().kind().clone()
+        };
+
+        assert_eq!(
+            ErrorKind::UndefinedSymbol("missing".to_owned()),
+            kind("nop\njmp missing\n", OutputFormat::Binary)
+        );
+        assert_eq!(
+            ErrorKind::UndefinedSymbol("missing".to_owned()),
+            kind(".hidden missing\n", OutputFormat::Elf)
+        );
+        assert_eq!(ErrorKind::Lex, kind("nop\r\n", OutputFormat::Elf));
+        assert_eq!(ErrorKind::Parse, kind("movq %rax,\n", OutputFormat::Elf));
+        assert_eq!(
+            ErrorKind::Encode,
+            kind("movl %eax, %rbx\n", OutputFormat::Elf)
+        );
+    }
+
+    #[test]
+    fn should_locate_label_in_object() -> Result<()> {
+        let src = "nop\npatch_point:\nmovl $0, %eax\n.bss\nbuf:\n.skip 4\n";
+        let assembled = assemble(src, &AssembleOptions::default())?;
+
+        // `.text` is the first section, right after the 64 bytes ELF header.
+        let offset = assembled.label_offset("patch_point").unwrap();
+        assert_eq!(0x40 + 1, offset);
+        assert_eq!(
+            [0xb8, 0x00, 0x00, 0x00, 0x00],
+            assembled.object[offset..offset + 5]
+        );
+        assert_eq!(None, assembled.label_offset("buf"));
+        assert_eq!(None, assembled.label_offset("undefined"));
+
+        let options = AssembleOptions {
+            output_format: OutputFormat::Binary,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(1),
+            assemble(src, &options)?.label_offset("patch_point")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_stamp_comment_with_version() -> Result<()> {
+        let contains =
+            |object: &[u8], bytes: &[u8]| object.windows(bytes.len()).any(|window| window == bytes);
+        let identity = format!("ras {}\0", env!("CARGO_PKG_VERSION"));
+
+        let object = assemble("nop\n", &AssembleOptions::default())?.object;
+        assert!(contains(&object, b".comment\0"));
+        assert!(contains(&object, identity.as_bytes()));
+        // `e_shoff` stays 8 bytes aligned after the unaligned `.comment`.
+        let e_shoff = u64::from_le_bytes(object[0x28..0x30].try_into().unwrap());
+        assert_eq!(0, e_shoff % 8);
+
+        let options = AssembleOptions {
+            comment: false,
+            ..Default::default()
+        };
+        let object = assemble("nop\n", &options)?.object;
+        assert!(!contains(&object, b".comment\0"));
+        assert!(!contains(&object, identity.as_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_reference_across_fed_source() -> Result<()> {
+        let mut encoder = Encoder::with_options(AssembleOptions {
+            output_format: OutputFormat::Binary,
+            ..Default::default()
+        });
+        encoder.feed(".equ N, 1\njmp done\n")?;
+        encoder.feed("movl $N, %eax\ndone:\n")?;
+        let assembled = encoder.finish()?;
+
+        // `done` is after `jmp`(5 bytes) and `movl`(5 bytes).
+        assert_eq!(
+            vec![0xe9, 0x05, 0x00, 0x00, 0x00, 0xb8, 0x01, 0x00, 0x00, 0x00],
+            assembled.object
+        );
+        assert_eq!(10, assembled.symbol_table()["done"].address);
+        Ok(())
+    }
+
+    #[test]
+    fn should_continue_location_across_fed_source() -> Result<()> {
+        let mut encoder = Encoder::default();
+        encoder.feed("nop\nnop")?;
+        let err = encoder.feed("movl %eax\n").unwrap_err();
+        assert_eq!(Some(2), err.location.map(|loc| loc.line));
+        Ok(())
+    }
+}
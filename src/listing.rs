@@ -0,0 +1,52 @@
+use crate::encoder::Encoder;
+use crate::utils::HashMap;
+use alloc::{format, string::String};
+use core::fmt::Write as _;
+
+/// Listing with the original source line of each instruction.
+///
+/// e.g. `   1 0000 4889C3               movq %rax, %rbx`
+pub(crate) fn listing_with_source(encoder: &Encoder, src: &str) -> String {
+    // line number -> source line
+    let lines: HashMap<usize, &str> = src.lines().enumerate().collect();
+
+    let mut listing = String::new();
+    for instr in &encoder.instrs {
+        let line = instr.loc.line;
+        let source = lines.get(&line).map_or("", |source| source.trim());
+        let bytes: String = instr
+            .code
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect();
+        let _ = writeln!(
+            listing,
+            "{:>4} {:04x} {bytes:<20} {source}",
+            line + 1,
+            instr.addr
+        );
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::parse;
+    use crate::error::Result;
+    use crate::lexer::tokenize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_list_with_source() -> Result<()> {
+        let src = "nop\n  movq %rax, %rbx  # comment\n";
+        let mut encoder = parse(tokenize(src)?)?;
+        encoder.assign_addresses()?;
+
+        assert_eq!(
+            "   1 0000 90                   nop\n   2 0001 4889C3               movq %rax, %rbx  # comment\n",
+            listing_with_source(&encoder, src)
+        );
+        Ok(())
+    }
+}
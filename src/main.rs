@@ -1,16 +1,7 @@
-mod elf;
-mod encoder;
-mod error;
-mod globals;
-mod lexer;
-mod utils;
-
-use crate::elf::Elf;
-use crate::encoder::parse;
-use crate::error::{bail, Result};
-use crate::lexer::tokenize;
-use clap::{arg, command, Parser};
+use clap::{Parser, ValueEnum};
+use ras::{assemble, decode_source, preprocess, AssembleOptions, OutputFormat, Result};
 use std::fs;
+use std::process::ExitCode;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -24,29 +15,161 @@ struct Args {
     /// Keeps local symbols (e.g., those starting with `.L`
     #[arg(short, long, default_value_t = false)]
     keep_locals: bool,
+    /// Keeps the local symbols which start with the prefix (e.g., `.Lfunc`). May be given more than once
+    #[arg(long, value_name = "PREFIX")]
+    keep_locals_matching: Vec<String>,
+    /// Error if no executable section(e.g. `.text`) has code
+    #[arg(long, default_value_t = false)]
+    require_text: bool,
+    /// Log tokens and encoded instructions to stderr
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+    /// Error on unknown directives instead of ignoring them
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+    /// Treat warnings as errors
+    #[arg(long, default_value_t = false)]
+    fatal_warnings: bool,
+    /// Print the source with the conditional blocks expanded, without writing the object
+    #[arg(short = 'E', long, default_value_t = false)]
+    preprocess_only: bool,
+    /// Print a listing which shows each instruction with its source line
+    #[arg(long, default_value_t = false)]
+    listing_with_source: bool,
+    /// Use relaxable `R_X86_64_(REX_)GOTPCRELX` for `@GOTPCREL` (yes|no)
+    #[arg(
+        long = "mrelax-relocations",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new(),
+    )]
+    relax_relocations: bool,
+    /// Keep the relocations resolved by the assembler, with their values filled
+    #[arg(long, default_value_t = false)]
+    emit_relocs: bool,
+    /// Align `.word`, `.long` and `.quad` to their size
+    #[arg(long, default_value_t = false)]
+    natural_align: bool,
+    /// Load 64-bit immediates of `mov` from `.rodata` constants instead of `movabs`
+    #[arg(long, default_value_t = false)]
+    imm64_in_rodata: bool,
+    /// Assemble twice and fail if the objects are not byte-identical
+    #[arg(long, default_value_t = false)]
+    reproducible: bool,
+    /// Enable or disable CPU features (e.g., `+sse2,-avx`). All are enabled by default. May be given more than once
+    #[arg(long, value_name = "FEATURES", allow_hyphen_values = true)]
+    mattr: Vec<String>,
+    /// Error if the section is larger than N bytes after layout (e.g., `.text=4096`). May be given more than once
+    #[arg(long, value_name = "SECTION=N")]
+    assert_size: Vec<String>,
+    /// List the local symbols which are never referenced
+    #[arg(long, default_value_t = false)]
+    list_unused: bool,
+    /// Output file format
+    #[arg(long, alias = "output-format", value_enum, default_value_t = Oformat::Elf)]
+    oformat: Oformat,
+    /// Do not stamp the `.comment` section with the assembler version
+    #[arg(long, default_value_t = false)]
+    no_comment: bool,
+    /// Print the counts of tokens, instructions, symbols and relocations, section sizes and phase timings
+    #[arg(long, default_value_t = false)]
+    statistics: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Oformat {
+    /// ELF64 relocatable object file
+    Elf,
+    /// Flat binary without ELF wrapper. All symbols must be defined.
+    #[value(alias = "bin")]
+    Binary,
 }
 
-fn main() -> Result<()> {
+/// Writes the log records of the assembler to stderr. e.g. `DEBUG instruction encoded 1:1: .text: Nop [90]`
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("{:<5} {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    if args.verbose {
+        static LOGGER: StderrLogger = StderrLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+    }
+    match run(args) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let program = match fs::read_to_string(args.file_name) {
-        Ok(src) => src,
-        Err(err) => bail!("{err}"),
+fn run(args: Args) -> Result<ExitCode> {
+    let bytes = fs::read(args.file_name)?;
+    let program = decode_source(&bytes)?;
+    let mut options = AssembleOptions {
+        keep_locals: args.keep_locals,
+        keep_locals_matching: args.keep_locals_matching,
+        require_text: args.require_text,
+        listing_with_source: args.listing_with_source,
+        relax_relocations: args.relax_relocations,
+        output_format: match args.oformat {
+            Oformat::Elf => OutputFormat::Elf,
+            Oformat::Binary => OutputFormat::Binary,
+        },
+        list_unused: args.list_unused,
+        statistics: args.statistics,
+        comment: !args.no_comment,
+        strict: args.strict,
+        emit_relocs: args.emit_relocs,
+        natural_align: args.natural_align,
+        imm64_in_rodata: args.imm64_in_rodata,
+        reproducible: args.reproducible,
+        disabled_features: Vec::new(),
+        size_limits: Vec::new(),
     };
-    let tokens = tokenize(&program)?;
-    dbg!(&tokens);
-
-    parse(tokens)?;
-    // let mut en = Encoder::new(&mut l, file_name);
-    // en.encode();
-    // en.assign_addresses();
-
-    let mut e = Elf::new(&args.out_file, args.keep_locals);
-    e.collect_rela_symbols();
-    e.build_symtab_strtab();
-    e.rela_text_users();
-    e.build_shstrtab();
-    e.build_headers();
-    e.write_elf();
-    Ok(())
+    for mattr in &args.mattr {
+        options.mattr(mattr)?;
+    }
+    for arg in &args.assert_size {
+        options.assert_size(arg)?;
+    }
+    if args.preprocess_only {
+        print!("{}", preprocess(program, &options)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let assembled = assemble(program, &options)?;
+
+    if let Some(listing) = assembled.listing() {
+        print!("{listing}");
+    }
+    for symbol in assembled.unused_symbols().unwrap_or_default() {
+        println!("unused symbol: {symbol}");
+    }
+    if let Some(statistics) = assembled.statistics() {
+        eprint!("{statistics}");
+    }
+    for warning in assembled.warnings() {
+        eprintln!("warning: {warning}");
+    }
+    if args.fatal_warnings && !assembled.warnings().is_empty() {
+        eprintln!("error: warnings are treated as errors by `--fatal-warnings`");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    fs::write(&args.out_file, assembled.object)?;
+    Ok(ExitCode::SUCCESS)
 }
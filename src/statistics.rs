@@ -0,0 +1,116 @@
+use crate::encoder::arch::x86_64::instructions::InstrKind;
+use crate::encoder::Encoder;
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use core::time::Duration;
+
+/// Counts, section sizes and phase timings of an assembly job, made by `--statistics`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+    pub tokens: usize,
+    /// Statements which may emit bytes. Labels and symbol/section directives are not counted.
+    pub instructions: usize,
+    pub symbols: usize,
+    /// Relocations left to the linker. The resolved jumps are not counted.
+    pub relocations: usize,
+    /// (section name, size in bytes) in order of first appearance
+    pub section_sizes: Vec<(String, usize)>,
+    /// (phase name, wall-clock time) in execution order
+    pub timings: Vec<(&'static str, Duration)>,
+}
+
+/// Wall-clock time of each phase of [`crate::assemble`]. Without `std`, no time is measured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PhaseTimer {
+    #[cfg(feature = "std")]
+    start: std::time::Instant,
+    pub(crate) timings: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimer {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            start: std::time::Instant::now(),
+            timings: Vec::new(),
+        }
+    }
+
+    /// Start the next phase now. e.g. the time between [`crate::Encoder::feed`] calls is not counted.
+    pub(crate) fn restart(&mut self) {
+        #[cfg(feature = "std")]
+        {
+            self.start = std::time::Instant::now();
+        }
+    }
+
+    /// Record the time since the previous lap.
+    pub(crate) fn lap(&mut self, _phase: &'static str) {
+        #[cfg(feature = "std")]
+        {
+            let now = std::time::Instant::now();
+            self.timings.push((_phase, now - self.start));
+            self.start = now;
+        }
+    }
+}
+
+impl Statistics {
+    pub(crate) fn collect(encoder: &Encoder) -> Self {
+        let instructions = encoder
+            .instrs
+            .iter()
+            .filter(|instr| {
+                !matches!(
+                    instr.kind,
+                    InstrKind::None
+                        | InstrKind::Label
+                        | InstrKind::Section
+                        | InstrKind::Global
+                        | InstrKind::Local
+                        | InstrKind::Weak
+                        | InstrKind::Hidden
+                        | InstrKind::Internal
+                        | InstrKind::Protected
+                )
+            })
+            .count();
+        let section_sizes = encoder
+            .section_names()
+            .into_iter()
+            .filter_map(|name| {
+                let size = encoder.user_defined_sections.get(&name)?.addr;
+                Some((name, size))
+            })
+            .collect();
+
+        Self {
+            tokens: encoder.token_count,
+            instructions,
+            symbols: encoder.user_defined_symbols.len(),
+            relocations: encoder
+                .rela_text_users
+                .iter()
+                .filter(|rela| !rela.is_already_resolved)
+                .count(),
+            section_sizes,
+            timings: encoder.timer.timings.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tokens: {}", self.tokens)?;
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "symbols: {}", self.symbols)?;
+        writeln!(f, "relocations: {}", self.relocations)?;
+        for (name, size) in &self.section_sizes {
+            writeln!(f, "section {name}: {size} bytes")?;
+        }
+        for (phase, time) in &self.timings {
+            writeln!(f, "time {phase}: {time:?}")?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,9 @@
+/// `std::collections::HashMap`, or `BTreeMap` without `std`.
+#[cfg(feature = "std")]
+pub(crate) use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+pub(crate) type HashMap<K, V> = alloc::collections::BTreeMap<K, V>;
+
 // https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
 pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::core::slice::from_raw_parts((p as *const T) as *const u8, ::core::mem::size_of::<T>())
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Assemble `src` by the binary and return its output.
+fn run_ras(name: &str, src: &str, args: &[&str]) -> Output {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src_path = dir.join(format!("{name}.s"));
+    fs::write(&src_path, src).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_ras"))
+        .arg(&src_path)
+        .arg("-o")
+        .arg(dir.join(format!("{name}.o")))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn should_not_trace_without_verbose() {
+    let output = run_ras("quiet", "nop\n", &[]);
+    assert!(output.status.success());
+    assert_eq!("", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_ras("verbose", "nop\n", &["-v"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("TRACE token 1:1: Ident(\"nop\")"));
+    assert!(stderr.contains("DEBUG instruction laid out .text+0x0: Nop [90]"));
+    assert!(stderr.contains("DEBUG section laid out .text: 0x1 bytes, flags 0x6"));
+}
+
+#[test]
+fn should_fail_on_warnings_with_fatal_warnings() {
+    let output = run_ras("warn", "movb $256, %al\n", &[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("warning: 1:1: value 0x100 truncated"));
+
+    let output = run_ras("fatal_warn", "movb $256, %al\n", &["--fatal-warnings"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn should_write_flat_binary() {
+    let src = "movl $1, %eax\nlea msg(%rip), %rsi\nmsg:\n.byte 0x41\n";
+    let output = run_ras("flat", src, &["--oformat", "binary"]);
+    assert!(output.status.success());
+
+    let bin = fs::read(PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("flat.o")).unwrap();
+    assert_eq!(
+        vec![
+            0xb8, 0x01, 0x00, 0x00, 0x00, // movl $1, %eax
+            0x48, 0x8d, 0x35, 0x00, 0x00, 0x00, 0x00, // lea msg(%rip), %rsi
+            0x41, // msg
+        ],
+        bin
+    );
+
+    let output = run_ras(
+        "flat_undefined",
+        "lea foo(%rip), %rsi\n",
+        &["--oformat=bin"],
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn should_print_statistics() {
+    let src = "foo:\nmovl $1, %eax\njmp foo\nlea baz(%rip), %rax\n.data\nbar:\n.long 7\n";
+    let output = run_ras("statistics", src, &["--statistics"]);
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("instructions: 4\n"));
+    assert!(stderr.contains("symbols: 2\n"));
+    assert!(stderr.contains("relocations: 1\n"));
+    assert!(stderr.contains("section .text: 17 bytes\n"));
+    assert!(stderr.contains("section .data: 4 bytes\n"));
+    assert!(stderr.contains("time write object: "));
+}
+
+#[test]
+fn should_keep_locals_matching_prefix() {
+    let src = ".Lfunc0:\nnop\n.Ltmp0:\nnop\n";
+    let output = run_ras(
+        "keep_locals_matching",
+        src,
+        &["--keep-locals-matching", ".Lfunc"],
+    );
+    assert!(output.status.success());
+
+    let object =
+        fs::read(PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("keep_locals_matching.o"))
+            .unwrap();
+    let has_name = |name: &[u8]| object.windows(name.len()).any(|window| window == name);
+    assert!(has_name(b"\0.Lfunc0\0"));
+    assert!(!has_name(b"\0.Ltmp0\0"));
+}
+
+#[test]
+fn should_print_preprocessed_source() {
+    let src = ".set DEBUG, 0\n.if DEBUG\n    int3\n.else\n    nop\n.endif\n";
+    let output = run_ras("preprocess", src, &["-E"]);
+    assert!(output.status.success());
+    assert_eq!(
+        ".set DEBUG, 0\n    nop\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(!PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join("preprocess.o")
+        .exists());
+
+    let src = ".macro zero reg\n    xorl %\\reg, %\\reg\n.endm\nzero eax\n    unknown %eax\n";
+    let output = run_ras("preprocess_macro", src, &["-E"]);
+    assert!(output.status.success());
+    assert_eq!(
+        "    xorl %eax, %eax\n    unknown %eax\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn should_write_identical_objects_with_reproducible() {
+    let src = ".section .s1,\"a\"\n.quad x1\n.section .s2,\"a\"\n.quad x2\n.data\n.quad x3\n.text\ncall x4\n";
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let objects: Vec<_> = ["reproducible1", "reproducible2"]
+        .into_iter()
+        .map(|name| {
+            let output = run_ras(name, src, &["--reproducible"]);
+            assert!(output.status.success());
+            fs::read(dir.join(format!("{name}.o"))).unwrap()
+        })
+        .collect();
+    assert_eq!(objects[0], objects[1]);
+}
+
+#[test]
+fn should_locate_invalid_utf8_in_source() {
+    let src_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("latin1.s");
+    fs::write(&src_path, b"nop\n# caf\xe9\nnop\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ras"))
+        .arg(&src_path)
+        .arg("-o")
+        .arg(src_path.with_extension("o"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        "error: 2:6: invalid UTF-8 byte 0xe9. The source must be UTF-8\n",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn should_print_error_with_its_location() {
+    let output = run_ras(
+        "error",
+        "nop
+  frob %rax
+",
+        &[],
+    );
+    assert!(!output.status.success());
+    assert_eq!(
+        "error: 2:3: unknown instruction `frob`\n",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,64 @@
+//! A classic `exit(0)` program assembles into an object which `ld` can link as it is.
+use ras::constants::{STB_GLOBAL, STT_NOTYPE};
+use ras::{assemble, AssembleOptions, Symbol};
+
+const EXIT: &str = "
+.global _start
+.text
+_start:
+    movl $60, %eax
+    xorl %edi, %edi
+    syscall
+";
+
+fn u16_at(object: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes(object[offset..offset + 2].try_into().unwrap()).into()
+}
+
+fn u64_at(object: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(object[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// Find the bytes of the section named `name` by the section headers.
+fn section<'a>(object: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let (shoff, shentsize) = (u64_at(object, 0x28), u16_at(object, 0x3a));
+    let header = |idx: usize| &object[shoff + idx * shentsize..][..shentsize];
+    let shstrtab = header(u16_at(object, 0x3e));
+    let names = &object[u64_at(shstrtab, 0x18)..];
+
+    (0..u16_at(object, 0x3c)).map(header).find_map(|sh| {
+        let sh_name = u32::from_le_bytes(sh[..4].try_into().unwrap()) as usize;
+        let end = names[sh_name..].iter().position(|&byte| byte == 0)?;
+        (&names[sh_name..sh_name + end] == name.as_bytes())
+            .then(|| &object[u64_at(sh, 0x18)..][..u64_at(sh, 0x20)])
+    })
+}
+
+#[test]
+fn should_assemble_exit_program() {
+    let assembled = assemble(EXIT, &AssembleOptions::default()).unwrap();
+    assert!(assembled.warnings().is_empty());
+
+    assert_eq!(
+        Some(
+            &[
+                0xb8, 0x3c, 0x00, 0x00, 0x00, // movl $60, %eax
+                0x31, 0xff, // xorl %edi, %edi
+                0x0f, 0x05, // syscall
+            ][..]
+        ),
+        section(&assembled.object, ".text")
+    );
+    assert_eq!(None, section(&assembled.object, ".rela.text"));
+
+    assert_eq!(
+        Some(&Symbol {
+            address: 0,
+            section: ".text".to_owned(),
+            binding: STB_GLOBAL,
+            symbol_type: STT_NOTYPE,
+            size: 0,
+        }),
+        assembled.symbol_table().get("_start")
+    );
+}
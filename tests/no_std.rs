@@ -0,0 +1,16 @@
+//! The library is usable from a `no_std` crate with only `alloc`.
+#![no_std]
+extern crate alloc;
+
+use alloc::vec;
+use ras::{assemble, AssembleOptions, OutputFormat};
+
+#[test]
+fn should_assemble_text_without_std() {
+    let options = AssembleOptions {
+        output_format: OutputFormat::Binary,
+        ..AssembleOptions::default()
+    };
+    let assembled = assemble("movl $1, %eax\nnop\n", &options).unwrap();
+    assert_eq!(vec![0xb8, 0x01, 0x00, 0x00, 0x00, 0x90], assembled.object);
+}